@@ -0,0 +1,38 @@
+use std::path::Path;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+/// Directory additional audio tracks are downloaded into, next to the segments directory
+pub const AUDIO_TRACKS_DIR_NAME: &str = "audio_tracks";
+
+const TRACK_FILE_NAME: &str = "track.json";
+
+/// Description of an additional audio track, written alongside its segments
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct AudioTrack {
+    /// Representation ID in the manifest
+    pub id: Option<String>,
+
+    /// Language of the track's adaptation set
+    pub lang: Option<String>,
+}
+
+impl AudioTrack {
+    /// Name to label the track with in a merged video
+    pub fn label(&self) -> Option<&str> {
+        self.lang.as_deref().or(self.id.as_deref())
+    }
+
+    pub async fn read(dir: impl AsRef<Path>) -> Result<Self> {
+        let data = fs::read(dir.as_ref().join(TRACK_FILE_NAME)).await?;
+        Ok(serde_json::from_slice(&data)?)
+    }
+
+    pub async fn write(&self, dir: impl AsRef<Path>) -> Result<()> {
+        let data = serde_json::to_vec_pretty(self)?;
+        fs::write(dir.as_ref().join(TRACK_FILE_NAME), data).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,143 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use reqwest::{Client, Url};
+use tokio::fs;
+use tokio::sync::Mutex;
+use tracing::info;
+
+use super::{fetch_init, fetch_media, segment_path, DownloadConfig};
+use crate::audio_track::{AudioTrack, AUDIO_TRACKS_DIR_NAME};
+use crate::error::IgLiveError;
+use crate::mpd::{Mpd, Representation};
+use crate::state::State;
+
+/// Live segments of the audio representations besides the selected one
+pub struct AudioTracks {
+    dir: PathBuf,
+    tracks: Vec<Track>,
+}
+
+struct Track {
+    /// Representation ID, or position among the audio representations without one
+    key: String,
+    dir: PathBuf,
+    init: Vec<u8>,
+    downloaded: HashSet<usize>,
+}
+
+impl AudioTracks {
+    /// Audio tracks to be downloaded into numbered directories under `dir`
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            tracks: vec![],
+        }
+    }
+
+    /// Download segments listed in `manifest` of every audio representation except `selected`.
+    /// Each track's directory holds its initialization segment, a description and a `segments`
+    /// directory, like a download directory.
+    pub async fn download(
+        &mut self,
+        state: &Mutex<State>,
+        client: &Client,
+        config: &DownloadConfig,
+        manifest: &Mpd,
+        selected: &Representation,
+        url_base: &Url,
+    ) -> Result<()> {
+        let others = manifest
+            .audio_representations()
+            .into_iter()
+            .enumerate()
+            .filter(|(_, rep)| !std::ptr::eq(*rep, selected));
+        for (i, rep) in others {
+            let key = rep.id.clone().unwrap_or_else(|| i.to_string());
+            let track = match self.tracks.iter_mut().position(|t| t.key == key) {
+                Some(index) => &mut self.tracks[index],
+                None => {
+                    let number = self.tracks.len();
+                    let track = Track::new(client, rep, url_base, &self.dir, number, key).await?;
+                    let info = AudioTrack {
+                        id: rep.id.clone(),
+                        lang: rep.lang.clone(),
+                    };
+                    let label = info.label().unwrap_or(&track.key);
+                    info!("Downloading audio track {label}");
+                    info.write(&track.dir).await?;
+                    self.tracks.push(track);
+                    self.tracks.last_mut().unwrap()
+                }
+            };
+            track
+                .download_segments(state, client, config, rep, url_base)
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+impl Track {
+    async fn new(
+        client: &Client,
+        rep: &Representation,
+        url_base: &Url,
+        dir: impl AsRef<Path>,
+        number: usize,
+        key: String,
+    ) -> Result<Self> {
+        let dir = dir
+            .as_ref()
+            .join(AUDIO_TRACKS_DIR_NAME)
+            .join(number.to_string());
+        fs::create_dir_all(dir.join("segments")).await?;
+        let init = fetch_init(client, rep, url_base, &dir).await?;
+        Ok(Self {
+            key,
+            dir,
+            init,
+            downloaded: HashSet::new(),
+        })
+    }
+
+    /// Download listed segments that weren't downloaded yet.
+    /// Segments that aren't available yet are tried again on the next call.
+    async fn download_segments(
+        &mut self,
+        state: &Mutex<State>,
+        client: &Client,
+        config: &DownloadConfig,
+        rep: &Representation,
+        url_base: &Url,
+    ) -> Result<()> {
+        for segment in rep.segments() {
+            if self.downloaded.contains(&segment.t) {
+                continue;
+            }
+            let url = rep.download_url(url_base, segment.t)?;
+            let segment_data = match fetch_media(state, client, config, &url).await {
+                Ok(data) => data,
+                Err(e)
+                    if matches!(
+                        e.downcast_ref(),
+                        Some(
+                            IgLiveError::StatusNotFound
+                                | IgLiveError::SegmentTooSmall(_)
+                                | IgLiveError::UnexpectedContentType(_)
+                        )
+                    ) =>
+                {
+                    continue
+                }
+                Err(e) => return Err(e),
+            };
+            let mut data = self.init.clone();
+            data.extend_from_slice(&segment_data);
+            fs::write(segment_path(&self.dir.join("segments"), &url)?, data).await?;
+            self.downloaded.insert(segment.t);
+        }
+        Ok(())
+    }
+}
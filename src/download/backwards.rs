@@ -1,20 +1,27 @@
 // download/backwards.rs
 
-use std::collections::BTreeSet;
+use std::collections::{BTreeSet, HashMap};
 use std::path::Path;
 use std::sync::Arc;
+use std::time::Instant;
 
 use anyhow::Result;
+use futures::future;
 use futures::stream::{self, StreamExt};
 use indicatif::ProgressBar;
 use reqwest::{Client, Url};
 use tokio::sync::{Mutex, Semaphore};
+use tokio::time;
+use tracing::{debug, info, trace, warn};
 
-use super::download_file;
+use super::progress::ProgressSender;
+use super::{download_file, media_segment_path, DownloadConfig, ProgressEvent};
 use crate::error::IgLiveError;
+use crate::marker::{BackwardsMarker, BackwardsPosition};
 use crate::mpd::{MediaType, Representation};
 use crate::state::State;
 
+#[allow(clippy::too_many_arguments)]
 pub async fn download_reps_backwards(
     state: Arc<Mutex<State>>,
     client: &Client,
@@ -22,68 +29,308 @@ pub async fn download_reps_backwards(
     reps: impl IntoIterator<Item = (&Representation, ProgressBar)>,
     start_frame: usize,
     dir: impl AsRef<Path> + Send,
-    parallel_candidates: usize,
+    config: &Arc<DownloadConfig>,
+    events: &ProgressSender,
 ) -> Result<()> {
-    futures::future::try_join_all(reps.into_iter().map(|(rep, pb)| {
-        download_backwards(state.clone(), client, url_base, rep, start_frame, dir.as_ref(), pb, parallel_candidates)
-    }))
-    .await?;
+    let walks = reps.into_iter().map(|(rep, pb)| {
+        let state = state.clone();
+        let dir = dir.as_ref();
+        async move {
+            let start = Instant::now();
+            let result = async {
+                let windows = search_windows(
+                    &state,
+                    client,
+                    url_base,
+                    rep,
+                    start_frame,
+                    dir,
+                    &pb,
+                    config,
+                    events,
+                );
+                let windows = windows.await?;
+                let walks = windows.into_iter().map(|window| {
+                    let (state, pb) = (state.clone(), pb.clone());
+                    download_backwards(
+                        state, client, url_base, rep, window, dir, pb, config, events,
+                    )
+                });
+                futures::future::try_join_all(walks).await.map(|_| ())
+            }
+            .await;
+            let media_type = rep.media_type();
+            state.lock().await.backwards_times.insert(media_type.clone(), start.elapsed());
+
+            // Keep the other walks going, recording why this one stopped
+            if let (Err(e), false) = (&result, config.fail_fast) {
+                warn!("{media_type:?} Searching past segments failed: {e}");
+                pb.finish_with_message("Failed");
+                state.lock().await.backwards_errors.insert(media_type, e.to_string());
+                return Ok(());
+            }
+            result
+        }
+    });
+    futures::future::try_join_all(walks).await?;
     Ok(())
 }
 
+/// Range of past segments searched by one walk
+#[derive(Clone, Copy, Debug)]
+struct SearchWindow {
+    /// Segment to search below. If `None`, the earliest downloaded segment, or the position
+    /// resumed from; only this walk reads and writes the marker.
+    top: Option<isize>,
+
+    /// Time to search down to
+    bottom: isize,
+}
+
+/// Split the search between `start_frame` and the earliest downloaded segment into
+/// [DownloadConfig::backwards_windows] windows, each below a segment found near its top.
+/// Windows whose top has no segment nearby are merged into the one above.
+#[allow(clippy::too_many_arguments)]
+async fn search_windows(
+    state: &Arc<Mutex<State>>,
+    client: &Client,
+    url_base: &Url,
+    rep: &Representation,
+    start_frame: usize,
+    dir: &Path,
+    pb: &ProgressBar,
+    config: &DownloadConfig,
+    events: &ProgressSender,
+) -> Result<Vec<SearchWindow>> {
+    let media_type = rep.media_type();
+    let start_frame = isize::try_from(start_frame).unwrap_or(isize::MAX);
+    // Search in one walk, which stops right away if downloading was stopped before any segment
+    let earliest = earliest_segment(state, &media_type).await?;
+    let split = config.backwards_windows > 1 && !rep.is_segment_list();
+    let Some(latest_t) = earliest.filter(|_| split) else {
+        return Ok(vec![SearchWindow {
+            top: None,
+            bottom: start_frame,
+        }]);
+    };
+    let width = top_delta(state, &media_type, config).await + FAIR_JITTER_RANGE;
+    let span = latest_t.saturating_sub(start_frame);
+    let windows = config.backwards_windows as isize;
+    let mut visited = BTreeSet::new();
+    let mut anchors = vec![];
+    for i in 1..windows {
+        let center = latest_t - span / windows * i;
+        if center - width / 2 <= start_frame {
+            break;
+        }
+        let visited = &mut visited;
+        let probed = probe_window(
+            state, client, url_base, rep, center, width, visited, dir, pb, config, events,
+        );
+        if let Some(t) = probed.await? {
+            anchors.push(t);
+        }
+    }
+    info!("{media_type:?} Searching {} window(s) in parallel", anchors.len() + 1);
+
+    let tops = std::iter::once(None).chain(anchors.iter().copied().map(Some));
+    let bottoms = anchors.iter().copied().chain([start_frame]);
+    Ok(tops
+        .zip(bottoms)
+        .map(|(top, bottom)| SearchWindow { top, bottom })
+        .collect())
+}
+
+/// Earliest downloaded segment of `media_type`, if any
+async fn earliest_segment(state: &Mutex<State>, media_type: &MediaType) -> Result<Option<isize>> {
+    let earliest = state.lock().await.downloaded_segs[media_type].iter().min().copied();
+    Ok(earliest.map(isize::try_from).transpose()?)
+}
+
+/// Most common delta between segments of `media_type`, or
+/// [DownloadConfig::assumed_missing_delta] if none is known
+async fn top_delta(state: &Mutex<State>, media_type: &MediaType, config: &DownloadConfig) -> isize {
+    let deltas = state.lock().await.top_deltas(media_type, 1);
+    let fallback = config.assumed_missing_delta as isize;
+    deltas.first().copied().unwrap_or(fallback).max(1)
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn download_backwards(
     state: Arc<Mutex<State>>,
     client: &Client,
     url_base: &Url,
     rep: &Representation,
-    start_frame: usize,
+    window: SearchWindow,
     dir: impl AsRef<Path>,
     pb: ProgressBar,
-    parallel_candidates: usize,
+    config: &Arc<DownloadConfig>,
+    events: &ProgressSender,
 ) -> Result<()> {
     let media_type = rep.media_type();
-    let mut latest_t = *state.lock().await.downloaded_segs[&media_type]
-        .iter()
-        .min()
-        .unwrap() as isize;
+
+    // Explicitly listed segments are all known from the manifest, nothing to search for
+    if rep.is_segment_list() {
+        pb.finish_with_message("Segment list manifest, no past segments to search");
+        return Ok(());
+    }
+
+    let mut latest_t: isize = match window.top {
+        Some(top) => top,
+        None => match earliest_segment(&state, &media_type).await? {
+            Some(t) => t,
+            None => {
+                pb.finish_with_message("No segments downloaded to search below");
+                return Ok(());
+            }
+        },
+    };
+    let start_frame = window.bottom;
 
     let mut visited: BTreeSet<isize> = BTreeSet::new();
     let mut pts_too_early_segments: BTreeSet<isize> = BTreeSet::new();
+    let mut pts_too_early_retries: HashMap<isize, usize> = HashMap::new();
     let mut lower_bound = 0;
     let mut prev_delta = 0;
-    let assumed_missing_delta = 2000;
+    let assumed_missing_delta = config.assumed_missing_delta as isize;
     let mut skipped_segments = 0;
+    let mut gaps = vec![];
 
-    let concurrency_limit = 10;
-    let semaphore = Arc::new(Semaphore::new(concurrency_limit));
+    let concurrency_limit = if config.sequential { 1 } else { config.backwards_concurrency.max(1) };
+    let ramp = config.segment_concurrency_ramp;
+    let initial_limit = if ramp.is_zero() { concurrency_limit } else { 1 };
+    let semaphore = Arc::new(Semaphore::new(initial_limit));
+
+    // Add the remaining permits evenly over the ramp
+    if initial_limit < concurrency_limit {
+        let semaphore = semaphore.clone();
+        let step = ramp / (concurrency_limit - initial_limit) as u32;
+        tokio::spawn(async move {
+            for _ in initial_limit..concurrency_limit {
+                time::sleep(step).await;
+                semaphore.add_permits(1);
+            }
+        });
+    }
+    let permits = state.lock().await.permits.clone();
+
+    // The marker is written next to metadata, above the segments directory
+    let marker_dir = dir.as_ref().parent().unwrap_or(dir.as_ref()).to_path_buf();
+    let mut marked_t = None;
+
+    // Continue below where a previous run stopped, resuming from this directory if it has a marker
+    let marker = match &config.resume_backwards_from {
+        _ if window.top.is_some() => None,
+        Some(resume_dir) => Some(BackwardsMarker::read(resume_dir).await?),
+        None if config.resume => BackwardsMarker::read(&marker_dir).await.ok(),
+        None => None,
+    };
+    if let Some(marker) = marker {
+        if let Some(position) = marker.positions.get(&media_type) {
+            if position.latest_t < latest_t {
+                info!("{media_type:?} Resuming from {}", position.latest_t);
+                latest_t = position.latest_t;
+                if position.lower_bound != lower_bound {
+                    debug!(
+                        "{media_type:?} Lower bound moved from {lower_bound} to {}",
+                        position.lower_bound
+                    );
+                    lower_bound = position.lower_bound;
+                }
+                state.lock().await.prioritize_deltas(&media_type, &position.deltas);
+            }
+        }
+    }
+
+    // Skip times known not to exist from previous runs
+    if let Some(cache) = &state.lock().await.probe_cache {
+        if let Some(not_found) = cache.not_found.get(&media_type) {
+            visited.extend(not_found);
+        }
+    }
 
     pb.set_message(format!("Latest: {}", latest_t));
 
-    while latest_t > start_frame as isize {
+    while latest_t > start_frame {
+        if marked_t != Some(latest_t) {
+            if window.top.is_none() {
+                write_marker(&state, &media_type, latest_t, lower_bound, &marker_dir).await?;
+            }
+            marked_t = Some(latest_t);
+            ProgressEvent::BackwardsProgress {
+                media_type: media_type.clone(),
+                latest_t,
+            }
+            .emit(events, config);
+        }
+
+        if config.pause.is_paused() {
+            pb.set_message(format!("{media_type:?} Paused at {latest_t}"));
+            config.pause.wait_resumed(&config.cancel).await;
+        }
+        if config.cancel.is_cancelled() {
+            pb.finish_with_message("Stopped");
+            return Ok(());
+        }
+
         let candidates =
-            find_next_candidates(&state, &media_type, latest_t, &mut visited, lower_bound, parallel_candidates).await;
+            find_next_candidates(&state, &media_type, latest_t, &mut visited, lower_bound, config).await;
 
+        trace!("{media_type:?} Candidates below {latest_t}: {candidates:?}");
         if candidates.is_empty() {
-            // No candidate found.  Assume a segment is missing *here*.
-            pb.println(format!("Segment near {} appears to be missing, skipping.", latest_t));
-            latest_t -= assumed_missing_delta;
-            lower_bound = 0;
-            visited.insert(latest_t);
-            // Consider PTS too early segments for next round of candidates
-            for &seg in &pts_too_early_segments {
-                visited.remove(&seg);
-            }
-            pts_too_early_segments.clear();
+            // No candidate found.  Segments are missing *here*, find where they continue.
+            info!("Segment near {latest_t} appears to be missing, searching further back.");
+            gaps.push(latest_t);
             skipped_segments += 1;
-
-            if skipped_segments > 5 {
-                pb.println("Too many consecutive missing segments.  Giving up.");
+            if skipped_segments > config.max_consecutive_skips {
+                info!("Too many consecutive missing segments.  Giving up.");
                 break;
             }
+            let anchor = reanchor(
+                &state,
+                client,
+                url_base,
+                rep,
+                latest_t,
+                start_frame,
+                &mut visited,
+                dir.as_ref(),
+                &pb,
+                config,
+                events,
+            )
+            .await?;
+            let anchor = match anchor {
+                Some(anchor) => anchor,
+                None if config.cancel.is_cancelled() => continue,
+                None => {
+                    info!("No segments found further back.  Giving up.");
+                    break;
+                }
+            };
+            info!("{media_type:?} Continuing from {anchor}");
+            latest_t = anchor;
+            if lower_bound != 0 {
+                debug!("{media_type:?} Lower bound moved from {lower_bound} to 0");
+                lower_bound = 0;
+            }
+            retry_pts_too_early(
+                &state,
+                &media_type,
+                &mut pts_too_early_segments,
+                &mut pts_too_early_retries,
+                &mut visited,
+                config.max_pts_early_retries,
+            )
+            .await;
             continue;
         }
 
         // Create a stream of futures for the download tasks.
+        let (url_base, variable) = {
+            let state = state.lock().await;
+            (state.url_base(url_base), state.template_variable(rep))
+        };
         let download_tasks = stream::iter(candidates)
             .map(|(candidate_t, delta)| {
                 let state = state.clone();
@@ -93,35 +340,49 @@ async fn download_backwards(
                 let dir = dir.as_ref().to_path_buf();
                 let pb = pb.clone();
                 let semaphore = semaphore.clone();
+                let permits = permits.clone();
                 let media_type = media_type.clone();
+                let config = config.clone();
 
                 tokio::spawn(async move {
                     let _permit = semaphore.acquire().await.expect("Semaphore error");
+                    let _shared_permit = match &permits {
+                        Some(permits) => Some(permits.acquire_low().await),
+                        None => None,
+                    };
 
-                    pb.set_message(format!(
+                    let mut message = format!(
                         "{:?} Latest: {} | Prev Δ: {} | Checking: {} (Δ{})",
                         media_type, latest_t, prev_delta, candidate_t, delta
-                    ));
+                    );
+                    if config.show_retries {
+                        message += &format!(" | Retries: {}", state.lock().await.retries);
+                    }
+                    pb.set_message(message);
                     pb.tick();
 
-                    let url = rep.download_url(&url_base, candidate_t)?;
-                    let filename = dir.join(
-                        url.path_segments()
-                            .ok_or(IgLiveError::InvalidUrl)?
-                            .rev()
-                            .next()
-                            .ok_or(IgLiveError::InvalidUrl)?,
-                    );
+                    let url = rep.download_url_as(&url_base, candidate_t as usize, variable)?;
+                    let filename = media_segment_path(
+                        &dir,
+                        &url,
+                        &media_type,
+                        candidate_t as usize,
+                        config.compat_filenames,
+                    )?;
 
                     let result = download_file(
                         state.clone(),
                         &client,
+                        &config,
                         media_type,
-                        skipped_segments == 0, // ignore PTS check if we've lost previous segment(s)
+                        // ignore PTS check if we've lost previous segment(s), or other windows'
+                        // segments are mixed in
+                        skipped_segments == 0 && config.backwards_windows <= 1,
                         &url,
                         filename,
                     )
-                    .await;
+                    .await
+                    .map(|data| data.len());
 
                     Ok::<_, anyhow::Error>((candidate_t, delta, result))
                 })
@@ -135,58 +396,461 @@ async fn download_backwards(
             });
 
         // Process the results of the download tasks.
-        let results: Vec<Result<(isize, isize, Result<()>)>> = download_tasks.collect().await;
+        let results: Vec<Result<(isize, isize, Result<usize>)>> = download_tasks.collect().await;
 
         for result in results {
             match result {
                 Ok((candidate_t, delta, download_result)) => match download_result {
-                    Ok(()) => {
+                    Ok(bytes) => {
+                        debug!("{media_type:?} Found {candidate_t} with offset {delta}");
+                        ProgressEvent::SegmentDownloaded {
+                            media_type: media_type.clone(),
+                            representation: rep.id.clone(),
+                            t: candidate_t as usize,
+                            bytes,
+                            live: false,
+                        }
+                        .emit(events, config);
+                        state
+                            .lock()
+                            .await
+                            .durations
+                            .get_mut(&media_type)
+                            .unwrap()
+                            .insert(candidate_t as usize, (latest_t - candidate_t) as usize);
                         prev_delta = delta;
                         latest_t = candidate_t;
                          *state.lock().await.deltas.get_mut(&media_type).unwrap().entry(delta).or_insert(0) += 1;
                         skipped_segments = 0;
-                        // Consider PTS too early segments for next round of candidates
-                        for &seg in &pts_too_early_segments {
-                            visited.remove(&seg);
-                        }
-                        pts_too_early_segments.clear();
+                        retry_pts_too_early(
+                            &state,
+                            &media_type,
+                            &mut pts_too_early_segments,
+                            &mut pts_too_early_retries,
+                            &mut visited,
+                            config.max_pts_early_retries,
+                        )
+                        .await;
                     }
                     Err(e) => {
                         if let Some(e) = e.downcast_ref::<IgLiveError>() {
                             match e {
-                                IgLiveError::StatusNotFound => (),
+                                IgLiveError::StatusNotFound => {
+                                    trace!("{media_type:?} {candidate_t} not found");
+                                    if let Some(cache) = &mut state.lock().await.probe_cache {
+                                        cache
+                                            .not_found
+                                            .entry(media_type.clone())
+                                            .or_default()
+                                            .insert(candidate_t);
+                                    }
+                                }
                                 IgLiveError::PtsTooEarly => {
-                                    pb.println(format!(
+                                    info!(
                                         "{media_type:?} Found {candidate_t} with offset {delta} but PTS too early, saving"
-                                    ));
+                                    );
                                     pts_too_early_segments.insert(candidate_t);
                                 }
-                                _ => pb.println(format!("Download failed: {e:?}")),
+                                IgLiveError::SegmentTooSmall(size) => info!(
+                                    "{media_type:?} Skipping {candidate_t}, response is only {size} bytes"
+                                ),
+                                IgLiveError::UnexpectedContentType(content_type) => info!(
+                                    "{media_type:?} Skipping {candidate_t}, response is {content_type}"
+                                ),
+                                IgLiveError::Cancelled => (),
+                                _ => warn!("Download failed: {e:?}"),
                             }
                         }
                     }
                 },
                 Err(e) => {
-                    pb.println(format!("Task error: {e:?}"));
+                    warn!("Task error: {e:?}");
                 }
             }
         }
     }
 
+    // Search the gaps once more, in case their segments failed transiently
+    if config.reprobe_gaps && !gaps.is_empty() {
+        let recovered = reprobe_gaps(
+            &state,
+            client,
+            url_base,
+            rep,
+            &gaps,
+            assumed_missing_delta,
+            dir.as_ref(),
+            &pb,
+            config,
+            events,
+        )
+        .await?;
+        info!("{media_type:?} Recovered {recovered} of {} gap(s)", gaps.len());
+    }
+
     pb.finish_with_message("Finished");
     Ok(())
 }
 
+/// Search the `gap_size` before each of `gap_ends` again, one candidate at a time, following
+/// found segments further back until the gap is crossed.
+/// Return the number of gaps in which at least one segment was found.
+#[allow(clippy::too_many_arguments)]
+async fn reprobe_gaps(
+    state: &Arc<Mutex<State>>,
+    client: &Client,
+    url_base: &Url,
+    rep: &Representation,
+    gap_ends: &[isize],
+    gap_size: isize,
+    dir: &Path,
+    pb: &ProgressBar,
+    config: &DownloadConfig,
+    events: &ProgressSender,
+) -> Result<usize> {
+    let media_type = rep.media_type();
+    let permits = state.lock().await.permits.clone();
+    let mut recovered = 0;
+
+    for &gap_end in gap_ends {
+        let gap_start = gap_end.saturating_sub(gap_size);
+        let mut visited = BTreeSet::new();
+        let mut latest_t = gap_end;
+        let mut found = false;
+
+        'search: while latest_t > gap_start {
+            if config.cancel.is_cancelled() {
+                return Ok(recovered);
+            }
+
+            // Give up on the gap after one round of candidates without a segment
+            let candidates =
+                find_next_candidates(state, &media_type, latest_t, &mut visited, gap_start, config).await;
+            for (candidate_t, _) in candidates {
+                let _shared_permit = match &permits {
+                    Some(permits) => Some(permits.acquire_low().await),
+                    None => None,
+                };
+                pb.set_message(format!(
+                    "{media_type:?} Reprobing gap at {gap_end} | Checking: {candidate_t}"
+                ));
+
+                let (url_base, variable) = {
+                    let state = state.lock().await;
+                    (state.url_base(url_base), state.template_variable(rep))
+                };
+                let url = rep.download_url_as(&url_base, candidate_t as usize, variable)?;
+                let filename = media_segment_path(
+                    dir,
+                    &url,
+                    &media_type,
+                    candidate_t as usize,
+                    config.compat_filenames,
+                )?;
+                let result = download_file(
+                    state.clone(),
+                    client,
+                    config,
+                    media_type.clone(),
+                    false, // the segments around a gap aren't contiguous
+                    &url,
+                    filename,
+                )
+                .await;
+                if let Ok(data) = result {
+                    ProgressEvent::SegmentDownloaded {
+                        media_type: media_type.clone(),
+                        representation: rep.id.clone(),
+                        t: candidate_t as usize,
+                        bytes: data.len(),
+                        live: false,
+                    }
+                    .emit(events, config);
+                    state
+                        .lock()
+                        .await
+                        .durations
+                        .get_mut(&media_type)
+                        .unwrap()
+                        .insert(candidate_t as usize, (latest_t - candidate_t) as usize);
+                    latest_t = candidate_t;
+                    found = true;
+                    continue 'search;
+                }
+            }
+            break;
+        }
+
+        if found {
+            info!("{media_type:?} Recovered segment(s) in gap at {gap_end}");
+            recovered += 1;
+        }
+    }
+
+    Ok(recovered)
+}
+
+/// Find the latest segment below a gap ending at `gap_end`, after no candidate was found.
+/// Probes windows one segment wide exponentially further below `gap_end`, down to
+/// `start_frame`, then bisects between the first window with a segment and the empty window
+/// above it, assuming segments are missing in one stretch.
+/// Return the time of the segment found closest to the gap, if any.
+#[allow(clippy::too_many_arguments)]
+async fn reanchor(
+    state: &Arc<Mutex<State>>,
+    client: &Client,
+    url_base: &Url,
+    rep: &Representation,
+    gap_end: isize,
+    start_frame: isize,
+    visited: &mut BTreeSet<isize>,
+    dir: &Path,
+    pb: &ProgressBar,
+    config: &DownloadConfig,
+    events: &ProgressSender,
+) -> Result<Option<isize>> {
+    let media_type = rep.media_type();
+    let delta = top_delta(state, &media_type, config).await;
+    let back_pts = state.lock().await.back_pts.get(&media_type).copied();
+    let width = delta + FAIR_JITTER_RANGE;
+
+    // Windows 2, 4, 8... segments below the gap, the last one reaching down to the start
+    let mut empty = gap_end - delta;
+    let mut distance = 2 * delta;
+    let mut found = loop {
+        let center = gap_end.saturating_sub(distance).max(start_frame + width / 2);
+        if center >= empty || config.cancel.is_cancelled() {
+            return Ok(None);
+        }
+        let probed = probe_window(
+            state, client, url_base, rep, center, width, visited, dir, pb, config, events,
+        );
+        if let Some(t) = probed.await? {
+            break t;
+        }
+        empty = center;
+        distance = distance.saturating_mul(2);
+    };
+
+    // Narrow down to where segments continue
+    while empty - found > 2 * delta && !config.cancel.is_cancelled() {
+        let center = found + (empty - found) / 2;
+        let probed = probe_window(
+            state, client, url_base, rep, center, width, visited, dir, pb, config, events,
+        );
+        match probed.await? {
+            Some(t) => found = found.max(t),
+            None => empty = center,
+        }
+    }
+
+    // The PTS of probed segments doesn't follow on from the segment found closest to the gap,
+    // leave the next segment's PTS unchecked instead
+    if let Some(pts) = back_pts {
+        state.lock().await.back_pts.insert(media_type, pts);
+    }
+    Ok(Some(found))
+}
+
+/// Download a segment starting within `width / 2` of `center`, checking the closest times
+/// first. Return its time, if any.
+#[allow(clippy::too_many_arguments)]
+pub(super) async fn probe_window(
+    state: &Arc<Mutex<State>>,
+    client: &Client,
+    url_base: &Url,
+    rep: &Representation,
+    center: isize,
+    width: isize,
+    visited: &mut BTreeSet<isize>,
+    dir: &Path,
+    pb: &ProgressBar,
+    config: &DownloadConfig,
+    events: &ProgressSender,
+) -> Result<Option<isize>> {
+    let media_type = rep.media_type();
+    let times: Vec<isize> = (0..=width / 2)
+        .flat_map(|offset| [center - offset, center + offset])
+        .skip(1)
+        .filter(|&t| t > 0 && !visited.contains(&t))
+        .collect();
+
+    pb.set_message(format!("{media_type:?} Probing around {center}"));
+    debug!("{media_type:?} Probing {} time(s) around {center}", times.len());
+    probe_times(state, client, url_base, rep, &times, visited, dir, config, events).await
+}
+
+/// Download segments starting at any of `times`, in batches in the given order, until a batch
+/// finds one. Return the latest time found, if any.
+#[allow(clippy::too_many_arguments)]
+pub(super) async fn probe_times(
+    state: &Arc<Mutex<State>>,
+    client: &Client,
+    url_base: &Url,
+    rep: &Representation,
+    times: &[isize],
+    visited: &mut BTreeSet<isize>,
+    dir: &Path,
+    config: &DownloadConfig,
+    events: &ProgressSender,
+) -> Result<Option<isize>> {
+    let media_type = rep.media_type();
+    let batch_size = if config.sequential { 1 } else { config.parallel_candidates.max(1) };
+    let (url_base, permits, variable) = {
+        let state = state.lock().await;
+        (state.url_base(url_base), state.permits.clone(), state.template_variable(rep))
+    };
+    for batch in times.chunks(batch_size) {
+        if config.cancel.is_cancelled() {
+            return Ok(None);
+        }
+        visited.extend(batch);
+        let downloads = batch.iter().map(|&t| {
+            let (url_base, permits, media_type) = (&url_base, &permits, media_type.clone());
+            async move {
+                let _shared_permit = match permits {
+                    Some(permits) => Some(permits.acquire_low().await),
+                    None => None,
+                };
+                let url = rep.download_url_as(url_base, t as usize, variable)?;
+                let filename = media_segment_path(
+                    dir,
+                    &url,
+                    &media_type,
+                    t as usize,
+                    config.compat_filenames,
+                )?;
+                let data =
+                    download_file(state.clone(), client, config, media_type, false, &url, filename)
+                        .await?;
+                Ok::<_, anyhow::Error>((t, data.len()))
+            }
+        });
+        let found: Vec<_> = future::join_all(downloads).await.into_iter().flatten().collect();
+        for &(t, bytes) in &found {
+            ProgressEvent::SegmentDownloaded {
+                media_type: media_type.clone(),
+                representation: rep.id.clone(),
+                t: t as usize,
+                bytes,
+                live: false,
+            }
+            .emit(events, config);
+        }
+        let mut hits: Vec<isize> = found.iter().map(|&(t, _)| t).collect();
+        hits.sort_unstable();
+        if let Some(&t) = hits.last() {
+            debug!("{media_type:?} Probe found {t}");
+            record_probe_hits(state, &media_type, &hits, visited, config).await;
+            return Ok(Some(t));
+        }
+    }
+    Ok(None)
+}
+
+/// Record segments found by probing, at ascending times `hits`, like the walk records the
+/// segments it finds, and unmark them as visited so the walk can step onto them.
+/// The latest hit's duration isn't known yet and is assumed to be the most common delta.
+async fn record_probe_hits(
+    state: &Mutex<State>,
+    media_type: &MediaType,
+    hits: &[isize],
+    visited: &mut BTreeSet<isize>,
+    config: &DownloadConfig,
+) {
+    let assumed = top_delta(state, media_type, config).await;
+    let mut state = state.lock().await;
+    for (i, &t) in hits.iter().enumerate() {
+        let delta = hits.get(i + 1).map(|&next| next - t);
+        state
+            .durations
+            .get_mut(media_type)
+            .unwrap()
+            .insert(t as usize, delta.unwrap_or(assumed) as usize);
+        if let Some(delta) = delta {
+            *state.deltas.get_mut(media_type).unwrap().entry(delta).or_insert(0) += 1;
+        }
+        visited.remove(&t);
+    }
+}
+
+/// Record the search position of `media_type` and write all positions to the marker file,
+/// along with the probe cache
+async fn write_marker(
+    state: &Arc<Mutex<State>>,
+    media_type: &MediaType,
+    latest_t: isize,
+    lower_bound: isize,
+    dir: &Path,
+) -> Result<()> {
+    let mut state = state.lock().await;
+    let position = BackwardsPosition {
+        latest_t,
+        lower_bound,
+        deltas: state.top_deltas(media_type, 5),
+    };
+    state.marker.positions.insert(media_type.clone(), position);
+    let marker = state.marker.clone();
+    let cache = state.probe_cache.clone();
+    drop(state);
+
+    marker.write(dir).await?;
+    if let Some(cache) = cache {
+        cache.write(dir).await?;
+    }
+    Ok(())
+}
+
+/// Consider PTS too early segments for the next round of candidates.
+/// Segments that were already retried `max_retries` times stay visited, so the search advances.
+async fn retry_pts_too_early(
+    state: &Arc<Mutex<State>>,
+    media_type: &MediaType,
+    pts_too_early_segments: &mut BTreeSet<isize>,
+    pts_too_early_retries: &mut HashMap<isize, usize>,
+    visited: &mut BTreeSet<isize>,
+    max_retries: usize,
+) {
+    let mut retried = 0;
+    for &seg in pts_too_early_segments.iter() {
+        let retries = pts_too_early_retries.entry(seg).or_insert(0);
+        if *retries >= max_retries {
+            info!("{media_type:?} {seg} still PTS too early after {retries} retries, skipping");
+            continue;
+        }
+        *retries += 1;
+        retried += 1;
+        visited.remove(&seg);
+    }
+    if retried > 0 {
+        info!("{media_type:?} Retrying {retried} PTS too early segment(s)");
+    }
+    state.lock().await.retries += retried;
+    #[cfg(feature = "metrics")]
+    crate::metrics::METRICS
+        .retries
+        .fetch_add(retried as u64, std::sync::atomic::Ordering::Relaxed);
+    pts_too_early_segments.clear();
+}
+
+/// Number of most common deltas whose jitter is probed in turn before other deltas
+const FAIR_DELTAS: usize = 3;
+
+/// Offsets from the most common deltas probed before other deltas
+const FAIR_JITTER_RANGE: isize = 100;
+
 async fn find_next_candidates(
     state: &Arc<Mutex<State>>,
     media_type: &MediaType,
     latest_t: isize,
     visited: &mut BTreeSet<isize>,
     lower_bound: isize,
-    parallel_candidates: usize,
+    config: &DownloadConfig,
 ) -> Vec<(isize, isize)> {
-    let search_range = 1000;
+    let search_range = config.search_range as isize;
+    let max_candidates = if config.sequential { 1 } else { config.parallel_candidates };
     let mut candidates = Vec::new();
+    let mut candidates_per_delta: HashMap<isize, usize> = HashMap::new();
 
     // Get deltas sorted by count (descending)
     let locked_state = state.lock().await;
@@ -194,14 +858,74 @@ async fn find_next_candidates(
     let mut deltas: Vec<_> = deltas_map.iter().collect();
     deltas.sort_by(|(_, a), (_, b)| b.cmp(a));
 
+    // Time ranges covered by downloaded segments, past their start so probed segments can be
+    // stepped onto
+    let durations = &locked_state.durations[media_type];
+    let is_covered = |t: isize| {
+        config.skip_covered
+            && durations
+                .range(..t as usize)
+                .next_back()
+                .is_some_and(|(&start, &d)| (t as usize) < start + d)
+    };
+
+    let is_candidate = |t: isize, visited: &BTreeSet<isize>| {
+        t > lower_bound.max(0) && t < latest_t && !visited.contains(&t) && !is_covered(t)
+    };
+    // Leave room for other deltas once one has enough candidates
+    let at_limit = |delta_candidates: usize| {
+        config
+            .limit_candidates_per_delta
+            .is_some_and(|limit| delta_candidates >= limit)
+    };
+
+    // Probe jitter around the top few deltas first, taking the nearest unprobed time of each
+    // delta in turn
+    let mut fair_deltas: Vec<_> = deltas[..deltas.len().min(FAIR_DELTAS)]
+        .iter()
+        .map(|&(&delta, _)| {
+            let times = (0..=FAIR_JITTER_RANGE)
+                .flat_map(move |offset| jitter_times(latest_t, delta, offset));
+            (delta, times)
+        })
+        .collect();
+    while !fair_deltas.is_empty() && candidates.len() < max_candidates {
+        // Deltas without any more times are dropped
+        fair_deltas.retain_mut(|(delta, times)| {
+            if candidates.len() >= max_candidates {
+                return true;
+            }
+            let delta_candidates = candidates_per_delta.entry(*delta).or_insert(0);
+            if at_limit(*delta_candidates) {
+                return false;
+            }
+            let Some(candidate_t) = times.find(|&t| is_candidate(t, visited)) else {
+                return false;
+            };
+            candidates.push((candidate_t, latest_t - candidate_t));
+            visited.insert(candidate_t);
+            *delta_candidates += 1;
+            true
+        });
+    }
+    if candidates.len() >= max_candidates {
+        return candidates;
+    }
+
+    // Then around all deltas
     for offset in 0..=search_range {
-        for (&delta, cc) in &deltas {
-            let potential_candidates = [latest_t - (delta + offset), latest_t - (delta - offset)];
-            for &candidate_t in &potential_candidates {
-                if candidate_t > lower_bound && candidate_t < latest_t && !visited.contains(&candidate_t) {
+        for (&delta, _) in &deltas {
+            let delta_candidates = candidates_per_delta.entry(delta).or_insert(0);
+            if at_limit(*delta_candidates) {
+                continue;
+            }
+
+            for candidate_t in jitter_times(latest_t, delta, offset) {
+                if is_candidate(candidate_t, visited) {
                     candidates.push((candidate_t, latest_t - candidate_t));
                     visited.insert(candidate_t);
-                    if candidates.len() >= parallel_candidates {
+                    *delta_candidates += 1;
+                    if candidates.len() >= max_candidates {
                         return candidates;
                     }
                 }
@@ -211,3 +935,207 @@ async fn find_next_candidates(
 
     candidates
 }
+
+/// Times `delta` plus and minus `offset` before `latest_t`.
+/// Times that would overflow can't be valid candidates and are left out.
+fn jitter_times(latest_t: isize, delta: isize, offset: isize) -> impl Iterator<Item = isize> {
+    [
+        delta.checked_add(offset).and_then(|d| latest_t.checked_sub(d)),
+        delta.checked_sub(offset).and_then(|d| latest_t.checked_sub(d)),
+    ]
+    .into_iter()
+    .flatten()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+
+    #[tokio::test]
+    async fn pts_too_early_retries_stop_at_the_cap() {
+        let state = Arc::new(Mutex::new(State::new()));
+        let mut segments = BTreeSet::new();
+        let mut retries = HashMap::new();
+        let mut visited = BTreeSet::new();
+
+        // The segment is found too early again on every retry
+        for _ in 0..5 {
+            segments.insert(1000);
+            visited.insert(1000);
+            retry_pts_too_early(
+                &state,
+                &MediaType::Video,
+                &mut segments,
+                &mut retries,
+                &mut visited,
+                3,
+            )
+            .await;
+        }
+
+        assert_eq!(retries[&1000], 3);
+        assert!(visited.contains(&1000));
+        assert_eq!(state.lock().await.retries, 3);
+    }
+
+    #[tokio::test]
+    async fn candidates_near_the_time_limits_stay_in_range() {
+        let state = Arc::new(Mutex::new(State::new()));
+        let deltas = [(2000, 5), (isize::MAX, 1)].into_iter().collect();
+        state.lock().await.deltas.insert(MediaType::Video, deltas);
+        let config = DownloadConfig {
+            search_range: 10,
+            parallel_candidates: 64,
+            ..Default::default()
+        };
+
+        // Huge deltas from a huge time would overflow, but still reach times after 0
+        let latest_t = isize::MAX - 5;
+        let candidates = find_next_candidates(
+            &state,
+            &MediaType::Video,
+            latest_t,
+            &mut BTreeSet::new(),
+            isize::MIN,
+            &config,
+        )
+        .await;
+        assert!(candidates.iter().any(|&(t, _)| t < 100));
+        assert!(candidates.iter().all(|&(t, d)| t > 0 && t < latest_t && t + d == latest_t));
+
+        // Every delta reaches past time 0
+        let candidates = find_next_candidates(
+            &state,
+            &MediaType::Video,
+            1500,
+            &mut BTreeSet::new(),
+            -100_000,
+            &config,
+        )
+        .await;
+        assert!(candidates.is_empty());
+    }
+
+    #[tokio::test]
+    async fn top_deltas_take_turns() {
+        let state = Arc::new(Mutex::new(State::new()));
+        let deltas = [(2000, 5), (2033, 4)].into_iter().collect();
+        state.lock().await.deltas.insert(MediaType::Video, deltas);
+        let config = DownloadConfig {
+            parallel_candidates: 4,
+            ..Default::default()
+        };
+
+        // The nearest jitter of the most common delta was probed already
+        let latest_t = 100000;
+        let mut visited = (97998..=98002).collect();
+        let candidates = find_next_candidates(
+            &state,
+            &MediaType::Video,
+            latest_t,
+            &mut visited,
+            0,
+            &config,
+        )
+        .await;
+        assert_eq!(candidates, [(97997, 2003), (97967, 2033), (98003, 1997), (97966, 2034)]);
+    }
+
+    #[tokio::test]
+    async fn candidates_per_delta_limit_diversifies_candidates() {
+        let state = Arc::new(Mutex::new(State::new()));
+        let deltas = [(2000, 10), (2033, 5), (1967, 3), (4000, 1)].into_iter().collect();
+        state.lock().await.deltas.insert(MediaType::Video, deltas);
+        let candidates = |limit_candidates_per_delta| {
+            let state = state.clone();
+            async move {
+                let config = DownloadConfig {
+                    parallel_candidates: 8,
+                    limit_candidates_per_delta,
+                    ..Default::default()
+                };
+                let mut visited = BTreeSet::new();
+                find_next_candidates(&state, &MediaType::Video, 100000, &mut visited, 0, &config)
+                    .await
+            }
+        };
+
+        // The most common deltas fill the round
+        let unlimited = candidates(None).await;
+        assert_eq!(unlimited.len(), 8);
+        assert!(unlimited.iter().all(|&(_, d)| d < 3000));
+
+        // Two candidates per delta leave room for the least common delta
+        let limited = candidates(Some(2)).await;
+        assert_eq!(limited.len(), 8);
+        assert_eq!(limited.iter().filter(|&&(_, d)| d >= 3000).count(), 2);
+    }
+
+    #[tokio::test]
+    async fn walk_runs_with_hidden_progress() {
+        let (url, requests) = test_util::serve(404, "text/plain", vec![]).await;
+        let manifest = test_util::manifest(&[10000, 12000]);
+        let rep = manifest.representations()[0];
+        let state = Arc::new(Mutex::new(State::new()));
+        state
+            .lock()
+            .await
+            .downloaded_segs
+            .get_mut(&MediaType::Video)
+            .unwrap()
+            .insert(10000);
+        let dir = test_util::temp_dir("hidden-progress").join("segments");
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = Arc::new(DownloadConfig {
+            search_range: 0,
+            max_consecutive_skips: 0,
+            ..Default::default()
+        });
+
+        let pb = ProgressBar::hidden();
+        download_reps_backwards(
+            state,
+            &Client::new(),
+            &url,
+            [(rep, pb.clone())],
+            0,
+            &dir,
+            &config,
+            &ProgressSender::default(),
+        )
+        .await
+        .unwrap();
+        assert!(pb.is_finished());
+        assert!(requests.load(std::sync::atomic::Ordering::SeqCst) > 0);
+    }
+
+    #[tokio::test]
+    async fn walk_without_downloaded_segments_stops() {
+        let (url, requests) = test_util::serve(404, "text/plain", vec![]).await;
+        let manifest = test_util::manifest(&[10000, 12000]);
+        let rep = manifest.representations()[0];
+        let dir = test_util::temp_dir("no-segments").join("segments");
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = Arc::new(DownloadConfig {
+            backwards_windows: 4,
+            ..Default::default()
+        });
+
+        let pb = ProgressBar::hidden();
+        download_reps_backwards(
+            Arc::new(Mutex::new(State::new())),
+            &Client::new(),
+            &url,
+            [(rep, pb.clone())],
+            0,
+            &dir,
+            &config,
+            &ProgressSender::default(),
+        )
+        .await
+        .unwrap();
+        assert!(pb.is_finished());
+        assert_eq!(requests.load(std::sync::atomic::Ordering::SeqCst), 0);
+    }
+}
@@ -0,0 +1,66 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::Result;
+use tokio::sync::Mutex;
+
+use crate::error::IgLiveError;
+use crate::mpd::{MediaType, Representation};
+use crate::pts::get_codec;
+use crate::state::State;
+
+/// Codecs that can be muxed into an `.mp4` file, as `ffprobe` codec names and as manifest
+/// codecs string prefixes
+const MP4_VIDEO_CODECS: [&str; 11] = [
+    "h264", "hevc", "av1", "vp9", "mpeg4", "avc1", "avc3", "hvc1", "hev1", "av01", "vp09",
+];
+const MP4_AUDIO_CODECS: [&str; 10] = [
+    "aac", "mp3", "opus", "ac3", "eac3", "flac", "mp4a", "ac-3", "ec-3", "fLaC",
+];
+
+/// Check that the video and audio of `reps` can be merged into an `.mp4` file.
+/// Codecs are taken from the manifest if listed, otherwise probed from downloaded
+/// initialization segments. Video and audio must share a timescale, since their segments are
+/// aligned by time.
+pub async fn check_compatibility(
+    state: &Arc<Mutex<State>>,
+    reps: impl IntoIterator<Item = &Representation>,
+    ffprobe: &Path,
+) -> Result<()> {
+    let reps: Vec<_> = reps.into_iter().collect();
+    let timescale = |media_type| {
+        reps.iter()
+            .find(|r| r.media_type() == media_type)
+            .map(|r| r.timescale())
+    };
+    if let (Some(video), Some(audio)) = (timescale(MediaType::Video), timescale(MediaType::Audio)) {
+        if video != audio {
+            return Err(IgLiveError::MismatchedTimescales(video, audio).into());
+        }
+    }
+
+    for rep in reps {
+        let media_type = rep.media_type();
+        let allowed: &[&str] = match media_type {
+            MediaType::Video => &MP4_VIDEO_CODECS,
+            MediaType::Audio => &MP4_AUDIO_CODECS,
+            _ => continue,
+        };
+
+        let codec = match &rep.codecs {
+            Some(codecs) => codecs.split('.').next().unwrap_or_default().to_owned(),
+            None => {
+                let init = state.lock().await.downloaded_init[&media_type]
+                    .read()
+                    .await?
+                    .into_owned();
+                get_codec(ffprobe, init).await?
+            }
+        };
+        if !allowed.contains(&codec.as_str()) {
+            return Err(IgLiveError::IncompatibleCodec(media_type, codec).into());
+        }
+    }
+
+    Ok(())
+}
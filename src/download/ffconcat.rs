@@ -0,0 +1,94 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::Path;
+
+use anyhow::Result;
+use reqwest::Url;
+use tokio::fs;
+
+use super::media_segment_path;
+use crate::mpd::Representation;
+
+/// Write an `ffconcat` playlist of downloaded segments with explicit per-segment durations.
+///
+/// Each segment lasts its recorded duration. Missing segments are marked by a comment with the
+/// length of the jump to the next segment.
+///
+/// # Arguments
+///
+/// * `rep` - Representation the segments were downloaded from.
+/// * `times` - Start times `t` of downloaded segments.
+/// * `durations` - Recorded durations of downloaded segments by start time. Segments without
+///   one last as listed in the manifest, or else until the next segment.
+/// * `compat_filenames` - Whether segments were written with zero-padded names.
+/// * `path` - Output file path. Segment paths are written relative to its directory.
+pub async fn write_ffconcat(
+    rep: &Representation,
+    url_base: &Url,
+    times: impl IntoIterator<Item = usize>,
+    durations: &BTreeMap<usize, usize>,
+    compat_filenames: bool,
+    path: impl AsRef<Path>,
+) -> Result<()> {
+    let mut times: Vec<_> = times.into_iter().collect();
+    times.sort_unstable();
+
+    let timeline = rep.segments();
+    let timescale = rep.timescale() as f64;
+    let mut playlist = String::from("ffconcat version 1.0\n");
+    for (i, &t) in times.iter().enumerate() {
+        let next_t = times.get(i + 1).copied();
+        let d = durations
+            .get(&t)
+            .copied()
+            .or_else(|| timeline.iter().find(|s| s.t == t).map(|s| s.d))
+            .or(next_t.map(|next_t| next_t - t))
+            .or(timeline.last().map(|s| s.d))
+            .unwrap_or(0);
+        let file = media_segment_path(
+            Path::new("segments"),
+            &rep.download_url(url_base, t)?,
+            &rep.media_type(),
+            t,
+            compat_filenames,
+        )?;
+        // Quotes are closed, escaped and reopened
+        let file = file.to_string_lossy().replace('\'', r"'\''");
+        writeln!(playlist, "file '{file}'")?;
+        writeln!(playlist, "duration {:.6}", d as f64 / timescale)?;
+        let gap = next_t.and_then(|next_t| next_t.checked_sub(t + d));
+        if let Some(gap) = gap.filter(|&gap| gap > 0) {
+            writeln!(playlist, "# gap {:.6}", gap as f64 / timescale)?;
+        }
+    }
+
+    fs::write(path, playlist).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{manifest, temp_dir};
+
+    #[tokio::test]
+    async fn durations_are_recorded_ones_with_gaps_marked() {
+        let manifest = manifest(&[1000, 3000, 7000]);
+        let rep = manifest.select_tracks(&Default::default()).unwrap()[0];
+        let url_base = Url::parse("https://cdn/live/stream.mpd").unwrap();
+        let durations = BTreeMap::from([(1000, 2000), (3000, 1900)]);
+        let dir = temp_dir("ffconcat");
+        let path = dir.join("video.ffconcat");
+
+        write_ffconcat(rep, &url_base, [7000, 1000, 3000], &durations, false, &path)
+            .await
+            .unwrap();
+        let playlist = fs::read_to_string(&path).await.unwrap();
+        let expected = "ffconcat version 1.0\n\
+            file 'segments/video-1000.m4v'\nduration 2.000000\n\
+            file 'segments/video-3000.m4v'\nduration 1.900000\n# gap 2.100000\n\
+            file 'segments/video-7000.m4v'\nduration 2.000000\n";
+        assert_eq!(playlist, expected);
+        fs::remove_dir_all(dir).await.unwrap();
+    }
+}
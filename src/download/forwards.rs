@@ -1,14 +1,27 @@
+use std::collections::HashMap;
 use std::path::Path;
 use std::sync::Arc;
 
 use anyhow::Result;
+use chrono::Utc;
 use futures::future;
 use indicatif::ProgressBar;
 use reqwest::{Client, Url};
+use tokio::fs;
 use tokio::sync::Mutex;
-use tokio::time::{self, Duration};
+use tokio::time::{self, Duration, Instant};
+use tracing::{debug, info, warn};
 
-use crate::download::download_rep;
+use crate::download::audio_tracks::AudioTracks;
+use crate::download::initialization::fetch_init;
+use crate::download::limits::limit_reached;
+use crate::download::manifest_log::{ManifestSnapshot, TimelineSnapshot};
+use crate::download::progress::ProgressSender;
+use crate::download::refresh::refresh_mpd_url;
+use crate::download::{
+    download_rep, DeltaSeed, DownloadConfig, IdChangePolicy, ProgressEvent, SegmentSource,
+};
+use crate::error::IgLiveError;
 use crate::mpd::{MediaType, Mpd, Representation};
 use crate::state::State;
 
@@ -18,50 +31,223 @@ pub async fn download_forwards(
     url_base: &Url,
     dir: impl AsRef<Path> + Send,
     pb: ProgressBar,
+    config: &DownloadConfig,
+    events: &ProgressSender,
 ) -> Result<()> {
     // Set up 2 second interval
     let mut interval = time::interval(Duration::from_millis(1000));
     interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+
+    let mut manifest_failures = 0;
+    let mut refreshed = false;
+    let mut finished_at = None;
+    let mut live_start_t = None;
+    let mut audio_tracks = AudioTracks::new(dir.as_ref().parent().unwrap_or(dir.as_ref()));
+    let mut url_base = url_base.clone();
+    let mut segments_dir = dir.as_ref().to_path_buf();
+    // Initialization segments of the stream being split off into a continuation directory
+    let mut continuation_inits: HashMap<MediaType, Vec<u8>> = HashMap::new();
     
     let ret = loop {
         // Wait for interval
-        interval.tick().await;
+        tokio::select! {
+            _ = interval.tick() => (),
+            _ = config.cancel.cancelled() => break Ok(()),
+        }
+
+        // Hold back manifest refreshes while paused
+        if config.pause.is_paused() {
+            pb.set_message("Paused");
+            config.pause.wait_resumed(&config.cancel).await;
+            if config.cancel.is_cancelled() {
+                break Ok(());
+            }
+        }
+
+        // Download manifest, backing off on failure
+        let manifest = match Mpd::download_from_url(client, &url_base).await {
+            Ok(manifest) => manifest,
+            // The URL's token expired, get a fresh one and retry right away
+            Err(e) if is_forbidden(&e) && !refreshed => {
+                info!("Manifest URL expired, refreshing it");
+                refreshed = true;
+                match refresh_mpd_url(client, config).await {
+                    Ok(Some(url)) => {
+                        state.lock().await.refreshed_url = Some(url.clone());
+                        url_base = url;
+                    }
+                    Ok(None) => warn!(
+                        "Can't refresh the manifest URL without a username or refresh command"
+                    ),
+                    Err(e) => warn!("Failed to refresh manifest URL ({e})"),
+                }
+                continue;
+            }
+            Err(e) => {
+                manifest_failures += 1;
+                if manifest_failures > config.max_manifest_failures {
+                    break Err(e);
+                }
+                let backoff = Duration::from_secs(1 << manifest_failures.min(5));
+                warn!("Failed to refresh manifest ({e}), retrying in {}s", backoff.as_secs());
+                tokio::select! {
+                    _ = time::sleep(backoff) => (),
+                    _ = config.cancel.cancelled() => break Ok(()),
+                }
+                continue;
+            }
+        };
+        if manifest_failures > 0 {
+            info!("Manifest refresh recovered after {manifest_failures} failures");
+            manifest_failures = 0;
+        }
+        refreshed = false;
+        let reps = manifest.select_tracks(&config.media_selection())?;
+        let find_rep = |media_type| reps.iter().copied().find(|r| r.media_type() == media_type);
+
+        // Log listed segments next to metadata, above the segments directory
+        if config.manifest_log {
+            let snapshot = ManifestSnapshot {
+                time: Utc::now(),
+                id: manifest.id.clone(),
+                finished: manifest.finished,
+                video: find_rep(MediaType::Video).map(TimelineSnapshot::new),
+                audio: find_rep(MediaType::Audio).map(TimelineSnapshot::new),
+            };
+            snapshot.append(dir.as_ref().parent().unwrap_or(dir.as_ref())).await?;
+        }
 
-        // Download manifest
-        let manifest = Mpd::download_from_url(client, url_base).await?;
-        let (video_rep, audio_rep) = manifest.best_media()?;
+        // Check if stream was re-published under a new ID
+        let id_changed = {
+            let ids = &mut state.lock().await.ids;
+            if ids.last() != Some(&manifest.id) {
+                let old_id = ids.last().map_or("", |id| id.as_str());
+                info!("Stream ID changed from {old_id} to {}", manifest.id);
+                ids.push(manifest.id.clone());
+                true
+            } else {
+                false
+            }
+        };
+        if id_changed {
+            match config.id_change_policy {
+                IdChangePolicy::Continue => (),
+                IdChangePolicy::Stop => {
+                    info!("Stopping live download, download the new stream separately");
+                    break Ok(());
+                }
+                IdChangePolicy::Split => {
+                    let parent = dir.as_ref().parent().unwrap_or(dir.as_ref());
+                    let base = parent.join(format!("continuation-{}", manifest.id));
+                    segments_dir = base.join("segments");
+                    fs::create_dir_all(&segments_dir).await?;
+                    for rep in &reps {
+                        let init = fetch_init(client, rep, &url_base, &base).await?;
+                        continuation_inits.insert(rep.media_type(), init);
+                    }
+                    info!("Downloading live segments of the new stream into {base:?}");
+                }
+            }
+        }
 
-        // Find last segments downloaded
-        let (latest_video_t, latest_audio_t) = {
+        // Find last segments downloaded, if any were before a cancellation or rejection
+        let latest_ts: Vec<Option<usize>> = {
             let segs = &state.lock().await.downloaded_segs;
-            let latest_video_t = *segs[&MediaType::Video].iter().max().unwrap();
-            let latest_audio_t = *segs[&MediaType::Audio].iter().max().unwrap();
-            (latest_video_t, latest_audio_t)
+            reps.iter()
+                .map(|rep| segs[&rep.media_type()].iter().max().copied())
+                .collect()
         };
         
        // Download reps
-        let futures: Vec<_> = [video_rep, audio_rep]
-            .into_iter()
-            .map(|rep| download_rep(state.clone(), client, rep, url_base, dir.as_ref()))
+        let sources: Vec<_> = reps
+            .iter()
+            .map(|rep| SegmentSource {
+                rep,
+                url_base: &url_base,
+                dir: &segments_dir,
+                init: continuation_inits.get(&rep.media_type()).map(Vec::as_slice),
+            })
+            .collect();
+        let futures: Vec<_> = sources
+            .iter()
+            .map(|source| download_rep(state.clone(), client, config, source, events))
             .collect();
         future::join_all(futures)
             .await
             .into_iter()
             .collect::<Result<()>>()?;
 
-        check_overlap(video_rep, latest_video_t, &pb);
-        check_overlap(audio_rep, latest_audio_t, &pb);
+        for (rep, &latest_t) in reps.iter().zip(&latest_ts) {
+            if let Some(latest_t) = latest_t {
+                check_overlap(rep, latest_t);
+            }
+        }
+
+        // Learn the deltas between segments new to this refresh
+        if config.deltas_seed == DeltaSeed::Observed {
+            let mut state = state.lock().await;
+            for (rep, &latest_t) in reps.iter().zip(&latest_ts) {
+                let times: Vec<_> = rep
+                    .segments()
+                    .iter()
+                    .map(|s| s.t)
+                    .filter(|&t| latest_t.is_none_or(|latest_t| t >= latest_t))
+                    .collect();
+                state.learn_deltas(&rep.media_type(), &times);
+            }
+        }
+
+        // Download other audio tracks next to the segments directory
+        if let Some(audio_rep) = find_rep(MediaType::Audio).filter(|_| config.all_audio) {
+            let result = audio_tracks
+                .download(&state, client, config, &manifest, audio_rep, &url_base)
+                .await;
+            if let Err(e) = result {
+                warn!("Downloading other audio tracks failed: {e}");
+            }
+        }
 
         // Update progress bar
-        pb.set_message(format!(
-            "Downloaded video segment {}, audio segment {}",
-            latest_video_t, latest_audio_t
-        ));
+        let segments: Vec<_> = reps
+            .iter()
+            .zip(&latest_ts)
+            .filter_map(|(rep, t)| {
+                t.map(|t| format!("{:?} segment {t}", rep.media_type()).to_lowercase())
+            })
+            .collect();
+        let mut message = match segments.is_empty() {
+            true => "Waiting for segments".to_owned(),
+            false => format!("Downloaded {}", segments.join(", ")),
+        };
+        debug!("{message}");
+        if config.show_retries {
+            message += &format!(" | Retries: {}", state.lock().await.retries);
+        }
+        pb.set_message(message);
         pb.tick();
+
+        // Stop everything once a limit is reached, then return as if cancelled
+        let live_duration = latest_ts[0].map(|latest_t| {
+            let start_t = *live_start_t.get_or_insert(latest_t);
+            let elapsed_t = latest_t.saturating_sub(start_t);
+            Duration::from_secs_f64(elapsed_t as f64 / reps[0].timescale() as f64)
+        });
+        let bytes_downloaded = state.lock().await.bytes_downloaded;
+        if let Some(reason) = limit_reached(config, live_duration, bytes_downloaded) {
+            info!("{reason}, stopping download");
+            config.cancel.cancel();
+            break Ok(());
+        }
         
-        // Finish if stream ended
+        // Finish if stream ended, after waiting for late segments
         if manifest.finished {
-            break Ok(());
+            if finished_at.is_none() {
+                ProgressEvent::StreamEnded.emit(events, config);
+            }
+            let finished_at = *finished_at.get_or_insert_with(Instant::now);
+            if finished_at.elapsed() >= config.post_finish_grace {
+                break Ok(());
+            }
         }
     };
 
@@ -70,14 +256,95 @@ pub async fn download_forwards(
     ret
 }
 
-fn check_overlap(rep: &Representation, latest_t: usize, pb: &ProgressBar) {
-    if !rep
-        .segment_template
-        .segment_timeline
-        .segments
-        .iter()
-        .any(|s| s.t == latest_t)
-    {
-        pb.println(format!("Possible missed live segment t={latest_t}"));
+/// Whether `e` is a refused request, as when a signed URL expires
+fn is_forbidden(e: &anyhow::Error) -> bool {
+    matches!(e.downcast_ref(), Some(IgLiveError::StatusError(401 | 403, _)))
+}
+
+fn check_overlap(rep: &Representation, latest_t: usize) {
+    if !rep.segments().iter().any(|s| s.t == latest_t) {
+        warn!("Possible missed live segment t={latest_t}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mp4::tests::{init, moof, mp4_box};
+    use crate::state::InitSegment;
+    use crate::test_util;
+
+    #[tokio::test]
+    async fn split_writes_continuation_segments_after_their_init() {
+        let manifest = r#"<MPD loapStreamId="2" publishFrameTime="0">
+            <Period>
+                <AdaptationSet>
+                    <Representation id="video" mimeType="video/mp4" bandwidth="2000000">
+                        <SegmentTemplate initialization="video-init.m4v" media="video-$Time$.m4v"
+                            timescale="1000">
+                            <SegmentTimeline><S t="12000" d="1000"/></SegmentTimeline>
+                        </SegmentTemplate>
+                    </Representation>
+                </AdaptationSet>
+            </Period>
+        </MPD>"#;
+        let (old_init, new_init) = (init(1000, 40), init(1000, 50));
+        let segment = [moof(12000, 0, 20, None), mp4_box(b"mdat", &[0; 4])].concat();
+        let (url, _) = test_util::serve_with({
+            let (new_init, segment) = (new_init.clone(), segment.clone());
+            move |path| match path {
+                p if p.ends_with(".mpd") => (200, "application/dash+xml", manifest.into()),
+                p if p.ends_with("-init.m4v") => (200, "video/mp4", new_init.clone()),
+                _ => (200, "video/mp4", segment.clone()),
+            }
+        })
+        .await;
+
+        // The previous stream's segments were downloaded after its own initialization segment
+        let mut state = State::new();
+        state.ids.push("1".to_owned());
+        state
+            .downloaded_init
+            .insert(MediaType::Video, InitSegment::Memory(old_init));
+        state
+            .downloaded_segs
+            .get_mut(&MediaType::Video)
+            .unwrap()
+            .insert(10000);
+        let dir = test_util::temp_dir("split").join("segments");
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = DownloadConfig {
+            id_change_policy: IdChangePolicy::Split,
+            // Read segment times from their boxes
+            ffmpeg_path: Some(dir.join("missing-ffmpeg")),
+            ..Default::default()
+        };
+
+        let continuation = dir.parent().unwrap().join("continuation-2");
+        let written = continuation.join("segments/video-12000.m4v");
+        let (client, events) = (Client::new(), ProgressSender::default());
+        let forwards = download_forwards(
+            Arc::new(Mutex::new(state)),
+            &client,
+            &url,
+            &dir,
+            ProgressBar::hidden(),
+            &config,
+            &events,
+        );
+        let stop = async {
+            let wait = async {
+                while !written.exists() {
+                    time::sleep(Duration::from_millis(50)).await;
+                }
+            };
+            let _ = time::timeout(Duration::from_secs(10), wait).await;
+            config.cancel.cancel();
+        };
+        let (result, ()) = tokio::join!(forwards, stop);
+        result.unwrap();
+
+        assert_eq!(std::fs::read(continuation.join("video-init.m4v")).unwrap(), new_init);
+        assert_eq!(std::fs::read(written).unwrap(), [new_init, segment].concat());
     }
 }
@@ -0,0 +1,162 @@
+// download/gaps.rs
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::Path;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+use super::reconcile::parse_segment_file_name;
+use crate::mpd::{MediaType, Representation};
+
+const GAPS_FILE_NAME: &str = "gaps.json";
+
+/// Stream time missing from a download, written alongside its segments as `gaps.json`
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct GapReport {
+    /// Missing time of each downloaded track
+    pub tracks: Vec<TrackGaps>,
+}
+
+/// Missing time of one track
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TrackGaps {
+    /// Media type of the track
+    pub media_type: MediaType,
+
+    /// Number of segment files downloaded
+    pub segments: usize,
+
+    /// Seconds from the start of the stream to the end of the latest segment
+    pub total_secs: f64,
+
+    /// Seconds missing in total, including before the earliest segment
+    pub missing_secs: f64,
+
+    /// Most common segment duration, in segment time `t`
+    #[serde(default)]
+    pub typical_d: usize,
+
+    /// Missing time ranges, in order
+    pub gaps: Vec<Gap>,
+}
+
+/// Missing time range
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Gap {
+    /// Segment time `t` the gap starts at
+    pub start_t: usize,
+
+    /// Segment time `t` of the segment following the gap
+    pub end_t: usize,
+
+    /// Seconds from the start of the stream to the gap
+    pub offset_secs: f64,
+
+    /// Length of the gap in seconds
+    pub duration_secs: f64,
+}
+
+impl GapReport {
+    /// Find the gaps between the segment files of `reps` in `dir`, and before the earliest one
+    /// since `start_frame`.
+    /// Durations are taken from `durations` where known, otherwise the most common duration is
+    /// assumed. Gaps shorter than half a segment are jitter, not missing segments.
+    pub async fn new(
+        dir: impl AsRef<Path>,
+        reps: &[&Representation],
+        start_frame: usize,
+        durations: &HashMap<MediaType, BTreeMap<usize, usize>>,
+    ) -> Result<Self> {
+        let mut times: HashMap<MediaType, BTreeSet<usize>> = HashMap::new();
+        let mut entries = fs::read_dir(dir.as_ref()).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            if let Some((media_type, t)) =
+                parse_segment_file_name(&entry.file_name().to_string_lossy())
+            {
+                times.entry(media_type).or_default().insert(t);
+            }
+        }
+
+        let empty = BTreeMap::new();
+        let tracks = reps
+            .iter()
+            .filter_map(|rep| {
+                let media_type = rep.media_type();
+                let times = times.get(&media_type)?;
+                let durations = durations.get(&media_type).unwrap_or(&empty);
+                Some(track_gaps(
+                    media_type,
+                    times,
+                    durations,
+                    start_frame,
+                    rep.timescale(),
+                ))
+            })
+            .collect();
+        Ok(Self { tracks })
+    }
+
+    /// Read the report written to `dir`
+    pub async fn read(dir: impl AsRef<Path>) -> Result<Self> {
+        let data = fs::read(dir.as_ref().join(GAPS_FILE_NAME)).await?;
+        Ok(serde_json::from_slice(&data)?)
+    }
+
+    /// Write the report to `gaps.json` in `dir`
+    pub async fn write(&self, dir: impl AsRef<Path>) -> Result<()> {
+        let data = serde_json::to_vec_pretty(self)?;
+        fs::write(dir.as_ref().join(GAPS_FILE_NAME), data).await?;
+        Ok(())
+    }
+}
+
+fn track_gaps(
+    media_type: MediaType,
+    times: &BTreeSet<usize>,
+    durations: &BTreeMap<usize, usize>,
+    start_frame: usize,
+    timescale: usize,
+) -> TrackGaps {
+    // Most common duration, or delta between segments if no duration is known, preferring the
+    // shorter one on ties
+    let mut counts: HashMap<usize, usize> = HashMap::new();
+    for &d in durations.values().filter(|&&d| d > 0) {
+        *counts.entry(d).or_insert(0) += 1;
+    }
+    if counts.is_empty() {
+        let deltas = times.iter().zip(times.iter().skip(1)).map(|(a, b)| b - a);
+        for d in deltas {
+            *counts.entry(d).or_insert(0) += 1;
+        }
+    }
+    let typical = counts
+        .into_iter()
+        .max_by_key(|&(d, count)| (count, std::cmp::Reverse(d)))
+        .map_or(0, |(d, _)| d);
+    let secs = |t: usize| t as f64 / timescale.max(1) as f64;
+
+    let mut gaps = vec![];
+    let mut end = start_frame;
+    for &t in times {
+        if t > end + typical / 2 {
+            gaps.push(Gap {
+                start_t: end,
+                end_t: t,
+                offset_secs: secs(end.saturating_sub(start_frame)),
+                duration_secs: secs(t - end),
+            });
+        }
+        end = end.max(t + durations.get(&t).copied().unwrap_or(typical));
+    }
+
+    TrackGaps {
+        media_type,
+        segments: times.len(),
+        total_secs: secs(end.saturating_sub(start_frame)),
+        missing_secs: gaps.iter().map(|gap| gap.duration_secs).sum(),
+        typical_d: typical,
+        gaps,
+    }
+}
@@ -0,0 +1,70 @@
+use std::future::{Future, IntoFuture};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use anyhow::Result;
+use futures::Stream;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+use super::{DownloadReport, ProgressEvent};
+
+/// Download running in the background, started by [download][super::download].
+///
+/// Poll it as a [Stream] of [ProgressEvent]s, which ends once the download finishes, then await
+/// it for the [DownloadReport]. Up to 1024 events that aren't received yet are buffered, later
+/// ones are dropped. Events stop being buffered once the handle is awaited.
+/// Dropping the handle doesn't stop the download, call [DownloadHandle::cancel] first.
+#[derive(Debug)]
+pub struct DownloadHandle {
+    events: mpsc::Receiver<ProgressEvent>,
+    cancel: CancellationToken,
+    task: JoinHandle<Result<DownloadReport>>,
+}
+
+impl DownloadHandle {
+    pub(crate) fn new(
+        events: mpsc::Receiver<ProgressEvent>,
+        cancel: CancellationToken,
+        task: JoinHandle<Result<DownloadReport>>,
+    ) -> Self {
+        Self {
+            events,
+            cancel,
+            task,
+        }
+    }
+
+    /// Stop downloading early. No new segments are requested and in-flight downloads are
+    /// allowed to finish, then the download completes as usual with what was downloaded.
+    /// Same as cancelling [DownloadConfig::cancel][super::DownloadConfig::cancel].
+    pub fn cancel(&self) {
+        self.cancel.cancel();
+    }
+
+    /// Whether the download has finished, successfully or not.
+    pub fn is_finished(&self) -> bool {
+        self.task.is_finished()
+    }
+}
+
+impl Stream for DownloadHandle {
+    type Item = ProgressEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.events.poll_recv(cx)
+    }
+}
+
+impl IntoFuture for DownloadHandle {
+    type Output = Result<DownloadReport>;
+    type IntoFuture = Pin<Box<dyn Future<Output = Self::Output> + Send>>;
+
+    fn into_future(self) -> Self::IntoFuture {
+        // Events can't be received anymore
+        let Self { events, task, .. } = self;
+        drop(events);
+        Box::pin(async move { task.await? })
+    }
+}
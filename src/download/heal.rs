@@ -0,0 +1,200 @@
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::Result;
+use indicatif::ProgressBar;
+use reqwest::{Client, IntoUrl, Url};
+use tokio::sync::Mutex;
+use tracing::{debug, info};
+
+use super::backwards::probe_times;
+use super::gaps::GapReport;
+use super::initialization::download_reps_init;
+use super::progress::ProgressSender;
+use super::{build_client, DownloadConfig};
+use crate::error::IgLiveError;
+use crate::mpd::{MediaType, Mpd, Representation};
+use crate::state::{State, StateSnapshot};
+
+/// Most times checked for segments within a single gap
+const MAX_REQUESTS_PER_GAP: usize = 500;
+
+/// Number of the most common learned deltas checked, besides the typical segment duration
+const KNOWN_DELTAS: usize = 3;
+
+/// Search the gaps of an already downloaded stream once more, as with
+/// [DownloadConfig::heal_after]. Rewrites `gaps.json` and returns the gaps that remain.
+///
+/// # Arguments
+///
+/// * `mpd_url` - Full URL of the live stream's .mpd manifest, which must still be available.
+///   Fails with [IgLiveError::ManifestExpired] once it isn't.
+/// * `dir` - Download directory, containing the `segments` directory.
+/// * `config` - Options used for download, such as the selected tracks and retries.
+pub async fn heal(
+    mpd_url: impl IntoUrl,
+    dir: impl AsRef<Path>,
+    config: &DownloadConfig,
+) -> Result<GapReport> {
+    let dir = dir.as_ref();
+    let url_base = mpd_url.into_url()?;
+    let client = build_client(config)?;
+    let manifest = match Mpd::download_from_url(&client, &url_base).await {
+        Err(e) => match e.downcast_ref() {
+            Some(&IgLiveError::StatusError(status @ 400..=499, _)) => {
+                return Err(IgLiveError::ManifestExpired(status).into());
+            }
+            _ => return Err(e),
+        },
+        manifest => manifest?,
+    };
+    let reps = manifest.select_tracks(&config.media_selection())?;
+
+    // Search with the deltas learned while downloading
+    let snapshot = StateSnapshot::read(dir).await.ok();
+    let mut state = State::new();
+    if let Some(snapshot) = &snapshot {
+        state.restore(snapshot);
+    }
+    let state = Arc::new(Mutex::new(state));
+
+    // Segments are written along with their initialization segment
+    download_reps_init(
+        state.clone(),
+        &client,
+        &url_base,
+        reps.clone(),
+        dir,
+        config,
+        None,
+    )
+    .await?;
+
+    let segments_dir = dir.join("segments");
+    let durations = snapshot
+        .map(|snapshot| snapshot.durations)
+        .unwrap_or_default();
+    let report = GapReport::new(&segments_dir, &reps, manifest.start_frame, &durations).await?;
+    let pb = ProgressBar::hidden();
+    let found = heal_gaps(
+        &state,
+        &client,
+        &url_base,
+        &reps,
+        &report,
+        manifest.start_frame,
+        &segments_dir,
+        &pb,
+        config,
+        &ProgressSender::default(),
+    )
+    .await?;
+    info!("Found {found} segment(s) in gaps");
+
+    let report = GapReport::new(&segments_dir, &reps, manifest.start_frame, &durations).await?;
+    report.write(dir).await?;
+    Ok(report)
+}
+
+/// Search the gaps of `report` between downloaded segments once more, walking forward from the
+/// start of each and checking the times the next segment starts at after each known delta and
+/// its +33 and +67 jitter variants. Segments sometimes only appear on the CDN minutes after
+/// they were first missing.
+/// At most [MAX_REQUESTS_PER_GAP] times are checked per gap, the rest of the gap is given up on.
+/// The time before the earliest segment, since `start_frame`, isn't searched.
+/// Return the number of segments found.
+#[allow(clippy::too_many_arguments)]
+pub(super) async fn heal_gaps(
+    state: &Arc<Mutex<State>>,
+    client: &Client,
+    url_base: &Url,
+    reps: &[&Representation],
+    report: &GapReport,
+    start_frame: usize,
+    dir: &Path,
+    pb: &ProgressBar,
+    config: &DownloadConfig,
+    events: &ProgressSender,
+) -> Result<usize> {
+    let mut found = 0;
+    for track in &report.tracks {
+        let Some(rep) = reps.iter().find(|rep| rep.media_type() == track.media_type) else {
+            continue;
+        };
+        let typical = track.typical_d.max(1) as isize;
+        let offsets = {
+            let state = state.lock().await;
+            known_offsets(&state, &track.media_type, typical)
+        };
+        let mut visited = BTreeSet::new();
+
+        for gap in track.gaps.iter().filter(|gap| gap.start_t > start_frame) {
+            pb.set_message(format!(
+                "{:?} Healing gap at {}",
+                track.media_type, gap.start_t
+            ));
+            let mut requests = 0;
+            // The segment at the end of the gap is already downloaded
+            let mut expected = gap.start_t as isize;
+            while expected + typical / 2 < gap.end_t as isize {
+                if config.cancel.is_cancelled() {
+                    return Ok(found);
+                }
+                let times: Vec<isize> = offsets
+                    .iter()
+                    .map(|offset| expected + offset)
+                    .filter(|&t| t > 0 && t < gap.end_t as isize && !visited.contains(&t))
+                    .take(MAX_REQUESTS_PER_GAP - requests)
+                    .collect();
+                requests += times.len();
+                let probed = probe_times(
+                    state,
+                    client,
+                    url_base,
+                    rep,
+                    &times,
+                    &mut visited,
+                    dir,
+                    config,
+                    events,
+                );
+                expected = match probed.await? {
+                    Some(t) => {
+                        found += 1;
+                        t + typical
+                    }
+                    None => expected + typical,
+                };
+                if requests >= MAX_REQUESTS_PER_GAP {
+                    debug!(
+                        "{:?} Checked {requests} times in the gap at {}, giving up on it",
+                        track.media_type, gap.start_t
+                    );
+                    break;
+                }
+            }
+        }
+    }
+    Ok(found)
+}
+
+/// Offsets from where a segment is expected to start at, given the `typical` segment duration,
+/// at which it starts after the typical duration or one of the most common learned deltas of
+/// `media_type`, or their +33 and +67 jitter variants. Ordered by the delta's rank and
+/// deduplicated.
+fn known_offsets(state: &State, media_type: &MediaType, typical: isize) -> Vec<isize> {
+    let mut offsets = Vec::new();
+    for delta in [typical]
+        .into_iter()
+        .chain(state.top_deltas(media_type, KNOWN_DELTAS))
+    {
+        let base = delta - delta % 100;
+        for d in [delta, base, base + 33, base + 67] {
+            if d > 0 && !offsets.contains(&(d - typical)) {
+                offsets.push(d - typical);
+            }
+        }
+    }
+    offsets
+}
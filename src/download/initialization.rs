@@ -1,20 +1,27 @@
+use std::path::Path;
 use std::sync::Arc;
 
 use anyhow::Result;
 use futures::future;
 use indicatif::ProgressBar;
 use reqwest::{Client, StatusCode, Url};
+use tokio::fs;
 use tokio::sync::Mutex;
+use tracing::warn;
 
+use super::{check_content_type, segment_path, DownloadConfig};
 use crate::error::IgLiveError;
+use crate::mp4;
 use crate::mpd::Representation;
-use crate::state::State;
+use crate::state::{InitSegment, State};
 
 pub async fn download_reps_init(
     state: Arc<Mutex<State>>,
     client: &Client,
     url_base: &Url,
     reps: impl IntoIterator<Item = &Representation>,
+    dir: impl AsRef<Path>,
+    config: &DownloadConfig,
     pb: Option<ProgressBar>,
 ) -> Result<()> {
     if let Some(pb) = pb.as_ref() {
@@ -23,7 +30,7 @@ pub async fn download_reps_init(
 
     let futures: Vec<_> = reps
         .into_iter()
-        .map(|rep| download_init(state.clone(), client, url_base, rep))
+        .map(|rep| download_init(&state, client, rep, url_base, dir.as_ref(), config))
         .collect();
     future::join_all(futures)
         .await
@@ -37,18 +44,61 @@ pub async fn download_reps_init(
     Ok(())
 }
 
-async fn download_init(
-    state: Arc<Mutex<State>>,
+/// Download the initialization segment of a representation into `dir`, unless `state` already
+/// has one for its media type, and store it in [State::downloaded_init], in memory if
+/// [DownloadConfig::init_in_memory] is set.
+pub(super) async fn download_init(
+    state: &Arc<Mutex<State>>,
     client: &Client,
-    url_base: &Url,
     rep: &Representation,
+    url_base: &Url,
+    dir: &Path,
+    config: &DownloadConfig,
 ) -> Result<()> {
     let media_type = rep.media_type();
     if state.lock().await.downloaded_init.contains_key(&media_type) {
         return Ok(());
     }
 
-    let url = url_base.join(&rep.segment_template.initialization_path)?;
+    let buffer = fetch_init(client, rep, url_base, dir).await?;
+    let init = if config.init_in_memory {
+        InitSegment::Memory(buffer)
+    } else {
+        InitSegment::Disk(segment_path(dir, &rep.initialization_url(url_base)?)?)
+    };
+    state.lock().await.downloaded_init.insert(media_type, init);
+
+    Ok(())
+}
+
+/// Download the initialization segment of a representation into `dir`.
+/// Returns the segment data.
+///
+/// If the segment was already downloaded into `dir`, it is read from there instead of being
+/// downloaded again, unless it isn't a valid initialization segment, e.g. because writing it was
+/// interrupted.
+///
+/// # Arguments
+///
+/// * `client` - Client to download with.
+/// * `rep` - Representation to download the initialization segment of.
+/// * `url_base` - URL of the manifest `rep` is from.
+/// * `dir` - Directory to write the initialization segment to.
+pub async fn fetch_init(
+    client: &Client,
+    rep: &Representation,
+    url_base: &Url,
+    dir: impl AsRef<Path>,
+) -> Result<Vec<u8>> {
+    let url = rep.initialization_url(url_base)?;
+    let path = segment_path(dir.as_ref(), &url)?;
+    if let Ok(buffer) = fs::read(&path).await {
+        match mp4::check_init(&buffer) {
+            Ok(()) => return Ok(buffer),
+            Err(e) => warn!("Downloading {path:?} again, it is invalid ({e})"),
+        }
+    }
+
     let resp = client.get(url.as_str()).send().await?;
     if resp.status() == StatusCode::NOT_FOUND {
         return Err(IgLiveError::StatusNotFound.into());
@@ -56,14 +106,76 @@ async fn download_init(
     if !resp.status().is_success() {
         return Err(IgLiveError::StatusError(resp.status().into(), url.as_str().to_owned()).into());
     }
+    check_content_type(&resp)?;
 
     let buffer: Vec<_> = resp.bytes().await?.into_iter().collect();
+    // Written whole or not at all, a partial file is left behind as .part
+    let mut part_path = path.clone().into_os_string();
+    part_path.push(".part");
+    fs::write(&part_path, &buffer).await?;
+    fs::rename(&part_path, &path).await?;
 
-    state
-        .lock()
-        .await
-        .downloaded_init
-        .insert(media_type, buffer);
+    Ok(buffer)
+}
 
-    Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mp4::tests::init;
+    use crate::mpd::MediaType;
+    use crate::test_util::{manifest, serve, temp_dir};
+    use std::sync::atomic::Ordering;
+
+    #[tokio::test]
+    async fn init_is_fetched_once_and_reused() {
+        let (url_base, requests) = serve(200, "video/mp4", init(1000, 100)).await;
+        let manifest = manifest(&[1000, 3000]);
+        let rep = manifest.select_tracks(&Default::default()).unwrap()[0];
+        let dir = temp_dir("init-reuse");
+        let (client, config) = (Client::new(), DownloadConfig::default());
+
+        let state = Arc::new(Mutex::new(State::new()));
+        for _ in 0..2 {
+            download_init(&state, &client, rep, &url_base, &dir, &config)
+                .await
+                .unwrap();
+        }
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        assert!(state
+            .lock()
+            .await
+            .downloaded_init
+            .contains_key(&MediaType::Video));
+
+        // A later run reads it from disk
+        let state = Arc::new(Mutex::new(State::new()));
+        download_init(&state, &client, rep, &url_base, &dir, &config)
+            .await
+            .unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        assert!(state
+            .lock()
+            .await
+            .downloaded_init
+            .contains_key(&MediaType::Video));
+        fs::remove_dir_all(dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn invalid_init_on_disk_is_downloaded_again() {
+        let (url_base, requests) = serve(200, "video/mp4", init(1000, 100)).await;
+        let manifest = manifest(&[1000, 3000]);
+        let rep = manifest.select_tracks(&Default::default()).unwrap()[0];
+        let dir = temp_dir("init-invalid");
+        let path = segment_path(&dir, &rep.initialization_url(&url_base).unwrap()).unwrap();
+        fs::write(&path, b"trunc").await.unwrap();
+
+        let data = fetch_init(&Client::new(), rep, &url_base, &dir)
+            .await
+            .unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        assert_eq!(data, init(1000, 100));
+        assert_eq!(fs::read(&path).await.unwrap(), data);
+        fs::remove_dir_all(dir).await.unwrap();
+    }
 }
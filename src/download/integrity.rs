@@ -0,0 +1,89 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+use super::reconcile::parse_segment_file_name;
+use crate::mpd::{MediaType, Mpd};
+use crate::util::sha256_hex;
+
+const INTEGRITY_FILE_NAME: &str = "integrity.json";
+
+/// Checksums and timeline of a completed download, written alongside its segments as a
+/// tamper-evident record of what was captured
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct IntegrityManifest {
+    /// URL of the manifest the stream was downloaded from
+    pub source_url: String,
+
+    /// SHA-256 of the manifest as first downloaded, as hex
+    pub manifest_sha256: String,
+
+    /// Downloaded segments, ordered by media type and time
+    pub segments: Vec<SegmentRecord>,
+}
+
+/// Checksum and position of a downloaded segment file
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SegmentRecord {
+    /// File name in the segments directory
+    pub file_name: String,
+
+    pub media_type: MediaType,
+
+    /// Start time
+    pub t: usize,
+
+    /// Duration, if known
+    pub d: Option<usize>,
+
+    /// File size in bytes
+    pub size: u64,
+
+    /// SHA-256 of the file as written, as hex
+    pub sha256: String,
+}
+
+impl IntegrityManifest {
+    /// Checksum the segments in `dir`.
+    /// Durations are taken from `durations`, by media type and start time.
+    pub async fn new(
+        source_url: &str,
+        manifest: &Mpd,
+        dir: impl AsRef<Path>,
+        durations: &HashMap<MediaType, BTreeMap<usize, usize>>,
+    ) -> Result<Self> {
+        let mut segments = vec![];
+        let mut entries = fs::read_dir(dir.as_ref()).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let file_name = entry.file_name().to_string_lossy().to_string();
+            let Some((media_type, t)) = parse_segment_file_name(&file_name) else {
+                continue;
+            };
+            let data = fs::read(entry.path()).await?;
+            segments.push(SegmentRecord {
+                d: durations.get(&media_type).and_then(|d| d.get(&t)).copied(),
+                file_name,
+                media_type,
+                t,
+                size: data.len() as u64,
+                sha256: sha256_hex(&data),
+            });
+        }
+        segments.sort_by_key(|s| (s.media_type != MediaType::Video, s.t));
+
+        Ok(Self {
+            source_url: source_url.to_owned(),
+            manifest_sha256: manifest.sha256.clone(),
+            segments,
+        })
+    }
+
+    pub async fn write(&self, dir: impl AsRef<Path>) -> Result<()> {
+        let data = serde_json::to_vec_pretty(self)?;
+        fs::write(dir.as_ref().join(INTEGRITY_FILE_NAME), data).await?;
+        Ok(())
+    }
+}
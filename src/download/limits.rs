@@ -0,0 +1,90 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::Utc;
+use indicatif::HumanBytes;
+use tokio::sync::Mutex;
+use tokio::time;
+use tracing::info;
+
+use crate::download::DownloadConfig;
+use crate::state::State;
+
+/// Time between checks of the size and stop time limits
+const LIMIT_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Describe the limit of `config` that was reached, if any.
+/// The duration limit only applies to live downloads, given their `live_duration`.
+pub(super) fn limit_reached(
+    config: &DownloadConfig,
+    live_duration: Option<Duration>,
+    bytes_downloaded: u64,
+) -> Option<String> {
+    let live_duration = live_duration.unwrap_or_default();
+    if let Some(max) = config.max_duration.filter(|&max| live_duration >= max) {
+        return Some(format!("Downloaded {}s of the stream live", max.as_secs()));
+    }
+    if let Some(max) = config.max_size.filter(|&max| bytes_downloaded >= max) {
+        return Some(format!("Downloaded {} of segments", HumanBytes(max)));
+    }
+    if let Some(stop_at) = config.stop_at.filter(|&stop_at| Utc::now() >= stop_at) {
+        return Some(format!("Reached stop time {}", stop_at.to_rfc3339()));
+    }
+    None
+}
+
+/// Cancel the download once the size or stop time limit is reached, so live and past segment
+/// downloads stop alike
+pub(super) async fn watch_limits(state: Arc<Mutex<State>>, config: Arc<DownloadConfig>) {
+    let mut interval = time::interval(LIMIT_CHECK_INTERVAL);
+    while !config.cancel.is_cancelled() {
+        interval.tick().await;
+        let bytes_downloaded = state.lock().await.bytes_downloaded;
+        if let Some(reason) = limit_reached(&config, None, bytes_downloaded) {
+            info!("{reason}, stopping download");
+            config.cancel.cancel();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeDelta;
+
+    use super::*;
+
+    #[test]
+    fn limits_are_reached_at_their_value() {
+        let config = DownloadConfig {
+            max_duration: Some(Duration::from_secs(60)),
+            max_size: Some(1000),
+            ..Default::default()
+        };
+        assert_eq!(
+            limit_reached(&config, Some(Duration::from_secs(59)), 999),
+            None
+        );
+        assert!(limit_reached(&config, Some(Duration::from_secs(60)), 0).is_some());
+        assert!(limit_reached(&config, None, 1000).is_some());
+        // Past segment downloads have no live duration
+        assert_eq!(limit_reached(&config, None, 0), None);
+
+        let config = DownloadConfig {
+            stop_at: Some(Utc::now() - TimeDelta::seconds(1)),
+            ..Default::default()
+        };
+        assert!(limit_reached(&config, None, 0).is_some());
+    }
+
+    #[tokio::test]
+    async fn watcher_cancels_past_only_downloads() {
+        let state = Arc::new(Mutex::new(State::new()));
+        state.lock().await.bytes_downloaded = 2000;
+        let config = Arc::new(DownloadConfig {
+            max_size: Some(1000),
+            ..Default::default()
+        });
+        watch_limits(state, config.clone()).await;
+        assert!(config.cancel.is_cancelled());
+    }
+}
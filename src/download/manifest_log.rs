@@ -0,0 +1,69 @@
+use std::path::Path;
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+
+use crate::mpd::Representation;
+
+const MANIFEST_LOG_FILE_NAME: &str = "manifests.jsonl";
+
+/// Segments listed by one refresh of the manifest, logged as one line of JSON
+#[derive(Serialize, Debug)]
+pub struct ManifestSnapshot {
+    /// When the manifest was fetched
+    pub time: DateTime<Utc>,
+
+    /// Live stream ID
+    pub id: String,
+
+    /// Whether the manifest marked the stream as ended
+    pub finished: bool,
+
+    /// Listed video segments, if the manifest has video
+    pub video: Option<TimelineSnapshot>,
+
+    /// Listed audio segments, if the manifest has audio
+    pub audio: Option<TimelineSnapshot>,
+}
+
+/// Segments listed in one representation
+#[derive(Serialize, Debug)]
+pub struct TimelineSnapshot {
+    /// Number of segments
+    pub count: usize,
+
+    /// Start time of the first segment
+    pub first_t: Option<usize>,
+
+    /// Start time of the last segment
+    pub last_t: Option<usize>,
+}
+
+impl TimelineSnapshot {
+    pub fn new(rep: &Representation) -> Self {
+        let segments = rep.segments();
+        Self {
+            count: segments.len(),
+            first_t: segments.first().map(|s| s.t),
+            last_t: segments.last().map(|s| s.t),
+        }
+    }
+}
+
+impl ManifestSnapshot {
+    /// Append to the log in `dir`
+    pub async fn append(&self, dir: impl AsRef<Path>) -> Result<()> {
+        let mut line = serde_json::to_vec(self)?;
+        line.push(b'\n');
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.as_ref().join(MANIFEST_LOG_FILE_NAME))
+            .await?;
+        file.write_all(&line).await?;
+        Ok(())
+    }
+}
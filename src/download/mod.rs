@@ -1,28 +1,88 @@
+mod audio_tracks;
 mod backwards;
+mod compat;
+mod ffconcat;
 mod forwards;
+mod gaps;
+mod handle;
+mod heal;
 mod initialization;
+mod integrity;
+mod limits;
+mod manifest_log;
+mod pause;
+pub(crate) mod pipe;
+mod preview;
+mod progress;
+pub(crate) mod priority;
+mod rate_limit;
+pub(crate) mod reconcile;
+mod refresh;
+mod template;
+mod thumbnail;
 
+use std::collections::HashMap;
+use std::io::IsTerminal;
+use std::net::{IpAddr, SocketAddr};
 use std::path::{Path, PathBuf};
 use std::pin::Pin;
+#[cfg(feature = "metrics")]
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use anyhow::Result;
 use bitflags::bitflags;
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use flate2::write::GzEncoder;
+use flate2::Compression;
 use futures::{future, Future};
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
-use reqwest::{Client, IntoUrl, StatusCode, Url};
+use indicatif::{HumanBytes, MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE};
+use reqwest::{Client, IntoUrl, Proxy, Response, StatusCode, Url, Version};
 use tokio::fs;
 use tokio::io::AsyncWriteExt;
-use tokio::sync::Mutex;
+use tokio::sync::{mpsc, Mutex};
+use tokio::time;
+use tracing::{info, warn};
+pub use tokio_util::sync::CancellationToken;
 
 use self::backwards::download_reps_backwards;
+use self::compat::check_compatibility;
+use self::ffconcat::write_ffconcat;
 use self::forwards::download_forwards;
+pub use self::gaps::{Gap, GapReport, TrackGaps};
+pub use self::handle::DownloadHandle;
+pub use self::heal::heal;
+use self::heal::heal_gaps;
+pub use self::initialization::fetch_init;
 use self::initialization::download_reps_init;
-use crate::error::IgLiveError;
-use crate::mpd::{MediaType, Mpd, Representation};
-use crate::pts::get_pts;
+pub use self::pause::PauseSwitch;
+use self::integrity::IntegrityManifest;
+use self::limits::watch_limits;
+use self::pipe::LivePipe;
+pub use self::preview::download_preview;
+pub use self::progress::{ProgressEvent, ProgressOutput};
+pub use self::rate_limit::RateLimiter;
+use self::progress::{ProgressSender, EVENT_BUFFER_SIZE};
+use self::priority::PrioritySemaphore;
+use self::reconcile::{reconcile_segments, trailing_number};
+use self::template::{render_output_template, TemplateValues};
+use self::thumbnail::download_thumbnails;
+use crate::error::{redact_urls, IgLiveError};
+use crate::hooks::{Hook, HookEvent, HookEventKind};
+use crate::metadata::Metadata;
+#[cfg(feature = "metrics")]
+use crate::metrics::METRICS;
+use crate::mpd::{
+    BandwidthRange, FrameRatePreference, MediaSelection, MediaType, Mpd, Quality, Representation,
+    Segment, TemplateVariable,
+};
+use crate::probe_cache::ProbeCache;
+use crate::pts::{ffprobe_path, get_pts};
 use crate::state::State;
+pub use crate::state::StateSnapshot;
 
 /// Options for download
 #[derive(Clone, Debug)]
@@ -31,11 +91,399 @@ pub struct DownloadConfig {
     /// If `None`, auto generate directory based on live stream ID.
     pub dir: Option<PathBuf>,
 
+    /// Place segments in a subdirectory of `dir` named after the live stream ID.
+    pub stream_subdir: bool,
+
+    /// Name the download directory by this template instead of the live stream ID, within `dir`
+    /// if set, e.g. `"{username}/{date}_{time}_{stream_id}"`. `/` separates directories.
+    /// Placeholders are `{username}`, `{stream_id}`, `{date}`, `{time}` and `{resolution}`,
+    /// see [DownloadConfig::username].
+    pub output_template: Option<String>,
+
+    /// Instagram username of the streaming account for output templates, and to look up a fresh
+    /// manifest URL once the original expires, if known.
+    pub username: Option<String>,
+
+    /// Session cookies of a logged-in account, used to look up a fresh manifest URL for
+    /// `username`. Instagram usually only answers logged-in requests.
+    pub cookie: Option<String>,
+
+    /// Shell command printing a fresh manifest URL, run when refreshing the manifest is
+    /// forbidden because the URL's token expired. Takes precedence over looking up `username`.
+    pub refresh_command: Option<String>,
+
+    /// Add [INCOMPLETE_SUFFIX] to the directory name.
+    /// Rename it with [complete_dir_path] once processing is complete.
+    pub mark_incomplete: bool,
+
+    /// Host names to resolve to fixed IP addresses instead of using DNS, e.g. to pin a CDN edge.
+    pub resolve: Vec<(String, IpAddr)>,
+
+    /// Number of times a segment request is retried after a server error, rate limiting,
+    /// timeout or connection error. 404 responses aren't retried.
+    pub retries: usize,
+
+    /// Delay before the first retry of a segment request, doubled for every further retry.
+    pub retry_delay: Duration,
+
+    /// Limit of the bytes per second downloaded across all live and past segment downloads.
+    /// Downloads sharing a limiter are limited together. `None` doesn't limit the rate.
+    pub rate_limiter: Option<Arc<RateLimiter>>,
+
+    /// Extra HTTP headers sent with every manifest and segment request, e.g. cookies.
+    pub headers: Vec<(String, String)>,
+
+    /// User agent sent with every request instead of reqwest's default.
+    pub user_agent: Option<String>,
+
+    /// HTTP, HTTPS or SOCKS5 proxy URL to send every request through, e.g.
+    /// `socks5h://127.0.0.1:1080`.
+    /// If `None`, the proxy set in the `HTTP_PROXY`, `HTTPS_PROXY` or `ALL_PROXY` environment
+    /// variables is used, if any.
+    pub proxy: Option<String>,
+
+    /// Request segments over HTTP/3 (QUIC) first, falling back to HTTP/1.1 or HTTP/2 for the
+    /// rest of the download once connecting over it fails.
+    /// HTTP/3 requests don't go through proxies, so the download fails with
+    /// [IgLiveError::Http3WithProxy] if `proxy` or a proxy environment variable is set.
+    /// Only takes effect if built with the `http3` feature and `RUSTFLAGS="--cfg
+    /// reqwest_unstable"`.
+    pub http3: bool,
+
     /// Choose whether to download live segments or past segments.
     pub segments: DownloadSegments,
+
+    /// Prefer video with the highest or lowest frame rate over the highest bandwidth.
+    pub frame_rate: Option<FrameRatePreference>,
+
+    /// Quality of the video and audio to download, among those matching the other selection
+    /// options.
+    pub quality: Quality,
+
+    /// Only consider video of this video adaptation set, if the manifest offers several
+    /// (e.g. different crops). See [Representation::adaptation_set].
+    pub video_set: Option<usize>,
+
+    /// Only consider video with a bandwidth within this range, picking the highest within it.
+    pub bandwidth: Option<BandwidthRange>,
+
+    /// Also download live segments of every other audio representation, e.g. a separate
+    /// commentary track, to be merged as additional audio streams.
+    /// Past segments are only searched for the selected audio representation.
+    pub all_audio: bool,
     
     /// Number of past segments to check in parallel
     pub parallel_candidates: usize,
+
+    /// Maximum number of past segments checked in parallel around the same delta, so other
+    /// deltas are checked as well. If `None`, a single delta may take all of
+    /// `parallel_candidates`.
+    pub limit_candidates_per_delta: Option<usize>,
+
+    /// Give live segment downloads priority over past segment downloads, which share a budget of
+    /// `parallel_candidates` concurrent downloads. Live segments expire from the manifest, so
+    /// without this past segments can crowd them out on slow connections.
+    pub prioritize_live: bool,
+
+    /// Number of the concurrent downloads shared with `prioritize_live` that past segments
+    /// never take, so a new live segment can start right away.
+    pub reserved_live_downloads: usize,
+
+    /// Check past segments one at a time, most common delta first.
+    /// Slower, but wastes fewer requests on nonexistent segments.
+    /// Overrides `parallel_candidates`.
+    pub sequential: bool,
+
+    /// Split the search for past segments into this many time windows, searched in parallel
+    /// once a segment is found near the top of each. Speeds up the search of long streams, but
+    /// found segments aren't checked for PTS continuity if more than `1`.
+    pub backwards_windows: usize,
+
+    /// Maximum number of past segments downloaded in parallel by each search, unless
+    /// `sequential`.
+    pub backwards_concurrency: usize,
+
+    /// Farthest offset from each known delta at which past segments are searched for before
+    /// assuming segments are missing.
+    pub search_range: usize,
+
+    /// Time searched again below each gap with `reprobe_gaps`, and the delta between segments
+    /// assumed before any is known.
+    pub assumed_missing_delta: usize,
+
+    /// Number of gaps in a row, with no segment found between them, after which the search for
+    /// past segments gives up.
+    pub max_consecutive_skips: usize,
+
+    /// Stop the whole download if searching past segments of one media type fails.
+    /// If `false`, the error is logged and the other media types and live segments continue, see
+    /// [DownloadReport::backwards_errors].
+    pub fail_fast: bool,
+
+    /// Start searching past segments one at a time and gradually allow more concurrent
+    /// downloads over this long, to avoid bursts of requests. Zero disables the ramp.
+    pub segment_concurrency_ramp: Duration,
+
+    /// Initial segment time deltas to search past segments with.
+    pub deltas_seed: DeltaSeed,
+
+    /// Number of newest live segments whose time deltas are ranked first when searching past
+    /// segments. `0` disables this.
+    pub warm_up_segments: usize,
+
+    /// Previous download directory to continue searching past segments from.
+    /// Its search position is read from the marker written alongside its segments.
+    pub resume_backwards_from: Option<PathBuf>,
+
+    /// Number of times a past segment found with a too early PTS is checked again before
+    /// skipping it.
+    pub max_pts_early_retries: usize,
+
+    /// Don't check past segment times that fall within already downloaded segments.
+    pub skip_covered: bool,
+
+    /// After searching for past segments, search each place where a segment appeared to be
+    /// missing once more, one candidate at a time, to recover segments that failed transiently.
+    pub reprobe_gaps: bool,
+
+    /// After downloading, wait this long, then search the gaps between downloaded segments
+    /// once more, checking the known deltas around where each missing segment is expected.
+    /// Segments sometimes only appear on the CDN minutes after they were first missing.
+    pub heal_after: Option<Duration>,
+
+    /// Before downloading into a directory with segments from a previous download, remove
+    /// partially written, duplicate and overlapping segments and continue from the remaining
+    /// ones instead of downloading them again.
+    pub dedupe_on_resume: bool,
+
+    /// Continue a download interrupted in the same directory.
+    /// Implies `dedupe_on_resume`, continues with the deltas saved in the directory's state, or
+    /// else ranks the deltas between the existing segments first, and continues searching past
+    /// segments from the marker left in the directory, if any.
+    pub resume: bool,
+
+    /// Write the download state next to the segments this often while downloading, so an
+    /// interrupted download can be resumed with its learned deltas. `None` only writes it after
+    /// downloading.
+    pub checkpoint_interval: Option<Duration>,
+
+    /// Remember past segment times that don't exist in the download directory, so later
+    /// downloads into the same directory don't check them again.
+    /// Ignored if the manifest URL changed since.
+    pub probe_cache: bool,
+
+    /// What to do when the stream is re-published under a new ID while downloading live.
+    pub id_change_policy: IdChangePolicy,
+
+    /// Keep downloading new live segments for this long after the stream is marked as ended.
+    pub post_finish_grace: Duration,
+
+    /// Stop downloading once this much of the stream was downloaded live.
+    /// Like cancelling, past segment downloads stop as well and the download returns normally.
+    pub max_duration: Option<Duration>,
+
+    /// Stop downloading once this many bytes of segments were downloaded, live and past
+    /// segments combined. Checked every second, so slightly more may be downloaded.
+    pub max_size: Option<u64>,
+
+    /// Stop downloading at this time.
+    pub stop_at: Option<DateTime<Utc>>,
+
+    /// Append the segments listed by each manifest refresh while downloading live to
+    /// `manifests.jsonl` in the download directory.
+    pub manifest_log: bool,
+
+    /// Number of times a manifest without segments is refreshed before giving up, when not
+    /// downloading live segments. When downloading live, it is refreshed until segments appear.
+    /// Also the number of times a manifest is refreshed when none of its segments of a media type
+    /// could be downloaded, because they were empty or not media.
+    pub empty_manifest_retries: usize,
+
+    /// Number of consecutive failed manifest refreshes tolerated while downloading live
+    /// before giving up.
+    pub max_manifest_failures: usize,
+
+    /// How to show progress.
+    pub progress: ProgressOutput,
+
+    /// Progress bars to draw into, shared by several downloads to show them together.
+    /// If `None`, the download draws its own.
+    pub progress_bars: Option<MultiProgress>,
+
+    /// Name shown before the download's progress bars, to tell several downloads apart.
+    pub label: Option<String>,
+
+    /// Notified once the download directory is created and downloading begins.
+    pub on_start: Option<Hook>,
+
+    /// Notified once downloading finished, before the download is merged, or failed.
+    pub on_end: Option<Hook>,
+
+    /// Token to stop downloading early.
+    /// When cancelled, no new segments are requested and in-flight downloads are allowed to
+    /// finish before returning.
+    pub cancel: CancellationToken,
+
+    /// Switch to pause requesting new segments and manifests.
+    /// While paused, in-flight downloads finish but nothing new is requested until resumed.
+    /// Live segments that leave the manifest while paused are missed.
+    pub pause: PauseSwitch,
+
+    /// Pause while this file exists and resume once it is removed.
+    pub pause_file: Option<PathBuf>,
+
+    /// Keep initialization segments in memory.
+    /// If `false`, they are read from the download directory for every segment instead, saving
+    /// memory when running many downloads at once.
+    pub init_in_memory: bool,
+
+    /// Show the number of segment download retries in progress and after download.
+    pub show_retries: bool,
+
+    /// Redact the query strings of signed URLs in the message of an error the download fails
+    /// with. Logged messages can be redacted with [redact_urls][crate::error::redact_urls] by
+    /// the tracing subscriber.
+    pub mask_urls: bool,
+
+    /// Log a line with the search position and amount downloaded this often, even when nothing
+    /// changed, so slow downloads don't look stuck.
+    pub heartbeat: Option<Duration>,
+
+    /// Check that the selected video and audio codecs can be merged before downloading segments.
+    pub check_compatibility: bool,
+
+    /// Path of the `ffmpeg` executable. Segments are probed with the `ffprobe` next to it.
+    /// If `None`, `ffprobe` is run from `$PATH`.
+    pub ffmpeg_path: Option<PathBuf>,
+
+    /// Named pipes to stream live video and audio segments to as they are downloaded, e.g. for
+    /// an external `ffmpeg` to mux live.
+    /// The initialization segment is written first, then segments in timeline order. Up to 10
+    /// out-of-order segments are held back waiting for earlier ones, after which the earliest is
+    /// written anyway. Segments arriving after later ones were written are not streamed.
+    /// Until a pipe is opened for reading, only its latest 10 segments are kept.
+    pub live_pipes: Option<(PathBuf, PathBuf)>,
+
+    /// Download thumbnail or poster images referenced in the manifest.
+    pub thumbnails: bool,
+
+    /// Write `video.ffconcat` and `audio.ffconcat` playlists with per-segment durations.
+    /// Playlists reference uncompressed segment file names, so are not usable together with
+    /// `compress_segments`.
+    pub ffconcat: bool,
+
+    /// Write `integrity.json` with the size, SHA-256, time and duration of every segment and
+    /// the source manifest when the download completes.
+    pub integrity: bool,
+
+    /// Segments smaller than this many bytes are treated as not yet available, to avoid writing
+    /// empty responses.
+    pub min_segment_size: usize,
+
+    /// Write segments gzip compressed, with an additional `.gz` extension.
+    /// [merge][crate::merge::merge] decompresses them transparently.
+    pub compress_segments: bool,
+
+    /// Name segments `video_<t>` and `audio_<t>` with zero-padded times, so they sort
+    /// lexically in the right order.
+    pub compat_filenames: bool,
+}
+
+impl Default for DownloadConfig {
+    fn default() -> Self {
+        Self {
+            dir: None,
+            stream_subdir: false,
+            output_template: None,
+            username: None,
+            cookie: None,
+            refresh_command: None,
+            mark_incomplete: false,
+            resolve: vec![],
+            retries: 3,
+            retry_delay: Duration::from_secs(1),
+            rate_limiter: None,
+            headers: vec![],
+            user_agent: None,
+            proxy: None,
+            http3: false,
+            segments: DownloadSegments::all(),
+            frame_rate: None,
+            quality: Quality::Best,
+            video_set: None,
+            bandwidth: None,
+            all_audio: false,
+            parallel_candidates: 10,
+            limit_candidates_per_delta: None,
+            prioritize_live: true,
+            reserved_live_downloads: 2,
+            sequential: false,
+            backwards_windows: 1,
+            backwards_concurrency: 10,
+            search_range: 1000,
+            assumed_missing_delta: 2000,
+            max_consecutive_skips: 5,
+            fail_fast: true,
+            segment_concurrency_ramp: Duration::ZERO,
+            deltas_seed: DeltaSeed::Default,
+            warm_up_segments: 0,
+            resume_backwards_from: None,
+            max_pts_early_retries: 3,
+            skip_covered: false,
+            reprobe_gaps: false,
+            heal_after: None,
+            dedupe_on_resume: false,
+            resume: false,
+            checkpoint_interval: Some(Duration::from_secs(30)),
+            probe_cache: false,
+            id_change_policy: IdChangePolicy::Continue,
+            post_finish_grace: Duration::ZERO,
+            max_duration: None,
+            max_size: None,
+            stop_at: None,
+            manifest_log: false,
+            empty_manifest_retries: 3,
+            max_manifest_failures: 5,
+            progress: ProgressOutput::Bars,
+            progress_bars: None,
+            label: None,
+            on_start: None,
+            on_end: None,
+            cancel: CancellationToken::new(),
+            pause: PauseSwitch::new(),
+            pause_file: None,
+            init_in_memory: true,
+            show_retries: false,
+            mask_urls: false,
+            heartbeat: None,
+            check_compatibility: false,
+            ffmpeg_path: None,
+            live_pipes: None,
+            thumbnails: false,
+            ffconcat: false,
+            integrity: false,
+            min_segment_size: 1,
+            compress_segments: false,
+            compat_filenames: false,
+        }
+    }
+}
+
+impl DownloadConfig {
+    /// Criteria to select the representations to download by.
+    pub fn media_selection(&self) -> MediaSelection {
+        MediaSelection {
+            quality: self.quality,
+            frame_rate: self.frame_rate,
+            video_set: self.video_set,
+            bandwidth: self.bandwidth,
+        }
+    }
+
+    /// `ffprobe` executable to probe segments with.
+    pub(crate) fn ffprobe_path(&self) -> PathBuf {
+        ffprobe_path(self.ffmpeg_path.as_deref())
+    }
 }
 
 bitflags! {
@@ -45,130 +493,845 @@ bitflags! {
         /// Download live segments.
         const LIVE = 0b00000001;
 
+        /// Download past video segments.
+        const PAST_VIDEO = 0b00000010;
+
+        /// Download past audio segments.
+        const PAST_AUDIO = 0b00000100;
+
         /// Download past segments.
-        const PAST = 0b00000010;
+        const PAST = Self::PAST_VIDEO.bits() | Self::PAST_AUDIO.bits();
     }
 }
 
-/// Download an IG live stream.
-/// Returns the download output path.
+/// Suffix of download directories that are still being written to
+pub const INCOMPLETE_SUFFIX: &str = ".incomplete";
+
+/// Path of a download directory without [INCOMPLETE_SUFFIX].
+pub fn complete_dir_path(dir: impl AsRef<Path>) -> PathBuf {
+    let dir = dir.as_ref();
+    match dir.to_str().and_then(|d| d.strip_suffix(INCOMPLETE_SUFFIX)) {
+        Some(d) => d.into(),
+        None => dir.to_path_buf(),
+    }
+}
+
+/// Strategy for seeding segment time deltas before searching past segments
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeltaSeed {
+    /// Built-in table of deltas common in IG live streams
+    Default,
+
+    /// Only deltas seen in the manifest, between consecutive segments and as their durations,
+    /// refined with the deltas between new live segments on every manifest refresh
+    Observed,
+
+    /// Built-in deltas, re-weighted around the durations and gaps measured by downloading the
+    /// newest few live segments
+    Calibrated,
+}
+
+/// Behavior when a stream's ID changes mid-broadcast
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IdChangePolicy {
+    /// Keep downloading into the same directory as the same stream
+    Continue,
+
+    /// Stop downloading live segments, so the new stream can be downloaded separately
+    Stop,
+
+    /// Keep downloading live segments of the new stream into a `continuation-{ID}` directory
+    /// inside the download directory, with its own initialization segments, so it can be
+    /// merged separately
+    Split,
+}
+
+/// Result of a download
+#[derive(Clone, Debug)]
+pub struct DownloadReport {
+    /// Download output path
+    pub path: PathBuf,
+
+    /// Time spent in each phase of the download
+    pub timings: Timings,
+
+    /// Stream time from the first to the last downloaded segment, if any were downloaded
+    pub duration: Option<Duration>,
+
+    /// Errors that stopped searching past segments early, by media type.
+    /// Only set if not [DownloadConfig::fail_fast], the search of other media types succeeded.
+    pub backwards_errors: HashMap<MediaType, String>,
+
+    /// Stream time missing from the download, also written to `gaps.json`
+    pub gaps: GapReport,
+}
+
+/// Wall-clock time spent in each phase of a download
+#[derive(Clone, Debug)]
+pub struct Timings {
+    /// Fetching the initial manifest
+    pub manifest: Duration,
+
+    /// Downloading live segments, if they were downloaded
+    pub live: Option<Duration>,
+
+    /// Searching past segments, by media type
+    pub backwards: HashMap<MediaType, Duration>,
+}
+
+/// Start downloading an IG live stream in the background.
+/// Returns a [DownloadHandle] streaming progress events, which resolves to the download output
+/// path and phase timings when awaited.
+/// Must be called from within a tokio runtime.
 ///
 /// # Arguments
 ///
 /// * `mpd_url` - Full URL of live stream's .mpd manifest.
-pub async fn download(mpd_url: impl IntoUrl, config: DownloadConfig) -> Result<PathBuf> {
+pub fn download(mpd_url: impl IntoUrl, config: DownloadConfig) -> DownloadHandle {
+    spawn_download(mpd_url.into_url(), None, config)
+}
+
+/// Start downloading an IG live stream in the background like [download], beginning with
+/// `manifest`, already downloaded from `mpd_url`, instead of downloading it again.
+pub fn download_with_manifest(
+    mpd_url: impl IntoUrl,
+    manifest: Mpd,
+    config: DownloadConfig,
+) -> DownloadHandle {
+    spawn_download(mpd_url.into_url(), Some(manifest), config)
+}
+
+fn spawn_download(
+    mpd_url: reqwest::Result<Url>,
+    manifest: Option<Mpd>,
+    config: DownloadConfig,
+) -> DownloadHandle {
+    let (events, receiver) = mpsc::channel(EVENT_BUFFER_SIZE);
+    let events = ProgressSender::new(events);
+    let cancel = config.cancel.clone();
+    let mask_urls = config.mask_urls;
+    let (on_end, username, dir) =
+        (config.on_end.clone(), config.username.clone(), config.dir.clone());
+    let task = tokio::spawn(async move {
+        let mut started = None;
+        let result = match mpd_url {
+            Ok(url) => download_stream(url, manifest, config, events, &mut started).await,
+            Err(e) => Err(e.into()),
+        };
+        // Keep the error downcastable, only replacing its message
+        let result = match result {
+            Err(e) if mask_urls => {
+                let message = redact_urls(&e.to_string());
+                Err(e.context(message))
+            }
+            result => result,
+        };
+
+        // Notify of the failure with as much as is known of the stream
+        if let (Err(e), Some(hook)) = (&result, on_end) {
+            let (stream_id, path) = match started {
+                Some((stream_id, path)) => (Some(stream_id), path),
+                None => (None, dir.unwrap_or_default()),
+            };
+            let event = HookEvent {
+                event: HookEventKind::End,
+                time: Utc::now(),
+                stream_id,
+                username,
+                path,
+                output: None,
+                missing_segments: None,
+                error: Some(e.to_string()),
+            };
+            hook.notify(&event).await;
+        }
+        result
+    });
+    DownloadHandle::new(receiver, cancel, task)
+}
+
+/// Download the stream at `url_base`, setting `started` to its ID and download directory once
+/// the directory is created
+async fn download_stream(
+    url_base: Url,
+    manifest: Option<Mpd>,
+    config: DownloadConfig,
+    events: ProgressSender,
+    started: &mut Option<(String, PathBuf)>,
+) -> Result<DownloadReport> {
+    #[cfg(feature = "metrics")]
+    let _active = ActiveDownload::start();
+    let config = Arc::new(config);
+
     // Reqwest client
-    let client = Client::builder().timeout(Duration::from_secs(10)).build()?;
+    if config.http3 && (config.proxy.is_some() || env_proxy_set()) {
+        return Err(IgLiveError::Http3WithProxy.into());
+    }
+    let client = build_client(&config)?;
+    if config.http3 && !cfg!(feature = "http3") {
+        warn!("Built without HTTP/3 support, using HTTP/1.1 or HTTP/2");
+    }
 
     // Download manifest
-    let url_base = mpd_url.into_url()?;
-    let manifest = Mpd::download_from_url(&client, url_base.clone()).await?;
-    let (video_rep, audio_rep) = manifest.best_media()?;
+    let manifest_start = Instant::now();
+    let manifest = match manifest {
+        Some(manifest) => manifest,
+        None => Mpd::download_from_url(&client, url_base.clone()).await?,
+    };
+    let manifest_time = manifest_start.elapsed();
+    let manifest = wait_for_segments(&client, &url_base, manifest, &config).await?;
+    let reps = manifest.select_tracks(&config.media_selection())?;
+    if let [rep] = reps[..] {
+        info!("Manifest only has {:?}, capturing a single track", rep.media_type());
+    }
 
     // Create directory
-    let base_dir_name: PathBuf = if let Some(d) = config.dir {
-        d
+    let name: PathBuf = match &config.output_template {
+        Some(template) => {
+            let video = reps.iter().find(|r| r.media_type() == MediaType::Video);
+            let values = TemplateValues {
+                username: config.username.as_deref(),
+                stream_id: &manifest.id,
+                start_time: manifest
+                    .segment_time(reps[0], manifest.start_frame)
+                    .unwrap_or_else(Utc::now),
+                resolution: video.and_then(|r| r.width.zip(r.height)),
+            };
+            render_output_template(template, &values)?
+        }
+        None => manifest.id.clone().into(),
+    };
+    let base_dir_name: PathBuf = match &config.dir {
+        Some(d) if config.stream_subdir || config.output_template.is_some() => d.join(name),
+        Some(d) => d.clone(),
+        None => name,
+    };
+    let base_dir_name = if config.mark_incomplete {
+        let mut name = base_dir_name.into_os_string();
+        name.push(INCOMPLETE_SUFFIX);
+        name.into()
     } else {
-        manifest.id.clone().into()
+        base_dir_name
     };
     let dir_name = base_dir_name.join("segments");
     fs::create_dir_all(&dir_name).await?;
+    *started = Some((manifest.id.clone(), base_dir_name.clone()));
+    if let Some(hook) = config.on_start.clone() {
+        let event = HookEvent {
+            event: HookEventKind::Start,
+            time: Utc::now(),
+            stream_id: Some(manifest.id.clone()),
+            username: config.username.clone(),
+            path: base_dir_name.clone(),
+            output: None,
+            missing_segments: None,
+            error: None,
+        };
+        // Don't hold up downloading live segments
+        tokio::spawn(async move { hook.notify(&event).await });
+    }
+
+    // Download thumbnails
+    if config.thumbnails {
+        let images = manifest.image_representations();
+        if images.is_empty() {
+            warn!("No thumbnails found in manifest");
+        } else {
+            download_thumbnails(&client, &url_base, images, &base_dir_name).await?;
+        }
+    }
 
     // Create state
     let state = Arc::new(Mutex::new(State::new()));
+    state.lock().await.ids.push(manifest.id.clone());
+    if config.probe_cache {
+        let cache = match ProbeCache::read(&base_dir_name).await {
+            Ok(cache) if cache.url == url_base.as_str() => cache,
+            _ => ProbeCache {
+                url: url_base.to_string(),
+                ..Default::default()
+            },
+        };
+        state.lock().await.probe_cache = Some(cache);
+    }
+    if config.dedupe_on_resume || config.resume {
+        let ffprobe = config.ffprobe_path();
+        let (kept, removed) =
+            reconcile_segments(&mut *state.lock().await, &dir_name, &ffprobe).await?;
+        if kept > 0 || removed > 0 {
+            info!("Resuming with {kept} existing segment(s), removed {removed} stray file(s)");
+        }
+    }
+    if config.resume {
+        let mut state = state.lock().await;
+        match StateSnapshot::read(&base_dir_name).await {
+            Ok(snapshot) => state.restore(&snapshot),
+            // Without a checkpoint, learn from the existing segments
+            Err(_) => {
+                for media_type in [MediaType::Video, MediaType::Audio] {
+                    let mut times: Vec<_> =
+                        state.downloaded_segs[&media_type].iter().copied().collect();
+                    times.sort_unstable();
+                    state.learn_deltas(&media_type, &times);
+                }
+            }
+        }
+    }
+    if config.prioritize_live {
+        state.lock().await.permits = Some(Arc::new(PrioritySemaphore::new(
+            config.parallel_candidates,
+            config.reserved_live_downloads,
+        )));
+    }
+    {
+        let mut state = state.lock().await;
+        for rep in &reps {
+            let timeline = rep.segments();
+            let (segments, replace) = match config.deltas_seed {
+                DeltaSeed::Observed => (&timeline[..], true),
+                _ => {
+                    let newest = timeline.len().saturating_sub(config.warm_up_segments);
+                    (&timeline[newest..], false)
+                }
+            };
+            state.seed_deltas(&rep.media_type(), segments, replace);
+        }
+    }
 
     // Progress bar
-    let m = MultiProgress::new();
+    let m = config.progress_bars.clone().unwrap_or_default();
+    if config.progress != ProgressOutput::Bars || !std::io::stderr().is_terminal() {
+        m.set_draw_target(ProgressDrawTarget::hidden());
+    }
     let spinner_style =
         ProgressStyle::with_template("{prefix:.bold.fg.green} {spinner} {wide_msg}")?;
+    let mut bars = vec![];
+    let prefix = |name: &str| match &config.label {
+        Some(label) => format!("{label} {name}"),
+        None => name.to_owned(),
+    };
 
     // Download initialization
     let pb_init = m.add(ProgressBar::new_spinner());
     pb_init.enable_steady_tick(Duration::from_millis(500));
     pb_init.set_style(spinner_style.clone());
-    pb_init.set_prefix("      Init");
+    pb_init.set_prefix(prefix("      Init"));
+    bars.push(pb_init.clone());
     download_reps_init(
         state.clone(),
         &client,
         &url_base,
-        [video_rep, audio_rep],
+        reps.clone(),
+        &base_dir_name,
+        &config,
         Some(pb_init),
     )
     .await?;
+    // Open live pipes
+    if let Some((video_pipe, audio_pipe)) = &config.live_pipes {
+        let mut state = state.lock().await;
+        for (media_type, path) in [(MediaType::Video, video_pipe), (MediaType::Audio, audio_pipe)] {
+            if !reps.iter().any(|r| r.media_type() == media_type) {
+                continue;
+            }
+            let init = state.downloaded_init[&media_type].read().await?.into_owned();
+            let pipe = Arc::new(Mutex::new(LivePipe::open(path, init)));
+            state.pipes.insert(media_type, pipe);
+        }
+    }
+    if config.check_compatibility {
+        check_compatibility(&state, reps.clone(), &config.ffprobe_path()).await?;
+    }
 
     // Download current rep
     let pb_current = m.add(ProgressBar::new_spinner());
     pb_current.enable_steady_tick(Duration::from_millis(500));
     pb_current.set_style(spinner_style.clone());
-    pb_current.set_prefix("   Current");
+    pb_current.set_prefix(prefix("   Current"));
+    bars.push(pb_current.clone());
     download_reps(
         state.clone(),
         &client,
+        &config,
         &url_base,
-        [video_rep, audio_rep],
+        reps.clone(),
         &dir_name,
-        Some(pb_current),
+        Some(pb_current.clone()),
+        &events,
     )
     .await?;
 
+    // Retry segments rejected as empty or not media from refreshed manifests, in case the CDN
+    // served them before they were ready
+    let mut refreshes = 0;
+    while !config.cancel.is_cancelled() && !segments_accepted(&state, &reps).await {
+        if refreshes >= config.empty_manifest_retries {
+            return Err(IgLiveError::NoSegmentsAccepted.into());
+        }
+        refreshes += 1;
+        warn!("No listed segment could be downloaded, retrying with a refreshed manifest");
+        tokio::select! {
+            _ = time::sleep(Duration::from_secs(2)) => (),
+            _ = config.cancel.cancelled() => break,
+        }
+        let refreshed = Mpd::download_from_url(&client, &url_base).await?;
+        download_reps(
+            state.clone(),
+            &client,
+            &config,
+            &url_base,
+            refreshed.select_tracks(&config.media_selection())?,
+            &dir_name,
+            Some(pb_current.clone()),
+            &events,
+        )
+        .await?;
+    }
+
+    // Stopped during the first round, merge what was downloaded without searching further
+    let cancelled = config.cancel.is_cancelled();
+    if config.deltas_seed == DeltaSeed::Calibrated && !cancelled {
+        for rep in &reps {
+            let media_type = rep.media_type();
+            match calibrate_deltas(&state, &client, &config, rep, &url_base).await {
+                Ok(Some(delta)) => info!("{media_type:?} Calibrated segment delta: {delta}"),
+                Ok(None) => {
+                    info!("{media_type:?} Too few segments to calibrate, using built-in deltas")
+                }
+                Err(e) => warn!("{media_type:?} Calibrating segment deltas failed: {e}"),
+            }
+        }
+    }
+
     // Download past and live segments
-    let mut futures: Vec<Pin<Box<dyn Future<Output = Result<()>>>>> = vec![];
-    if config.segments.contains(DownloadSegments::LIVE) {
+    let mut futures: Vec<Pin<Box<dyn Future<Output = Result<()>> + Send>>> = vec![];
+    if config.segments.contains(DownloadSegments::LIVE) && !cancelled {
         // Download live segments
         let pb_forwards = m.add(ProgressBar::new_spinner());
         pb_forwards.set_style(spinner_style.clone());
-        pb_forwards.set_prefix("      Live");
+        pb_forwards.set_prefix(prefix("      Live"));
+        bars.push(pb_forwards.clone());
 
-        futures.push(Box::pin(download_forwards(
-            state.clone(),
-            &client,
-            &url_base,
-            &dir_name,
-            pb_forwards,
-        )));
+        let state = state.clone();
+        let (client, url_base, dir_name, config) = (&client, &url_base, &dir_name, &config);
+        let events = &events;
+        futures.push(Box::pin(async move {
+            let start = Instant::now();
+            let forwards = download_forwards(
+                state.clone(),
+                client,
+                url_base,
+                dir_name,
+                pb_forwards,
+                config,
+                events,
+            );
+            let result = forwards.await;
+            state.lock().await.live_time = Some(start.elapsed());
+            result
+        }));
     }
-    if config.segments.contains(DownloadSegments::PAST) {
+    if config.segments.intersects(DownloadSegments::PAST) && !cancelled {
         // Download past segments
-        let pb_video = m.add(ProgressBar::new_spinner());
-        pb_video.set_style(spinner_style.clone());
-        pb_video.set_prefix("Past video");
-        let pb_audio = m.add(ProgressBar::new_spinner());
-        pb_audio.set_style(spinner_style.clone());
-        pb_audio.set_prefix("Past audio");
+        let find_rep = |media_type| reps.iter().copied().find(|r| r.media_type() == media_type);
+        let mut past_reps = vec![];
+        if let Some(video_rep) = find_rep(MediaType::Video)
+            .filter(|_| config.segments.contains(DownloadSegments::PAST_VIDEO))
+        {
+            let pb_video = m.add(ProgressBar::new_spinner());
+            pb_video.set_style(spinner_style.clone());
+            pb_video.set_prefix(prefix("Past video"));
+            bars.push(pb_video.clone());
+            past_reps.push((video_rep, pb_video));
+        }
+        if let Some(audio_rep) = find_rep(MediaType::Audio)
+            .filter(|_| config.segments.contains(DownloadSegments::PAST_AUDIO))
+        {
+            let pb_audio = m.add(ProgressBar::new_spinner());
+            pb_audio.set_style(spinner_style.clone());
+            pb_audio.set_prefix(prefix("Past audio"));
+            bars.push(pb_audio.clone());
+            past_reps.push((audio_rep, pb_audio));
+        }
 
         futures.push(Box::pin(download_reps_backwards(
             state.clone(),
             &client,
             &url_base,
-            [(video_rep, pb_video), (audio_rep, pb_audio)],
+            past_reps,
             manifest.start_frame,
             &dir_name,
-            config.parallel_candidates,
+            &config,
+            &events,
         )));
     }
-    future::join_all(futures)
+
+    // Log progress periodically if progress bars can't be shown, unless reporting it as JSON
+    let logger =
+        (m.is_hidden() && matches!(config.progress, ProgressOutput::Bars | ProgressOutput::Log))
+            .then(|| tokio::spawn(log_progress(bars)));
+    let heartbeat = config
+        .heartbeat
+        .map(|interval| tokio::spawn(log_heartbeat(state.clone(), interval)));
+    let checkpoint = config.checkpoint_interval.map(|interval| {
+        tokio::spawn(write_checkpoints(state.clone(), base_dir_name.clone(), interval))
+    });
+    let limit_watcher = (config.max_size.is_some() || config.stop_at.is_some())
+        .then(|| tokio::spawn(watch_limits(state.clone(), config.clone())));
+    let pause_file_watcher = config.pause_file.clone().map(|path| {
+        let cancel = config.cancel.clone();
+        tokio::spawn(config.pause.clone().watch_control_file(path, cancel))
+    });
+
+    let result = future::join_all(futures)
         .await
         .into_iter()
-        .collect::<Result<()>>()?;
+        .collect::<Result<()>>();
+    if let Some(logger) = logger {
+        logger.abort();
+    }
+    if let Some(heartbeat) = heartbeat {
+        heartbeat.abort();
+    }
+    if let Some(watcher) = limit_watcher {
+        watcher.abort();
+    }
+    if let Some(watcher) = pause_file_watcher {
+        watcher.abort();
+    }
+    if let Some(checkpoint) = checkpoint {
+        checkpoint.abort();
+    }
+
+    // Write state for inspection, and to resume from if the download failed
+    state.lock().await.snapshot().write(&base_dir_name).await?;
+    result?;
+
+    if config.cancel.is_cancelled() {
+        info!("Download stopped early");
+    }
+    if config.show_retries {
+        info!("Segment retries: {}", state.lock().await.retries);
+    }
+
+    // Search the gaps once more, once late segments had time to appear
+    if let Some(delay) = config.heal_after.filter(|_| !config.cancel.is_cancelled()) {
+        let durations = state.lock().await.durations.clone();
+        let gaps = GapReport::new(&dir_name, &reps, manifest.start_frame, &durations).await?;
+        let start_frame = manifest.start_frame;
+        if gaps.tracks.iter().flat_map(|t| &t.gaps).any(|gap| gap.start_t > start_frame) {
+            info!("Searching gaps again in {}", humantime::format_duration(delay));
+            tokio::select! {
+                _ = time::sleep(delay) => (),
+                _ = config.cancel.cancelled() => (),
+            }
+            let pb = ProgressBar::hidden();
+            let found = heal_gaps(
+                &state,
+                &client,
+                &url_base,
+                &reps,
+                &gaps,
+                start_frame,
+                &dir_name,
+                &pb,
+                &config,
+                &events,
+            )
+            .await?;
+            info!("Found {found} segment(s) in gaps");
+        }
+    }
+
+    // Write probe cache
+    let cache = state.lock().await.probe_cache.clone();
+    if let Some(cache) = cache {
+        cache.write(&base_dir_name).await?;
+    }
+
+    // Write metadata, timed by the video, or the only track
+    let first_rep = reps[0];
+    let (first_t, last_t) = {
+        let times = state.lock().await.segment_times(&first_rep.media_type());
+        (times.first().copied(), times.last().copied())
+    };
+    let metadata = Metadata {
+        id: manifest.id.clone(),
+        ids: state.lock().await.ids.clone(),
+        start_time: first_t.and_then(|t| manifest.segment_time(first_rep, t)),
+        end_time: last_t.and_then(|t| manifest.segment_time(first_rep, t)),
+    };
+    metadata.write(&base_dir_name).await?;
+
+    // Write ffconcat playlists
+    if config.ffconcat {
+        for &rep in &reps {
+            let name = match rep.media_type() {
+                MediaType::Video => "video.ffconcat",
+                _ => "audio.ffconcat",
+            };
+            let (times, durations) = {
+                let state = state.lock().await;
+                let media_type = rep.media_type();
+                let durations = state.durations.get(&media_type).cloned().unwrap_or_default();
+                (state.segment_times(&media_type), durations)
+            };
+            write_ffconcat(
+                rep,
+                &url_base,
+                times,
+                &durations,
+                config.compat_filenames,
+                base_dir_name.join(name),
+            )
+            .await?;
+        }
+    }
 
-    Ok(base_dir_name)
+    // Write checksums
+    if config.integrity {
+        let durations = state.lock().await.durations.clone();
+        let integrity =
+            IntegrityManifest::new(url_base.as_str(), &manifest, &dir_name, &durations).await?;
+        integrity.write(&base_dir_name).await?;
+    }
+
+    // Report missing time
+    let durations = state.lock().await.durations.clone();
+    let gaps = GapReport::new(&dir_name, &reps, manifest.start_frame, &durations).await?;
+    gaps.write(&base_dir_name).await?;
+
+    if let Some(hook) = &config.on_end {
+        let event = HookEvent {
+            event: HookEventKind::End,
+            time: Utc::now(),
+            stream_id: Some(manifest.id.clone()),
+            username: config.username.clone(),
+            path: base_dir_name.clone(),
+            output: None,
+            missing_segments: None,
+            error: None,
+        };
+        hook.notify(&event).await;
+    }
+
+    let state = state.lock().await;
+    Ok(DownloadReport {
+        path: base_dir_name,
+        timings: Timings {
+            manifest: manifest_time,
+            live: state.live_time,
+            backwards: state.backwards_times.clone(),
+        },
+        backwards_errors: state.backwards_errors.clone(),
+        gaps,
+        duration: metadata
+            .start_time
+            .zip(metadata.end_time)
+            .and_then(|(start, end)| (end - start).to_std().ok()),
+    })
+}
+
+/// Estimate the size in bytes of a live stream, based on the bandwidth of its best video and audio,
+/// or of its only track.
+///
+/// # Arguments
+///
+/// * `manifest` - Manifest of the live stream, as given to [download_with_manifest].
+/// * `config` - Options used for download.
+/// * `duration` - Expected stream duration.
+///   If `None`, use the duration of the stream so far.
+pub fn estimate_size(
+    manifest: &Mpd,
+    config: &DownloadConfig,
+    duration: Option<Duration>,
+) -> Result<u64> {
+    let reps = manifest.select_tracks(&config.media_selection())?;
+
+    let duration = match duration {
+        Some(d) => d,
+        None => {
+            let rep = reps[0];
+            let last_t = rep.segments().last().map_or(0, |s| s.t + s.d);
+            let elapsed = last_t.saturating_sub(manifest.start_frame);
+            Duration::from_secs_f64(elapsed as f64 / rep.timescale() as f64)
+        }
+    };
+    let bandwidth = reps.iter().map(|r| r.bandwidth).sum::<usize>() as f64;
+    Ok((bandwidth / 8.0 * duration.as_secs_f64()) as u64)
+}
+
+/// Whether a segment of each of `reps` was downloaded
+async fn segments_accepted(state: &Mutex<State>, reps: &[&Representation]) -> bool {
+    let state = state.lock().await;
+    reps.iter()
+        .all(|rep| !state.downloaded_segs[&rep.media_type()].is_empty())
+}
+
+/// Return a manifest whose selected media lists at least one segment, refreshing and
+/// selecting again while a just started broadcast has no segments.
+/// If downloading live segments, keep refreshing until segments appear, otherwise give up after
+/// `empty_manifest_retries` refreshes.
+async fn wait_for_segments(
+    client: &Client,
+    url_base: &Url,
+    mut manifest: Mpd,
+    config: &DownloadConfig,
+) -> Result<Mpd> {
+    let mut refreshes = 0;
+    loop {
+        let reps = manifest.select_tracks(&config.media_selection())?;
+        if reps.iter().all(|r| !r.segments().is_empty()) {
+            return Ok(manifest);
+        }
+        let live = config.segments.contains(DownloadSegments::LIVE);
+        if manifest.finished || (!live && refreshes >= config.empty_manifest_retries) {
+            return Err(IgLiveError::NoSegmentsYet.into());
+        }
+        if refreshes == 0 {
+            info!("No segments in manifest yet, waiting for broadcast to start");
+        }
+        refreshes += 1;
+
+        tokio::select! {
+            _ = time::sleep(Duration::from_secs(2)) => (),
+            _ = config.cancel.cancelled() => return Err(IgLiveError::NoSegmentsYet.into()),
+        }
+        manifest = Mpd::download_from_url(client, url_base).await?;
+    }
+}
+
+/// HTTP client for the manifest and segment requests of a download, and any other request
+/// made on its behalf, applying the connection options of `config`
+pub fn build_client(config: &DownloadConfig) -> Result<Client> {
+    let mut builder = Client::builder().timeout(Duration::from_secs(10));
+    for (host, ip) in &config.resolve {
+        // The port is taken from the URL
+        builder = builder.resolve(host, SocketAddr::new(*ip, 0));
+    }
+    let mut headers = HeaderMap::new();
+    for (name, value) in &config.headers {
+        headers.append(HeaderName::from_bytes(name.as_bytes())?, HeaderValue::from_str(value)?);
+    }
+    builder = builder.default_headers(headers);
+    if let Some(user_agent) = &config.user_agent {
+        builder = builder.user_agent(user_agent);
+    }
+    if let Some(proxy) = &config.proxy {
+        builder = builder.proxy(Proxy::all(proxy)?);
+    }
+    Ok(builder.build()?)
+}
+
+/// Whether a proxy is set in any of the environment variables `reqwest` reads proxies from
+fn env_proxy_set() -> bool {
+    ["HTTP_PROXY", "http_proxy", "HTTPS_PROXY", "https_proxy", "ALL_PROXY", "all_proxy"]
+        .into_iter()
+        .any(|name| std::env::var_os(name).is_some_and(|value| !value.is_empty()))
+}
+
+/// Periodically print the state of hidden progress bars
+async fn log_progress(bars: Vec<ProgressBar>) {
+    let mut interval = time::interval(Duration::from_secs(10));
+    loop {
+        interval.tick().await;
+        for pb in bars.iter().filter(|pb| !pb.is_finished()) {
+            info!("{}: {}", pb.prefix().trim(), pb.message());
+        }
+    }
+}
+
+/// Counts a running download in [METRICS] until dropped
+#[cfg(feature = "metrics")]
+struct ActiveDownload;
+
+#[cfg(feature = "metrics")]
+impl ActiveDownload {
+    fn start() -> Self {
+        METRICS.active_downloads.fetch_add(1, Ordering::Relaxed);
+        Self
+    }
+}
+
+#[cfg(feature = "metrics")]
+impl Drop for ActiveDownload {
+    fn drop(&mut self) {
+        METRICS.active_downloads.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Write the state to `dir` every `interval`, so an interrupted download can be resumed from it
+async fn write_checkpoints(state: Arc<Mutex<State>>, dir: PathBuf, interval: Duration) {
+    let mut interval = time::interval(interval);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let snapshot = state.lock().await.snapshot();
+        if let Err(e) = snapshot.write(&dir).await {
+            warn!("Failed to write state checkpoint: {e}");
+        }
+    }
+}
+
+/// Periodically print how far the download has gotten
+async fn log_heartbeat(state: Arc<Mutex<State>>, interval: Duration) {
+    let mut interval = time::interval(interval);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let mut msg = String::from("Still working:");
+        {
+            let state = state.lock().await;
+            for media_type in [MediaType::Video, MediaType::Audio] {
+                // Position of the past segment search, or else the newest segment
+                let latest_t = match state.marker.positions.get(&media_type) {
+                    Some(position) => Some(position.latest_t.to_string()),
+                    None => state.downloaded_segs[&media_type]
+                        .iter()
+                        .max()
+                        .map(|t| t.to_string()),
+                };
+                if let Some(latest_t) = latest_t {
+                    msg += &format!(" {media_type:?} latest_t={latest_t},");
+                }
+            }
+            let segments: usize = state.downloaded_segs.values().map(|s| s.len()).sum();
+            msg += &format!(
+                " segments={segments}, bytes={}",
+                HumanBytes(state.bytes_downloaded)
+            );
+        }
+        info!("{msg}");
+    }
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn download_reps(
     state: Arc<Mutex<State>>,
     client: &Client,
+    config: &DownloadConfig,
     url_base: &Url,
     reps: impl IntoIterator<Item = &Representation>,
     dir: impl AsRef<Path> + Send,
     pb: Option<ProgressBar>,
+    events: &ProgressSender,
 ) -> Result<()> {
     if let Some(pb) = pb.as_ref() {
         pb.set_message("Downloading");
     }
 
-    let futures: Vec<_> = reps
+    let sources: Vec<_> = reps
         .into_iter()
-        .map(|rep| download_rep(state.clone(), client, rep, url_base, dir.as_ref()))
+        .map(|rep| SegmentSource {
+            rep,
+            url_base,
+            dir: dir.as_ref(),
+            init: None,
+        })
+        .collect();
+    let futures: Vec<_> = sources
+        .iter()
+        .map(|source| download_rep(state.clone(), client, config, source, events))
         .collect();
     future::join_all(futures)
         .await
@@ -185,83 +1348,347 @@ async fn download_reps(
 async fn download_rep(
     state: Arc<Mutex<State>>,
     client: &Client,
-    rep: &Representation,
-    url_base: &Url,
-    dir: impl AsRef<Path>,
+    config: &DownloadConfig,
+    source: &SegmentSource<'_>,
+    events: &ProgressSender,
 ) -> Result<()> {
+    let rep = source.rep;
     let media_type = rep.media_type();
-    for segment in &rep.segment_template.segment_timeline.segments {
+    let permits = state.lock().await.permits.clone();
+    for segment in &rep.segments() {
         let t = segment.t;
 
         // Check if already downloaded
-        if state.lock().await.downloaded_segs[&media_type]
-            .get(&t)
-            .is_some()
-        {
+        if state.lock().await.downloaded_segs[&media_type].contains(&t) {
             continue;
         }
 
         // Try to download segment
-        let url = rep.download_url(url_base, t)?;
-        let filename = dir.as_ref().join(
-            url.path_segments()
-                .ok_or(IgLiveError::InvalidUrl)?
-                .rev()
-                .next()
-                .ok_or(IgLiveError::InvalidUrl)?,
-        );
-        download_file(
-            state.clone(),
-            client,
-            rep.media_type(),
-            false,
-            &url,
-            filename,
-        )
-        .await?;
+        let _permit = match &permits {
+            Some(permits) => Some(permits.acquire_high().await),
+            None => None,
+        };
+        let chosen = state
+            .lock()
+            .await
+            .template_variables
+            .get(&media_type)
+            .copied();
+        let variable = chosen.unwrap_or_else(|| rep.template_variable());
+        let mut result =
+            download_segment(state.clone(), client, config, source, t, variable).await;
+
+        // Until a segment has been found, try the other variable if the template is ambiguous
+        let mut variable = variable;
+        if chosen.is_none() {
+            if let Some(alternate) = rep.alternate_template_variable(variable) {
+                if matches!(
+                    result.as_ref().map_err(|e| e.downcast_ref()),
+                    Err(Some(IgLiveError::StatusNotFound))
+                ) {
+                    variable = alternate;
+                    result =
+                        download_segment(state.clone(), client, config, source, t, variable).await;
+                }
+                if result.is_ok() {
+                    info!("Locating {media_type:?} segments by template variable {variable:?}");
+                    state
+                        .lock()
+                        .await
+                        .template_variables
+                        .insert(media_type.clone(), variable);
+                }
+            }
+        }
+
+        // Leave implausibly small or non-media responses to be retried on the next manifest
+        // refresh
+        let data = match result {
+            Err(e)
+                if matches!(
+                    e.downcast_ref(),
+                    Some(IgLiveError::SegmentTooSmall(_) | IgLiveError::UnexpectedContentType(_))
+                ) =>
+            {
+                state.lock().await.retries += 1;
+                #[cfg(feature = "metrics")]
+                METRICS.retries.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+            Err(e) if matches!(e.downcast_ref(), Some(IgLiveError::Cancelled)) => break,
+            result => result?,
+        };
+
+        ProgressEvent::SegmentDownloaded {
+            media_type: media_type.clone(),
+            representation: rep.id.clone(),
+            t,
+            bytes: data.len(),
+            live: true,
+        }
+        .emit(events, config);
+
+        // Stream to the pipe outside the state lock
+        let pipe = state.lock().await.pipes.get(&media_type).cloned();
+        if let Some(pipe) = pipe {
+            pipe.lock().await.push(t, segment.d, data).await?;
+        }
 
         // Update state
+        let mut state = state.lock().await;
+        state.downloaded_segs.get_mut(&media_type).unwrap().insert(t);
         state
-            .lock()
-            .await
-            .downloaded_segs
+            .durations
             .get_mut(&media_type)
             .unwrap()
-            .insert(t);
+            .insert(t, segment.d);
     }
     Ok(())
 }
 
-async fn download_file(
+/// Where live segments of a representation are downloaded from and to
+pub(super) struct SegmentSource<'a> {
+    pub rep: &'a Representation,
+    pub url_base: &'a Url,
+    pub dir: &'a Path,
+
+    /// Initialization segment to write before each segment instead of the one in
+    /// [State::downloaded_init], e.g. of a continuation stream
+    pub init: Option<&'a [u8]>,
+}
+
+/// Download the segment of `source` starting at `t`, locating it by `variable`
+async fn download_segment(
     state: Arc<Mutex<State>>,
     client: &Client,
-    media_type: MediaType,
-    check_pts: bool,
+    config: &DownloadConfig,
+    source: &SegmentSource<'_>,
+    t: usize,
+    variable: TemplateVariable,
+) -> Result<Vec<u8>> {
+    let SegmentSource {
+        rep,
+        url_base,
+        dir,
+        init,
+    } = source;
+    let media_type = rep.media_type();
+    let url = rep.download_url_as(url_base, t, variable)?;
+    let filename = media_segment_path(dir, &url, &media_type, t, config.compat_filenames)?;
+    let data = fetch_media(&state, client, config, &url).await?;
+    match init {
+        // PTS of a continuation stream don't continue those of the past segments
+        Some(init) => {
+            write_segment(config, init, &data, filename).await?;
+        }
+        None => {
+            let init = read_init(&state, &media_type).await?;
+            let pts = write_segment(config, &init, &data, filename).await?;
+            track_pts(&state, media_type, pts, false).await?;
+        }
+    }
+    Ok(data)
+}
+
+/// Fail if a successful response is a web page or other text instead of media, e.g. an error or
+/// login page served with status 200
+fn check_content_type(resp: &Response) -> Result<()> {
+    let Some(content_type) = resp.headers().get(CONTENT_TYPE) else {
+        return Ok(());
+    };
+    let content_type = content_type.to_str().unwrap_or_default().to_ascii_lowercase();
+    if content_type.starts_with("text/")
+        || content_type.contains("html")
+        || content_type.contains("json")
+    {
+        return Err(IgLiveError::UnexpectedContentType(content_type).into());
+    }
+    Ok(())
+}
+
+fn segment_path(dir: &Path, url: &Url) -> Result<PathBuf> {
+    Ok(dir.join(
+        url.path_segments()
+            .ok_or(IgLiveError::InvalidUrl)?
+            .next_back()
+            .ok_or(IgLiveError::InvalidUrl)?,
+    ))
+}
+
+/// Path of a downloaded video or audio segment.
+/// Its file name ends with `t`, the last number in it, so `parse_segment_file_name` reads the
+/// time back. Names from `$Number$` templates and segment lists get `t` appended.
+fn media_segment_path(
+    dir: &Path,
     url: &Url,
-    path: impl AsRef<Path>,
-) -> Result<()> {
-    let resp = client.get(url.as_str()).send().await?;
+    media_type: &MediaType,
+    t: usize,
+    compat_filenames: bool,
+) -> Result<PathBuf> {
+    let path = segment_path(dir, url)?;
+    if !compat_filenames {
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        if trailing_number(&stem) == Some(t) {
+            return Ok(path);
+        }
+        let mut file_name = format!("{stem}_{t}");
+        if let Some(extension) = path.extension() {
+            file_name += ".";
+            file_name += &extension.to_string_lossy();
+        }
+        return Ok(path.with_file_name(file_name));
+    }
+
+    let prefix = match media_type {
+        MediaType::Video => "video",
+        MediaType::Audio => "audio",
+        _ => return Ok(path),
+    };
+    let mut file_name = format!("{prefix}_{t:012}");
+    if let Some(extension) = path.extension() {
+        file_name += ".";
+        file_name += &extension.to_string_lossy();
+    }
+    Ok(path.with_file_name(file_name))
+}
+
+fn gzip(data: &[u8]) -> Result<Vec<u8>> {
+    use std::io::Write;
+
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data)?;
+    Ok(encoder.finish()?)
+}
+
+/// Path of a segment written with `compress_segments`
+fn compressed_path(path: impl AsRef<Path>) -> PathBuf {
+    let mut path = path.as_ref().as_os_str().to_owned();
+    path.push(".gz");
+    path.into()
+}
+
+/// Request a segment, without retrying, keeping to `limiter` while receiving it
+async fn fetch_segment(
+    client: &Client,
+    url: &Url,
+    limiter: Option<&RateLimiter>,
+    http3: bool,
+) -> Result<Vec<u8>> {
+    let mut request = client.get(url.as_str());
+    if http3 {
+        request = request.version(Version::HTTP_3);
+    }
+    let mut resp = request.send().await?;
     if resp.status() == StatusCode::NOT_FOUND {
+        #[cfg(feature = "metrics")]
+        METRICS.not_found.fetch_add(1, Ordering::Relaxed);
         return Err(IgLiveError::StatusNotFound.into());
     }
+    #[cfg(feature = "metrics")]
+    if resp.status() == StatusCode::TOO_MANY_REQUESTS {
+        METRICS.rate_limited.fetch_add(1, Ordering::Relaxed);
+    }
 
     if !resp.status().is_success() {
         return Err(IgLiveError::StatusError(resp.status().into(), url.as_str().to_owned()).into());
     }
+    check_content_type(&resp)?;
+
+    let mut data = Vec::new();
+    while let Some(chunk) = resp.chunk().await? {
+        if let Some(limiter) = limiter {
+            limiter.consume(chunk.len()).await;
+        }
+        data.extend_from_slice(&chunk);
+    }
+    Ok(data)
+}
+
+/// Whether a failed request may succeed if retried: server errors, rate limiting, timeouts and
+/// connection errors
+fn is_transient(e: &anyhow::Error) -> bool {
+    match e.downcast_ref() {
+        Some(IgLiveError::StatusError(status, _)) => *status >= 500 || *status == 429,
+        Some(_) => false,
+        None => e
+            .downcast_ref::<reqwest::Error>()
+            .is_some_and(|e| e.is_timeout() || e.is_connect() || e.is_request() || e.is_body()),
+    }
+}
 
+/// Whether a failed HTTP/3 request means HTTP/3 doesn't work at all: connecting failed or the
+/// request was broken off, but didn't time out, which any protocol can
+fn is_http3_failure(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<reqwest::Error>()
+        .is_some_and(|e| (e.is_connect() || e.is_request()) && !e.is_timeout())
+}
+
+/// Delay before retry number `attempt`, starting at 0: `base` doubled per attempt, plus up to
+/// half of that again as jitter so parallel retries spread out
+fn retry_delay(base: Duration, attempt: usize) -> Duration {
+    let delay = base.saturating_mul(1 << attempt.min(10));
+    let jitter = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.subsec_nanos()) as f64
+        / 1e9
+        / 2.0;
+    delay.mul_f64(1.0 + jitter)
+}
+
+async fn download_file(
+    state: Arc<Mutex<State>>,
+    client: &Client,
+    config: &DownloadConfig,
+    media_type: MediaType,
+    check_pts: bool,
+    url: &Url,
+    path: impl AsRef<Path>,
+) -> Result<Vec<u8>> {
+    let data = fetch_media(&state, client, config, url).await?;
+    let init = read_init(&state, &media_type).await?;
+    let pts = write_segment(config, &init, &data, path).await?;
+    track_pts(&state, media_type, pts, check_pts).await?;
+    Ok(data)
+}
+
+/// Initialization segment downloaded for `media_type`
+async fn read_init(state: &Mutex<State>, media_type: &MediaType) -> Result<Vec<u8>> {
+    let state = state.lock().await;
+    Ok(state.downloaded_init[media_type].read().await?.into_owned())
+}
+
+/// Write `init` followed by segment `data` to `path`.
+/// Returns the start and end PTS of the segment.
+async fn write_segment(
+    config: &DownloadConfig,
+    init: &[u8],
+    data: &[u8],
+    path: impl AsRef<Path>,
+) -> Result<(usize, usize)> {
     // Concat initialization and segment data
     let mut buffer = Vec::new();
-    buffer
-        .write_all(state.lock().await.downloaded_init.get(&media_type).unwrap())
-        .await?;
-    buffer.write_all(&resp.bytes().await?).await?;
+    buffer.write_all(init).await?;
+    buffer.write_all(data).await?;
 
     // Write to file
-    let mut file_buffer = fs::File::create(path).await?;
-    file_buffer.write_all(&buffer).await?;
+    if config.compress_segments {
+        fs::write(compressed_path(path), gzip(&buffer)?).await?;
+    } else {
+        let mut file_buffer = fs::File::create(path).await?;
+        file_buffer.write_all(&buffer).await?;
+    }
 
-    // Check pts
-    let pts = get_pts(buffer).await?;
+    get_pts(config.ffprobe_path(), buffer).await
+}
+
+/// Track the earliest PTS of `media_type` downloaded, from a segment spanning `pts`.
+/// If `check_pts`, fail if the segment doesn't end where the earliest segment starts.
+async fn track_pts(
+    state: &Mutex<State>,
+    media_type: MediaType,
+    pts: (usize, usize),
+    check_pts: bool,
+) -> Result<()> {
     if check_pts {
         let target_pts = *state.lock().await.back_pts.get(&media_type).unwrap();
         if target_pts.abs_diff(pts.1) > 1 {
@@ -280,3 +1707,223 @@ async fn download_file(
 
     Ok(())
 }
+
+/// Number of newest live segments downloaded to calibrate the deltas with
+const CALIBRATION_SEGMENTS: usize = 3;
+
+/// Download the newest [CALIBRATION_SEGMENTS] live segments of `rep`, measure their durations
+/// and seed the deltas of its media type with them and the gaps between them.
+/// Return the dominant delta, or `None` if the manifest lists too few segments.
+async fn calibrate_deltas(
+    state: &Arc<Mutex<State>>,
+    client: &Client,
+    config: &DownloadConfig,
+    rep: &Representation,
+    url_base: &Url,
+) -> Result<Option<isize>> {
+    let media_type = rep.media_type();
+    let init = read_init(state, &media_type).await?;
+    let timeline = rep.segments();
+    let mut measured = vec![];
+    for segment in &timeline[timeline.len().saturating_sub(CALIBRATION_SEGMENTS)..] {
+        let url = rep.download_url_as(url_base, segment.t, rep.template_variable())?;
+        let data = fetch_media(state, client, config, &url).await?;
+        let (_, d) = get_pts(config.ffprobe_path(), [&init[..], &data].concat()).await?;
+        measured.push(Segment { t: segment.t, d });
+    }
+    Ok(state.lock().await.seed_deltas(&media_type, &measured, false))
+}
+
+/// Download the media segment data at `url`, retrying transient failures and rejecting
+/// implausibly small responses, and count it as downloaded
+async fn fetch_media(
+    state: &Mutex<State>,
+    client: &Client,
+    config: &DownloadConfig,
+    url: &Url,
+) -> Result<Vec<u8>> {
+    config.pause.wait_resumed(&config.cancel).await;
+    if config.cancel.is_cancelled() {
+        return Err(IgLiveError::Cancelled.into());
+    }
+
+    // Retry transient failures with exponential backoff. 404 means the segment doesn't exist
+    let limiter = config.rate_limiter.clone();
+    let http3_failed = state.lock().await.http3_failed;
+    let http3 = config.http3 && cfg!(feature = "http3") && !http3_failed;
+    let mut attempt = 0;
+    let data = loop {
+        let result = match fetch_segment(client, url, limiter.as_deref(), http3).await {
+            // Fall back for good if the server or network doesn't support QUIC
+            Err(e) if http3 && is_http3_failure(&e) => {
+                warn!("HTTP/3 request failed, falling back to HTTP/1.1 or HTTP/2: {e}");
+                state.lock().await.http3_failed = true;
+                fetch_segment(client, url, limiter.as_deref(), false).await
+            }
+            result => result,
+        };
+        match result {
+            Err(e) if attempt < config.retries && is_transient(&e) => {
+                state.lock().await.retries += 1;
+                #[cfg(feature = "metrics")]
+                METRICS.retries.fetch_add(1, Ordering::Relaxed);
+                tokio::select! {
+                    _ = time::sleep(retry_delay(config.retry_delay, attempt)) => (),
+                    _ = config.cancel.cancelled() => return Err(e),
+                }
+                attempt += 1;
+            }
+            result => break result?,
+        }
+    };
+
+    if data.len() < config.min_segment_size {
+        return Err(IgLiveError::SegmentTooSmall(data.len()).into());
+    }
+    state.lock().await.bytes_downloaded += data.len() as u64;
+    #[cfg(feature = "metrics")]
+    {
+        METRICS.segments.fetch_add(1, Ordering::Relaxed);
+        METRICS.bytes.fetch_add(data.len() as u64, Ordering::Relaxed);
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+
+    use super::*;
+    use crate::test_util;
+
+    #[tokio::test]
+    async fn empty_timeline_fails_unless_live() {
+        let (url, requests) = test_util::serve(404, "text/plain", Vec::new()).await;
+        let client = Client::new();
+        let config = DownloadConfig {
+            segments: DownloadSegments::PAST,
+            empty_manifest_retries: 0,
+            ..DownloadConfig::default()
+        };
+
+        let manifest = test_util::manifest(&[]);
+        assert!(manifest.representations().iter().all(|r| r.segments().is_empty()));
+        let e = wait_for_segments(&client, &url, manifest, &config).await.unwrap_err();
+        assert!(matches!(e.downcast_ref(), Some(IgLiveError::NoSegmentsYet)));
+
+        let manifest = test_util::manifest(&[0, 2000]);
+        assert!(wait_for_segments(&client, &url, manifest, &config).await.is_ok());
+        assert_eq!(requests.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn empty_segment_response_is_too_small() {
+        let (url, requests) = test_util::serve(200, "video/mp4", Vec::new()).await;
+        let state = Mutex::new(State::new());
+        let config = DownloadConfig::default();
+
+        let e = fetch_media(&state, &Client::new(), &config, &url).await.unwrap_err();
+        assert!(matches!(e.downcast_ref(), Some(IgLiveError::SegmentTooSmall(0))));
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        assert_eq!(state.lock().await.bytes_downloaded, 0);
+    }
+
+    #[tokio::test]
+    async fn all_empty_segments_fail_cleanly() {
+        let init = crate::mp4::tests::init(1000, 40);
+        let (url, _) = test_util::serve_with(move |path| match path {
+            p if p.contains("-init.") => (200, "video/mp4", init.clone()),
+            _ => (200, "video/mp4", Vec::new()),
+        })
+        .await;
+        let dir = test_util::temp_dir("all-empty");
+        let config = DownloadConfig {
+            dir: Some(dir.clone()),
+            progress: ProgressOutput::None,
+            empty_manifest_retries: 0,
+            ffmpeg_path: Some(dir.join("missing-ffmpeg")),
+            ..Default::default()
+        };
+
+        let manifest = test_util::manifest(&[10000, 12000]);
+        let mut started = None;
+        let result = download_stream(
+            url,
+            Some(manifest),
+            config,
+            ProgressSender::default(),
+            &mut started,
+        )
+        .await;
+        let e = result.unwrap_err();
+        assert!(matches!(e.downcast_ref(), Some(IgLiveError::NoSegmentsAccepted)));
+        assert!(started.is_some());
+    }
+
+    #[tokio::test]
+    async fn http3_is_rejected_with_a_proxy() {
+        let config = DownloadConfig {
+            http3: true,
+            proxy: Some("socks5h://127.0.0.1:1080".to_owned()),
+            progress: ProgressOutput::None,
+            ..Default::default()
+        };
+        let url = Url::parse("http://127.0.0.1:1/dash.mpd").unwrap();
+        let manifest = test_util::manifest(&[10000]);
+        let mut started = None;
+        let result =
+            download_stream(url, Some(manifest), config, ProgressSender::default(), &mut started);
+        let e = result.await.unwrap_err();
+        assert!(started.is_none());
+        assert!(matches!(e.downcast_ref(), Some(IgLiveError::Http3WithProxy)));
+    }
+
+    #[tokio::test]
+    async fn only_connection_failures_disable_http3() {
+        // Nothing listens on a port freed right after binding it
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let refused = Client::new()
+            .get(format!("http://127.0.0.1:{port}/"))
+            .send()
+            .await
+            .unwrap_err();
+        assert!(is_http3_failure(&refused.into()));
+
+        // A listener that never responds
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let client = Client::builder()
+            .timeout(Duration::from_millis(100))
+            .build()
+            .unwrap();
+        let timeout = client
+            .get(format!("http://127.0.0.1:{port}/"))
+            .send()
+            .await
+            .unwrap_err();
+        assert!(timeout.is_timeout());
+        assert!(!is_http3_failure(&timeout.into()));
+
+        assert!(!is_http3_failure(&IgLiveError::StatusNotFound.into()));
+    }
+
+    #[tokio::test]
+    async fn html_segment_response_is_rejected() {
+        let body = b"<!DOCTYPE html><html><body>Log in to continue</body></html>".to_vec();
+        let (url, _) = test_util::serve(200, "text/html; charset=utf-8", body).await;
+        let state = Mutex::new(State::new());
+        let config = DownloadConfig::default();
+
+        let e = fetch_media(&state, &Client::new(), &config, &url).await.unwrap_err();
+        assert!(matches!(
+            e.downcast_ref(),
+            Some(IgLiveError::UnexpectedContentType(content_type))
+                if content_type.starts_with("text/html")
+        ));
+        assert_eq!(state.lock().await.bytes_downloaded, 0);
+    }
+}
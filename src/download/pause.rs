@@ -0,0 +1,79 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::watch;
+use tokio::{fs, time};
+use tokio_util::sync::CancellationToken;
+
+/// How often the control file is checked for
+const CONTROL_FILE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Switch to pause and resume requesting new segments.
+/// Clones share the same state, so a download can be paused from another task, e.g. a signal
+/// handler.
+#[derive(Clone, Debug)]
+pub struct PauseSwitch {
+    paused: Arc<watch::Sender<bool>>,
+}
+
+impl Default for PauseSwitch {
+    fn default() -> Self {
+        Self {
+            paused: Arc::new(watch::Sender::new(false)),
+        }
+    }
+}
+
+impl PauseSwitch {
+    /// Create an unpaused switch.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stop requesting new segments. In-flight requests are allowed to finish.
+    pub fn pause(&self) {
+        self.paused.send_replace(true);
+    }
+
+    /// Continue requesting new segments.
+    pub fn resume(&self) {
+        self.paused.send_replace(false);
+    }
+
+    /// Whether new segment requests are held back.
+    pub fn is_paused(&self) -> bool {
+        *self.paused.borrow()
+    }
+
+    /// Wait until resumed, or until `cancel` is cancelled.
+    pub(crate) async fn wait_resumed(&self, cancel: &CancellationToken) {
+        let mut paused = self.paused.subscribe();
+        tokio::select! {
+            _ = paused.wait_for(|&p| !p) => (),
+            _ = cancel.cancelled() => (),
+        }
+    }
+
+    /// Pause while `path` exists and resume once it is removed, until `cancel` is cancelled.
+    /// Only changes of the file's existence switch, so [PauseSwitch::pause] and
+    /// [PauseSwitch::resume] still work in between.
+    pub(crate) async fn watch_control_file(self, path: PathBuf, cancel: CancellationToken) {
+        let mut existed = false;
+        while !cancel.is_cancelled() {
+            let exists = fs::try_exists(&path).await.unwrap_or(false);
+            if exists != existed {
+                if exists {
+                    self.pause();
+                } else {
+                    self.resume();
+                }
+                existed = exists;
+            }
+            tokio::select! {
+                _ = time::sleep(CONTROL_FILE_INTERVAL) => (),
+                _ = cancel.cancelled() => (),
+            }
+        }
+    }
+}
@@ -0,0 +1,82 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::path::PathBuf;
+
+use anyhow::Result;
+use tokio::fs::{self, File};
+use tokio::io::AsyncWriteExt;
+use tokio::task::JoinHandle;
+
+/// Number of out-of-order segments held back before the earliest one is written anyway
+const REORDER_BUFFER_SIZE: usize = 10;
+
+/// Named pipe that live segments of one media type are streamed to, in timeline order
+pub struct LivePipe {
+    file: Option<File>,
+    opening: Option<JoinHandle<io::Result<File>>>,
+    init: Vec<u8>,
+    next_t: Option<usize>,
+    pending: BTreeMap<usize, (usize, Vec<u8>)>,
+}
+
+impl LivePipe {
+    /// Start opening a pipe for writing.
+    /// Named pipes only open once a reader opens them, so segments are held back until then,
+    /// keeping only the latest ones.
+    pub fn open(path: impl Into<PathBuf>, init: Vec<u8>) -> Self {
+        let path = path.into();
+        let opening = tokio::spawn(async move {
+            fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(path)
+                .await
+        });
+        Self {
+            file: None,
+            opening: Some(opening),
+            init,
+            next_t: None,
+            pending: BTreeMap::new(),
+        }
+    }
+
+    /// Queue a segment starting at `t` lasting `d`, and write all segments that are next in the
+    /// timeline.
+    /// Segments earlier than ones already written are dropped.
+    pub async fn push(&mut self, t: usize, d: usize, data: Vec<u8>) -> Result<()> {
+        if self.next_t.is_some_and(|next_t| t < next_t) {
+            return Ok(());
+        }
+        self.pending.insert(t, (d, data));
+
+        // Write initialization first once the pipe is open
+        if let Some(opening) = self.opening.take_if(|opening| opening.is_finished()) {
+            let mut file = opening.await??;
+            file.write_all(&self.init).await?;
+            self.file = Some(file);
+        }
+        let Some(file) = &mut self.file else {
+            while self.pending.len() > REORDER_BUFFER_SIZE {
+                let (t, (d, _)) = self.pending.pop_first().unwrap();
+                self.next_t = Some(t + d);
+            }
+            return Ok(());
+        };
+
+        while let Some(&first_t) = self.pending.keys().next() {
+            let is_next = self.next_t.is_none_or(|next_t| first_t == next_t);
+            if !is_next && self.pending.len() <= REORDER_BUFFER_SIZE {
+                break;
+            }
+
+            let (t, (d, data)) = self.pending.pop_first().unwrap();
+            file.write_all(&data).await?;
+            self.next_t = Some(t + d);
+        }
+        file.flush().await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,87 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::Result;
+use reqwest::IntoUrl;
+use tokio::fs;
+use tokio::sync::Mutex;
+
+use super::initialization::download_reps_init;
+use super::{build_client, download_file, media_segment_path, DownloadConfig};
+use crate::error::IgLiveError;
+use crate::mpd::Mpd;
+use crate::state::State;
+
+/// Download only the most recent segments of an IG live stream.
+/// Useful for quickly checking a stream's content and quality before archiving it.
+/// Returns the download output path.
+///
+/// # Arguments
+///
+/// * `mpd_url` - Full URL of live stream's .mpd manifest.
+/// * `dir` - Directory to place downloaded segments.
+///   If `None`, auto generate directory based on live stream ID.
+/// * `segments` - Number of latest segments to download for each of video and audio.
+/// * `config` - Options used for download, such as request headers and the selected tracks.
+pub async fn download_preview(
+    mpd_url: impl IntoUrl,
+    dir: Option<PathBuf>,
+    segments: usize,
+    config: &DownloadConfig,
+) -> Result<PathBuf> {
+    // Reqwest client
+    let client = build_client(config)?;
+
+    // Download manifest
+    let url_base = mpd_url.into_url()?;
+    let manifest = Mpd::download_from_url(&client, url_base.clone()).await?;
+    let reps = manifest.select_tracks(&config.media_selection())?;
+    if reps.iter().any(|r| r.segments().is_empty()) {
+        return Err(IgLiveError::NoSegmentsYet.into());
+    }
+
+    // Create directory
+    let base_dir_name = dir.unwrap_or_else(|| format!("{}-preview", manifest.id).into());
+    let dir_name = base_dir_name.join("segments");
+    fs::create_dir_all(&dir_name).await?;
+
+    // Download initialization
+    let state = Arc::new(Mutex::new(State::new()));
+    download_reps_init(
+        state.clone(),
+        &client,
+        &url_base,
+        reps.iter().copied(),
+        &base_dir_name,
+        config,
+        None,
+    )
+    .await?;
+
+    // Download latest segments
+    for &rep in &reps {
+        let timeline = rep.segments();
+        for segment in &timeline[timeline.len().saturating_sub(segments)..] {
+            let url = rep.download_url(&url_base, segment.t)?;
+            let filename = media_segment_path(
+                &dir_name,
+                &url,
+                &rep.media_type(),
+                segment.t,
+                config.compat_filenames,
+            )?;
+            download_file(
+                state.clone(),
+                &client,
+                config,
+                rep.media_type(),
+                false,
+                &url,
+                filename,
+            )
+            .await?;
+        }
+    }
+
+    Ok(base_dir_name)
+}
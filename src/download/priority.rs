@@ -0,0 +1,55 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use tokio::sync::{Notify, Semaphore, SemaphorePermit};
+
+/// Semaphore that hands out permits to high priority waiters before low priority ones, and
+/// keeps some permits for high priority waiters only
+pub struct PrioritySemaphore {
+    semaphore: Semaphore,
+    /// Limits low priority holders, to leave the reserved permits to high priority waiters
+    low: Semaphore,
+    high_waiting: AtomicUsize,
+    high_done: Notify,
+}
+
+impl PrioritySemaphore {
+    /// At most `permits` are held at once, `reserved` of which only by high priority holders.
+    /// Low priority holders always get at least one permit.
+    pub fn new(permits: usize, reserved: usize) -> Self {
+        Self {
+            semaphore: Semaphore::new(permits),
+            low: Semaphore::new(permits.saturating_sub(reserved).max(1)),
+            high_waiting: AtomicUsize::new(0),
+            high_done: Notify::new(),
+        }
+    }
+
+    pub async fn acquire_high(&self) -> SemaphorePermit<'_> {
+        self.high_waiting.fetch_add(1, Ordering::SeqCst);
+        let permit = self.semaphore.acquire().await.expect("Semaphore error");
+        if self.high_waiting.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.high_done.notify_waiters();
+        }
+        permit
+    }
+
+    /// Only returns a permit while no high priority waiters are queued and, along with it, one
+    /// of the unreserved permits
+    pub async fn acquire_low(&self) -> (SemaphorePermit<'_>, SemaphorePermit<'_>) {
+        let low = self.low.acquire().await.expect("Semaphore error");
+        loop {
+            let high_done = self.high_done.notified();
+            if self.high_waiting.load(Ordering::SeqCst) > 0 {
+                high_done.await;
+                continue;
+            }
+
+            let permit = self.semaphore.acquire().await.expect("Semaphore error");
+            if self.high_waiting.load(Ordering::SeqCst) == 0 {
+                return (permit, low);
+            }
+            // Pass the permit on, so it reaches the high priority waiter
+            drop(permit);
+        }
+    }
+}
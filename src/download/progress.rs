@@ -0,0 +1,98 @@
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use serde::Serialize;
+use tokio::sync::mpsc;
+
+use super::DownloadConfig;
+use crate::mpd::MediaType;
+
+/// Number of events buffered for a [DownloadHandle][super::DownloadHandle] before later ones
+/// are dropped
+pub(crate) const EVENT_BUFFER_SIZE: usize = 1024;
+
+/// How a download shows its progress
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProgressOutput {
+    /// Progress bars, falling back to periodically logging progress if stderr is not a terminal
+    Bars,
+
+    /// Periodically log progress
+    Log,
+
+    /// Write [ProgressEvent]s to stdout as newline-delimited JSON, for scripts and other
+    /// programs to follow the download
+    Json,
+
+    /// Don't show progress
+    None,
+}
+
+/// Progress of a download, received from its [DownloadHandle][super::DownloadHandle], and
+/// written as one line of JSON to stdout with [ProgressOutput::Json]
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ProgressEvent {
+    /// A live or past segment was downloaded
+    SegmentDownloaded {
+        /// Media type of the segment
+        media_type: MediaType,
+        /// ID of the segment's representation, if the manifest names it
+        representation: Option<String>,
+        /// Start time of the segment
+        t: usize,
+        /// Size of the segment, excluding the initialization segment
+        bytes: usize,
+        /// Whether the segment was downloaded live rather than found searching past segments
+        live: bool,
+    },
+
+    /// The search for past segments of `media_type` reached `latest_t`
+    BackwardsProgress {
+        /// Media type searched for
+        media_type: MediaType,
+        /// Start time of the earliest segment found so far, or of the assumed gap
+        latest_t: isize,
+    },
+
+    /// The manifest marked the stream as ended
+    StreamEnded,
+}
+
+/// Event along with the time it happened
+#[derive(Serialize)]
+struct TimedEvent<'a> {
+    time: DateTime<Utc>,
+    #[serde(flatten)]
+    event: &'a ProgressEvent,
+}
+
+/// Sending side of a download's progress events, without a receiver for downloads not started
+/// through [download][super::download]
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ProgressSender(Option<mpsc::Sender<ProgressEvent>>);
+
+impl ProgressSender {
+    pub(crate) fn new(sender: mpsc::Sender<ProgressEvent>) -> Self {
+        Self(Some(sender))
+    }
+}
+
+impl ProgressEvent {
+    /// Send to the download's handle, and write as one line of JSON to stdout with
+    /// [ProgressOutput::Json]
+    pub(crate) fn emit(self, events: &ProgressSender, config: &DownloadConfig) {
+        if config.progress == ProgressOutput::Json {
+            let event = TimedEvent {
+                time: Utc::now(),
+                event: &self,
+            };
+            if let Ok(line) = serde_json::to_string(&event) {
+                println!("{line}");
+            }
+        }
+        // The handle may have been dropped or awaited, or not keep up
+        if let Some(events) = &events.0 {
+            let _ = events.try_send(self);
+        }
+    }
+}
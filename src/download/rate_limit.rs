@@ -0,0 +1,49 @@
+use std::time::Duration;
+
+use tokio::sync::Mutex;
+use tokio::time::{self, Instant};
+
+/// Token bucket limiting the bytes per second downloaded across all concurrent requests
+#[derive(Debug)]
+pub struct RateLimiter {
+    bytes_per_second: f64,
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// Bytes that can be downloaded right away. Negative while waiting for earlier downloads
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl RateLimiter {
+    /// Allow `bytes_per_second` on average, with bursts of up to one second's worth.
+    pub fn new(bytes_per_second: u64) -> Self {
+        Self {
+            bytes_per_second: bytes_per_second as f64,
+            bucket: Mutex::new(Bucket {
+                tokens: bytes_per_second as f64,
+                refilled: Instant::now(),
+            }),
+        }
+    }
+
+    /// Account for `bytes` downloaded, waiting until they fit into the rate.
+    pub async fn consume(&self, bytes: usize) {
+        let wait = {
+            let mut bucket = self.bucket.lock().await;
+            let now = Instant::now();
+            let refill = now.duration_since(bucket.refilled).as_secs_f64() * self.bytes_per_second;
+            bucket.tokens = (bucket.tokens + refill).min(self.bytes_per_second);
+            bucket.refilled = now;
+            bucket.tokens -= bytes as f64;
+            if bucket.tokens < 0.0 {
+                Duration::from_secs_f64(-bucket.tokens / self.bytes_per_second)
+            } else {
+                Duration::ZERO
+            }
+        };
+        time::sleep(wait).await;
+    }
+}
@@ -0,0 +1,168 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use tokio::fs;
+use tracing::warn;
+
+use crate::merge::read_segment;
+use crate::mpd::MediaType;
+use crate::pts::get_pts;
+use crate::state::{State, StateSnapshot};
+
+/// Suffixes of files left behind by interrupted writes
+const PARTIAL_SUFFIXES: [&str; 2] = [".part", ".tmp"];
+
+/// Clean up segments left in `dir` by a previous download and mark the remaining ones as
+/// downloaded in `state`, so resuming continues from them.
+///
+/// Partially written files are removed, as are segments with the same time as another segment
+/// or starting within the duration of the previous one. Durations are taken from the state
+/// written by the previous download, if any.
+/// Returns the number of segments kept and the number of files removed.
+/// Running it again on the same directory removes nothing more.
+pub async fn reconcile_segments(
+    state: &mut State,
+    dir: impl AsRef<Path>,
+    ffprobe: &Path,
+) -> Result<(usize, usize)> {
+    let base_dir = dir.as_ref().parent().unwrap_or(dir.as_ref());
+    let durations = StateSnapshot::read(base_dir)
+        .await
+        .map(|snapshot| snapshot.durations)
+        .unwrap_or_default();
+
+    // Collect segments by media type and time
+    let mut segments: HashMap<MediaType, BTreeMap<usize, Vec<PathBuf>>> = HashMap::new();
+    let mut removed = 0;
+    let mut entries = fs::read_dir(dir.as_ref()).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        if entry.file_type().await?.is_dir() {
+            continue;
+        }
+        let file_name = entry.file_name().to_string_lossy().to_string();
+        if PARTIAL_SUFFIXES.iter().any(|s| file_name.ends_with(s)) {
+            fs::remove_file(entry.path()).await?;
+            removed += 1;
+            continue;
+        }
+        if let Some((media_type, t)) = parse_segment_file_name(&file_name) {
+            let paths = segments
+                .entry(media_type)
+                .or_default()
+                .entry(t)
+                .or_default();
+            paths.push(entry.path());
+        }
+    }
+
+    // Keep one file per time, and drop segments overlapping the previous one
+    let mut kept = 0;
+    for (media_type, segments) in segments {
+        let mut covered_until = None;
+        for (t, mut paths) in segments {
+            paths.sort();
+            let overlaps = covered_until.is_some_and(|end| t < end);
+            let duplicates = if overlaps { &paths[..] } else { &paths[1..] };
+            for path in duplicates {
+                fs::remove_file(path).await?;
+                removed += 1;
+            }
+            if overlaps {
+                continue;
+            }
+
+            // The earliest segment is where searching past segments continues from. One that
+            // can't be probed is likely corrupt, and the next one is the earliest instead
+            if covered_until.is_none() {
+                let pts = match read_segment(&paths[0]) {
+                    Ok(data) => get_pts(ffprobe, data).await,
+                    Err(e) => Err(e),
+                };
+                match pts {
+                    Ok((pts, _)) => {
+                        state.back_pts.insert(media_type.clone(), pts);
+                    }
+                    Err(e) => {
+                        warn!("Removing {:?}, it can't be probed ({e})", paths[0]);
+                        fs::remove_file(&paths[0]).await?;
+                        removed += 1;
+                        continue;
+                    }
+                }
+            }
+
+            let d = durations.get(&media_type).and_then(|d| d.get(&t)).copied();
+            state
+                .downloaded_segs
+                .get_mut(&media_type)
+                .unwrap()
+                .insert(t);
+            if let Some(d) = d {
+                state.durations.get_mut(&media_type).unwrap().insert(t, d);
+            }
+            covered_until = Some(t + d.unwrap_or(1));
+            kept += 1;
+        }
+    }
+
+    Ok((kept, removed))
+}
+
+/// Media type and time of a segment file name, as written by `media_segment_path`.
+/// The time is the last number in the file name.
+pub(crate) fn parse_segment_file_name(file_name: &str) -> Option<(MediaType, usize)> {
+    let file_name = file_name.strip_suffix(".gz").unwrap_or(file_name);
+    let media_type = if file_name.ends_with(".m4v") || file_name.starts_with("video_") {
+        MediaType::Video
+    } else if file_name.ends_with(".m4a") || file_name.starts_with("audio_") {
+        MediaType::Audio
+    } else {
+        return None;
+    };
+
+    let stem = file_name
+        .rsplit_once('.')
+        .map_or(file_name, |(stem, _)| stem);
+    Some((media_type, trailing_number(stem)?))
+}
+
+/// Number at the end of `stem`, if any
+pub(super) fn trailing_number(stem: &str) -> Option<usize> {
+    let digits = stem.len() - stem.trim_end_matches(|c: char| c.is_ascii_digit()).len();
+    stem[stem.len() - digits..].parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::Url;
+
+    use super::*;
+    use crate::download::media_segment_path;
+
+    #[test]
+    fn segment_file_names_are_parsed_back() {
+        let urls = [
+            ("https://cdn/live/1790_0-12000.m4v", false),
+            ("https://cdn/live/1790_0-7.m4v", false),
+            ("https://cdn/live/1790_0-7.m4v", true),
+        ];
+        for (url, compat_filenames) in urls {
+            let url = Url::parse(url).unwrap();
+            let path = media_segment_path(
+                Path::new("."),
+                &url,
+                &MediaType::Video,
+                12000,
+                compat_filenames,
+            )
+            .unwrap();
+            let file_name = path.file_name().unwrap().to_string_lossy();
+            assert_eq!(
+                parse_segment_file_name(&file_name),
+                Some((MediaType::Video, 12000)),
+                "{file_name}"
+            );
+        }
+    }
+}
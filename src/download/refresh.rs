@@ -0,0 +1,49 @@
+use std::process::Stdio;
+
+use anyhow::Result;
+use reqwest::{Client, Url};
+
+use super::DownloadConfig;
+use crate::error::IgLiveError;
+use crate::hooks::shell_command;
+use crate::resolve::resolve_mpd_url;
+
+/// Get a fresh .mpd URL for the stream once the original expired, by running
+/// [DownloadConfig::refresh_command] or else looking up [DownloadConfig::username]'s broadcast.
+/// Returns `None` if neither is set.
+pub(super) async fn refresh_mpd_url(
+    client: &Client,
+    config: &DownloadConfig,
+) -> Result<Option<Url>> {
+    if let Some(command) = &config.refresh_command {
+        return run_refresh_command(command).await.map(Some);
+    }
+    match &config.username {
+        Some(username) => {
+            let (cookie, user_agent) = (config.cookie.as_deref(), config.user_agent.as_deref());
+            Ok(Some(
+                resolve_mpd_url(client, username, cookie, user_agent).await?,
+            ))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Run `command` in the shell and parse the first line it prints as a URL
+async fn run_refresh_command(command: &str) -> Result<Url> {
+    let output = shell_command(command)
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()
+        .await?;
+    if !output.status.success() {
+        return Err(IgLiveError::RefreshCommandFailed(output.status.to_string()).into());
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let line = stdout
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty())
+        .ok_or_else(|| IgLiveError::RefreshCommandFailed("no URL printed".to_owned()))?;
+    Ok(Url::parse(line)?)
+}
@@ -0,0 +1,134 @@
+use std::path::PathBuf;
+
+use chrono::{DateTime, Utc};
+
+use crate::error::IgLiveError;
+use crate::merge::sanitize_file_name;
+
+/// Values substituted into an output template
+pub(super) struct TemplateValues<'a> {
+    /// Instagram username, if known
+    pub username: Option<&'a str>,
+
+    /// Live stream ID
+    pub stream_id: &'a str,
+
+    /// Time the stream started
+    pub start_time: DateTime<Utc>,
+
+    /// Width and height of the downloaded video, if any
+    pub resolution: Option<(usize, usize)>,
+}
+
+/// Fill in the `{placeholder}`s of `template`. `/` separates directories, and `.` and `..`
+/// directories are rejected.
+///
+/// Placeholders are `{username}` (or `unknown`), `{stream_id}`, `{date}` and `{time}` in UTC
+/// (`2024-01-31` and `133000`), and `{resolution}` (e.g. `720x1280`, or `audio`).
+pub(super) fn render_output_template(
+    template: &str,
+    values: &TemplateValues,
+) -> anyhow::Result<PathBuf> {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let (before, from) = rest.split_at(start);
+        rendered += before;
+        let end = from
+            .find('}')
+            .ok_or_else(|| IgLiveError::UnknownPlaceholder(from.to_owned()))?;
+        let value = match &from[1..end] {
+            "username" => values.username.unwrap_or("unknown").to_owned(),
+            "stream_id" => values.stream_id.to_owned(),
+            "date" => values.start_time.format("%Y-%m-%d").to_string(),
+            "time" => values.start_time.format("%H%M%S").to_string(),
+            "resolution" => match values.resolution {
+                Some((width, height)) => format!("{width}x{height}"),
+                None => "audio".to_owned(),
+            },
+            _ => return Err(IgLiveError::UnknownPlaceholder(from[..=end].to_owned()).into()),
+        };
+        rendered += &sanitize_file_name(&value);
+        rest = &from[end + 1..];
+    }
+    rendered += rest;
+    if rendered.split('/').any(|name| name == "." || name == "..") {
+        return Err(IgLiveError::RelativeOutputPath(rendered).into());
+    }
+    Ok(rendered.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn values(stream_id: &str) -> TemplateValues<'_> {
+        TemplateValues {
+            username: Some("user"),
+            stream_id,
+            start_time: Utc.with_ymd_and_hms(2024, 1, 31, 13, 30, 0).unwrap(),
+            resolution: Some((720, 1280)),
+        }
+    }
+
+    fn is_error(result: anyhow::Result<PathBuf>, check: fn(&IgLiveError) -> bool) -> bool {
+        result.is_err_and(|e| e.downcast_ref().is_some_and(check))
+    }
+
+    #[test]
+    fn placeholders_are_filled_in() {
+        let template = "{username}/{date}_{time}_{stream_id}_{resolution}";
+        let rendered = render_output_template(template, &values("123")).unwrap();
+        assert_eq!(
+            rendered,
+            PathBuf::from("user/2024-01-31_133000_123_720x1280")
+        );
+
+        let values = TemplateValues {
+            username: None,
+            resolution: None,
+            ..values("123")
+        };
+        let rendered = render_output_template("{username}-{resolution}", &values).unwrap();
+        assert_eq!(rendered, PathBuf::from("unknown-audio"));
+    }
+
+    #[test]
+    fn unknown_and_unclosed_placeholders_are_rejected() {
+        let unknown = |e: &IgLiveError| matches!(e, IgLiveError::UnknownPlaceholder(_));
+        assert!(is_error(
+            render_output_template("{user}", &values("123")),
+            unknown
+        ));
+        assert!(is_error(
+            render_output_template("a/{date", &values("123")),
+            unknown
+        ));
+    }
+
+    #[test]
+    fn values_are_sanitized() {
+        let rendered = render_output_template("out/{stream_id}", &values("a/b:c")).unwrap();
+        assert_eq!(rendered, PathBuf::from("out/a_b_c"));
+    }
+
+    #[test]
+    fn dot_directories_are_rejected() {
+        let relative = |e: &IgLiveError| matches!(e, IgLiveError::RelativeOutputPath(_));
+        assert!(is_error(
+            render_output_template("{stream_id}", &values("..")),
+            relative
+        ));
+        assert!(is_error(
+            render_output_template("a/{stream_id}/b", &values(".")),
+            relative
+        ));
+        assert!(is_error(
+            render_output_template("../{stream_id}", &values("1")),
+            relative
+        ));
+        assert!(render_output_template("{stream_id}", &values("..a")).is_ok());
+    }
+}
@@ -0,0 +1,52 @@
+use std::path::Path;
+
+use anyhow::Result;
+use reqwest::{Client, Url};
+use tokio::fs;
+
+use super::segment_path;
+use crate::error::IgLiveError;
+use crate::mpd::Representation;
+
+/// Download thumbnail or poster images of image representations into `dir`.
+pub async fn download_thumbnails(
+    client: &Client,
+    url_base: &Url,
+    reps: impl IntoIterator<Item = &Representation>,
+    dir: impl AsRef<Path>,
+) -> Result<()> {
+    for rep in reps {
+        let segments = rep.segments();
+        let urls = if segments.is_empty() {
+            // Single image without a timeline
+            match &rep.segment_template {
+                Some(template) => vec![url_base.join(&template.media_path)?],
+                None => vec![],
+            }
+        } else {
+            segments
+                .iter()
+                .map(|s| rep.download_url(url_base, s.t))
+                .collect::<Result<_>>()?
+        };
+
+        for url in urls {
+            let path = segment_path(dir.as_ref(), &url)?;
+            if fs::try_exists(&path).await? {
+                continue;
+            }
+
+            let resp = client.get(url.as_str()).send().await?;
+            if !resp.status().is_success() {
+                return Err(IgLiveError::StatusError(
+                    resp.status().into(),
+                    url.as_str().to_owned(),
+                )
+                .into());
+            }
+            fs::write(path, resp.bytes().await?).await?;
+        }
+    }
+
+    Ok(())
+}
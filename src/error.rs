@@ -1,17 +1,146 @@
 use thiserror::Error;
 
+use crate::mpd::MediaType;
+
+/// Errors specific to downloading and merging IG live streams
 #[derive(Error, Debug)]
 pub enum IgLiveError {
+    /// URL can't be used to locate a segment
     #[error("Invalid URL")]
     InvalidUrl,
-    #[error("Received status code 404received")]
+    /// Path has no file name or parent directory
+    #[error("Invalid path")]
+    InvalidPath,
+    /// Segment does not exist (yet)
+    #[error("Received status code 404")]
     StatusNotFound,
+    /// Unexpected HTTP status code
     #[error("Received status code {0}, url: {1}")]
     StatusError(u16, String),
-    #[error("Missing init")]
+    /// Segment response was successful but too small to be a real segment
+    #[error("Segment response is only {0} bytes")]
+    SegmentTooSmall(usize),
+    /// Segment response was successful but is a web page or other text, e.g. a login page
+    #[error("Expected media but received {0}, access may have expired")]
+    UnexpectedContentType(String),
+    /// `ffmpeg` exited unsuccessfully
+    #[error("ffmpeg failed")]
     FfmpegFail,
+    /// Merge option can't be applied by the built-in muxer
+    #[error("{0} requires ffmpeg, which isn't used for merging")]
+    RequiresFfmpeg(&'static str),
+    /// Segment can't be remuxed by the built-in muxer
+    #[error("Segment is not a valid fragmented MP4: {0}")]
+    InvalidMp4(String),
+    /// Download is much shorter than the stream was expected to be
+    #[error("Captured {0} of the expected {1}, the download may have stopped early")]
+    ShortCapture(String, String),
+    /// Segments are missing and merging across gaps wasn't allowed
+    #[error("{0} segment(s) missing, not merging. Repair the download or merge with --force")]
+    MissingSegments(usize),
+    /// Segment was found but does not directly precede the earliest downloaded segment
     #[error("PTS too early")]
     PtsTooEarly,
+    /// Manifest has no period
     #[error("MPD manifest is empty or does not contain any media periods")]
     EmptyManifest,
+    /// Manifest has a period, but its adaptation sets have no representations
+    #[error("MPD manifest period has no representations")]
+    NoRepresentations,
+    /// Manifest has representations, but none are video or audio
+    #[error("MPD manifest has no video or audio representations")]
+    NoMedia,
+    /// Manifest has audio but no video
+    #[error("MPD manifest has no video, only audio")]
+    AudioOnly,
+    /// Manifest has video but no audio
+    #[error("MPD manifest has no audio, only video")]
+    VideoOnly,
+    /// User has no ongoing live stream to find a manifest for
+    #[error("{0} is not live, or the account is private and no session cookie was given")]
+    NotLive(String),
+    /// Broadcast has just started and no segments are available
+    #[error("Manifest does not list any segments yet")]
+    NoSegmentsYet,
+    /// HTTP/3 was requested with a proxy, which HTTP/3 requests would bypass
+    #[error("HTTP/3 can't be used with a proxy, including one set in the environment")]
+    Http3WithProxy,
+    /// Every listed segment of a media type came back empty or as a web page, even after
+    /// refreshing the manifest
+    #[error("No listed segment could be downloaded, responses were empty or not media")]
+    NoSegmentsAccepted,
+    /// No video representation lists a frame rate to select by
+    #[error("No video representation in the manifest has a frame rate")]
+    MissingFrameRate,
+    /// Manifest has fewer video adaptation sets than the one selected
+    #[error("Manifest has no video adaptation set {0}")]
+    NoSuchVideoSet(usize),
+    /// No video representation has a bandwidth within the selected range
+    #[error("Manifest has no video with a bandwidth {0}")]
+    NoBandwidthInRange(String),
+    /// No video representation has the selected quality
+    #[error("Manifest has no video of quality {0}")]
+    NoSuchQuality(String),
+    /// Command given to refresh an expired manifest URL failed or didn't print a URL
+    #[error("Manifest URL refresh command failed: {0}")]
+    RefreshCommandFailed(String),
+    /// Output template contains a placeholder that doesn't exist or isn't closed
+    #[error("Unknown output template placeholder {0}")]
+    UnknownPlaceholder(String),
+    /// Rendered output template has a `.` or `..` directory, which could leave the output
+    /// directory
+    #[error("Output template renders to {0}, which has a . or .. directory")]
+    RelativeOutputPath(String),
+    /// Hook command exited unsuccessfully
+    #[error("Hook command failed: {0}")]
+    HookFailed(String),
+    /// Representation has no way to locate its segments
+    #[error("Representation has neither a SegmentTemplate nor a SegmentList")]
+    MissingSegmentInfo,
+    /// Requested segment is not listed in the representation's `SegmentList` or `SegmentTimeline`
+    #[error("Segment t={0} is not listed in the manifest")]
+    SegmentNotListed(usize),
+    /// Manifest URL no longer loads, usually because its signature expired after the stream
+    #[error("Manifest URL can't be loaded anymore (status code {0}), a current URL is needed")]
+    ManifestExpired(u16),
+    /// Codec can't be merged into the output container
+    #[error("{0:?} codec {1} can't be merged into an mp4 file")]
+    IncompatibleCodec(MediaType, String),
+    /// Video and audio segment times can't be aligned
+    #[error("Video timescale {0} differs from audio timescale {1}")]
+    MismatchedTimescales(usize, usize),
+    /// Download was cancelled before the request was sent
+    #[error("Download was cancelled")]
+    Cancelled,
+}
+
+/// Replace the query strings of `http://` and `https://` URLs in `text`, which hold signatures
+/// of signed URLs, with `<redacted>`.
+pub fn redact_urls(text: &str) -> String {
+    let mut redacted = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("http") {
+        let (before, from) = rest.split_at(start);
+        redacted += before;
+        if !from.starts_with("http://") && !from.starts_with("https://") {
+            redacted += "http";
+            rest = &from["http".len()..];
+            continue;
+        }
+
+        let end = from
+            .find(|c: char| c.is_whitespace() || matches!(c, '"' | '\'' | ')' | '>'))
+            .unwrap_or(from.len());
+        let (url, after) = from.split_at(end);
+        match url.split_once('?') {
+            Some((path, _)) => {
+                redacted += path;
+                redacted += "?<redacted>";
+            }
+            None => redacted += url,
+        }
+        rest = after;
+    }
+    redacted += rest;
+    redacted
 }
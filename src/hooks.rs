@@ -0,0 +1,146 @@
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use reqwest::header::CONTENT_TYPE;
+use reqwest::{Client, Url};
+use serde::Serialize;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tracing::warn;
+
+use crate::error::IgLiveError;
+
+/// Longest time to wait for a webhook to answer
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Shell command or URL notified of a download lifecycle event
+#[derive(Clone, Debug)]
+pub enum Hook {
+    /// Shell command, run with the event as JSON on stdin, and its name and download directory
+    /// in the `IGLIVE_EVENT` and `IGLIVE_PATH` environment variables
+    Command(String),
+
+    /// URL the event is POSTed to as JSON
+    Webhook(Url),
+}
+
+/// Download lifecycle event
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum HookEventKind {
+    /// The download directory was created and downloading began
+    Start,
+    /// Downloading finished, before merging, or failed
+    End,
+    /// The merged video was written
+    MergeComplete,
+}
+
+/// Payload of a hook
+#[derive(Serialize, Clone, Debug)]
+pub struct HookEvent {
+    /// What happened
+    pub event: HookEventKind,
+
+    /// When it happened
+    pub time: DateTime<Utc>,
+
+    /// Live stream ID, if known
+    pub stream_id: Option<String>,
+
+    /// Username of the streaming account, if known
+    pub username: Option<String>,
+
+    /// Download directory
+    pub path: PathBuf,
+
+    /// Merged video file, for [HookEventKind::MergeComplete]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output: Option<PathBuf>,
+
+    /// Number of gaps merged across, for [HookEventKind::MergeComplete]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub missing_segments: Option<usize>,
+
+    /// Why downloading failed, for [HookEventKind::End]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl FromStr for Hook {
+    type Err = anyhow::Error;
+
+    /// Parse `http://` and `https://` URLs as webhooks, and anything else as a shell command
+    fn from_str(s: &str) -> Result<Self> {
+        if s.starts_with("http://") || s.starts_with("https://") {
+            Ok(Self::Webhook(Url::parse(s)?))
+        } else {
+            Ok(Self::Command(s.to_owned()))
+        }
+    }
+}
+
+impl Hook {
+    /// Notify the hook of `event`. Failures are only warned about, so a broken hook doesn't
+    /// stop the download.
+    pub async fn notify(&self, event: &HookEvent) {
+        if let Err(e) = self.try_notify(event).await {
+            warn!("{:?} hook failed: {e}", event.event);
+        }
+    }
+
+    async fn try_notify(&self, event: &HookEvent) -> Result<()> {
+        let payload = serde_json::to_vec(event)?;
+        match self {
+            Self::Command(command) => {
+                let event_name = serde_json::to_value(event.event)?;
+                let mut child = shell_command(command)
+                    .env("IGLIVE_EVENT", event_name.as_str().unwrap_or_default())
+                    .env("IGLIVE_PATH", &event.path)
+                    .stdin(Stdio::piped())
+                    .spawn()?;
+                if let Some(mut stdin) = child.stdin.take() {
+                    // The command may exit without reading its input
+                    let _ = stdin.write_all(&payload).await;
+                }
+                let status = child.wait().await?;
+                if !status.success() {
+                    return Err(IgLiveError::HookFailed(status.to_string()).into());
+                }
+            }
+            Self::Webhook(url) => {
+                let resp = Client::new()
+                    .post(url.clone())
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(payload)
+                    .timeout(WEBHOOK_TIMEOUT)
+                    .send()
+                    .await?;
+                let status = resp.status();
+                if !status.is_success() {
+                    return Err(IgLiveError::StatusError(status.into(), url.to_string()).into());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Command running `command` in the system shell
+pub(crate) fn shell_command(command: &str) -> Command {
+    let mut shell = if cfg!(windows) {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C");
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+    shell.arg(command);
+    shell
+}
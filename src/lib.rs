@@ -9,16 +9,55 @@
 //! Live streams can also be downloaded for a short while after they've ended.
 //! However, a valid `.mpd` link must be provided, which may be impossible to get at that point if
 //! you do not have an existing link.
+//!
+//! Progress messages are logged with [tracing], install a subscriber to see them.
+
+mod audio_track;
 
 /// IG live segment downloader
 pub mod download;
 
-mod error;
+/// Errors returned by the downloader
+pub mod error;
+
+/// Shell commands and webhooks notified of download lifecycle events
+pub mod hooks;
+
+mod marker;
 
 /// Video and audio segment merger
 pub mod merge;
 
-mod mpd;
+mod metadata;
+
+mod mp4;
+
+/// Prometheus-style download and merge metrics
+#[cfg(feature = "metrics")]
+pub mod metrics;
+
+/// Automatic download of a user's live streams as they begin
+pub mod monitor;
+
+mod probe_cache;
+
+/// DASH manifest parser
+pub mod mpd;
+
+/// Persistent queue of streams downloaded by a long-running daemon
+pub mod queue;
+
+/// Manifest URL lookup from Instagram usernames and live URLs
+pub mod resolve;
 
 mod state;
+
+/// Reconstructed segment timelines
+pub mod timeline;
+
+mod util;
+
 mod pts;
+
+#[cfg(test)]
+mod test_util;
@@ -1,45 +1,719 @@
+use std::io::{self, IsTerminal};
+use std::net::IpAddr;
 use std::path::PathBuf;
 use std::process;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use anyhow::Result;
-use clap::{Parser, Subcommand};
-use download_iglive::download::{download, DownloadConfig, DownloadSegments};
-use download_iglive::merge::merge;
+use anyhow::{Error, Result};
+use chrono::{DateTime, Utc};
+use clap::{ArgAction, Args as ClapArgs, Parser, Subcommand, ValueEnum};
+use download_iglive::download::{
+    build_client, complete_dir_path, download, download_preview, download_with_manifest,
+    estimate_size, heal, CancellationToken, DeltaSeed, DownloadConfig, DownloadSegments, GapReport,
+    IdChangePolicy, PauseSwitch, ProgressOutput, RateLimiter, StateSnapshot, Timings,
+};
+use download_iglive::error::{redact_urls, IgLiveError};
+use download_iglive::hooks::Hook;
+use download_iglive::merge::{
+    flatten_output, merge, MergeConfig, Muxer, OutputFormat, Recode,
+};
+use download_iglive::monitor::run_monitor;
+use download_iglive::mpd::{
+    BandwidthRange, FrameRatePreference, MediaSelection, MediaType, Mpd, Quality,
+};
+use download_iglive::queue::{run_daemon, Queue};
+use download_iglive::resolve::{resolve_mpd_url, username_from_input};
+use download_iglive::timeline;
+use futures::future;
+use indicatif::{HumanBytes, MultiProgress};
+use tracing::{info, warn, Event, Level, Subscriber};
+use tracing_subscriber::filter::{LevelFilter, Targets};
+use tracing_subscriber::fmt::format::{self, FormatEvent, FormatFields};
+use tracing_subscriber::fmt::{self, FmtContext, MakeWriter};
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::registry::LookupSpan;
 
 /// Download Instagram live streams, including past segments
 #[derive(Parser, Debug)]
 struct Args {
     #[clap(subcommand)]
     command: Command,
+
+    /// Log more details, e.g. each past segment found. Repeat to also log each time checked
+    #[clap(short, long, action = ArgAction::Count, global = true)]
+    verbose: u8,
+
+    /// Only log warnings
+    #[clap(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Also write the log to this file, with timestamps and at least --verbose details
+    #[clap(long, global = true)]
+    log_file: Option<PathBuf>,
 }
 
 #[derive(Subcommand, Debug)]
 enum Command {
-    Download(Download),
+    Download(Box<Download>),
     Merge(Merge),
+    Heal(Heal),
+    Preview(Preview),
+    DumpState(DumpState),
+    DumpTimeline(DumpTimeline),
+    List(List),
+    Queue(QueueArgs),
+    Daemon(Daemon),
+    Monitor(Box<Monitor>),
 }
 
 /// Download a live stream
 #[derive(Parser, Debug)]
 struct Download {
-    /// URL of .mpd file
-    mpd_url: String,
+    /// URLs of .mpd files, or Instagram usernames or live URLs (e.g.
+    /// "https://www.instagram.com/<user>/live/") to find them for.
+    /// Several streams are downloaded at once, each into its own subdirectory
+    #[clap(required_unless_present = "batch_file")]
+    mpd_urls: Vec<String>,
+
+    /// File listing streams to download at once, one .mpd URL, username or live URL per line.
+    /// Empty lines and lines starting with "#" are ignored
+    #[clap(long)]
+    batch_file: Option<PathBuf>,
+
+    /// Session cookies of a logged-in account, used to find the .mpd URL from a username or live
+    /// URL. Copy the Cookie header of any instagram.com request from the browser's network monitor
+    #[clap(long)]
+    cookie: Option<String>,
+
+    /// Shell command printing a fresh .mpd URL, run when the URL's token expires mid-stream.
+    /// Without it, the URL is looked up again if a username or live URL was given
+    #[clap(long)]
+    refresh_command: Option<String>,
 
     /// Output directory
     #[clap(short, long)]
     output: Option<PathBuf>,
 
+    /// Name the download directory and merged video by this template instead of the stream ID
+    /// (e.g. "{username}/{date}_{time}_{stream_id}"), within the output directory if given.
+    /// Placeholders: {username}, {stream_id}, {date}, {time}, {resolution}
+    #[clap(long)]
+    output_template: Option<String>,
+
     /// Don't merge into one video file after download
     #[clap(short, long)]
     no_merge: bool,
 
+    /// Don't merge what was downloaded when stopped with Ctrl-C or SIGTERM
+    #[clap(long)]
+    no_merge_on_interrupt: bool,
+
     /// Don't download past segments
     #[clap(short, long)]
     live_only: bool,
-    
+
+    /// Media types to download past segments for
+    #[clap(long, value_enum, default_value = "all", conflicts_with = "live_only")]
+    backwards: Backwards,
+
+    #[clap(flatten)]
+    connection: ConnectionArgs,
+
+    /// Number of times a segment request is retried after a server error, rate limiting or
+    /// network error
+    #[clap(long, default_value = "3")]
+    retries: usize,
+
+    /// Delay before retrying a segment request (e.g. "1s"), doubled for every further retry
+    #[clap(long, value_parser = humantime::parse_duration, default_value = "1s")]
+    retry_delay: Duration,
+
+    /// Maximum download rate in bytes per second across all segment downloads of all streams,
+    /// with an optional KiB, MiB or GiB suffix (e.g. "2MiB")
+    #[clap(long, value_parser = parse_byte_size)]
+    rate_limit: Option<u64>,
+
+    /// Request segments over HTTP/3 (QUIC) first, falling back to HTTP/1.1 or HTTP/2 if that
+    /// fails. Needs a build with the http3 feature. Can't be used with a proxy, including one
+    /// set in the environment
+    #[clap(long, conflicts_with = "proxy")]
+    http3: bool,
+
+    /// Quality to download: "best", "worst", a maximum resolution (e.g. "720p"), a maximum video
+    /// bandwidth in bits per second (e.g. "2M") or "audio-only"
+    #[clap(long, value_parser = parse_quality, default_value = "best")]
+    quality: Quality,
+
+    /// Prefer video with the highest or lowest frame rate over the highest bandwidth
+    #[clap(long, value_enum)]
+    select_by_framerate: Option<FrameRatePreference>,
+
+    /// Video adaptation set to download from, if the stream offers several (e.g. different
+    /// crops). See the list command
+    #[clap(long)]
+    video_set: Option<usize>,
+
+    /// Download the best video with a bandwidth in this range of bits per second (e.g. "2M-4M",
+    /// "-3M" or "1.5M-"). See the list command
+    #[clap(long, value_parser = parse_bandwidth_range)]
+    bandwidth: Option<BandwidthRange>,
+
+    /// Also download live segments of every other audio track (e.g. commentary) and merge
+    /// them as additional audio streams
+    #[clap(long)]
+    all_audio: bool,
+
     /// Number of past segments to check in parallel
     #[clap(short, long, default_value = "10")]
     parallel_candidates: usize,
+
+    /// Maximum number of past segments checked in parallel around the same time delta, so less
+    /// common deltas are checked too
+    #[clap(long)]
+    limit_candidates_per_delta: Option<usize>,
+
+    /// Download live segments before past segments, limiting all concurrent downloads to
+    /// --parallel-candidates. This is the default
+    #[clap(long, overrides_with = "no_prioritize_live")]
+    prioritize_live: bool,
+
+    /// Check past segments independently of live segments, which may be missed on slow
+    /// connections
+    #[clap(long, overrides_with = "prioritize_live")]
+    no_prioritize_live: bool,
+
+    /// Number of concurrent downloads kept free for live segments with --prioritize-live
+    #[clap(long, default_value = "2")]
+    reserved_live_downloads: usize,
+
+    /// Check past segments one at a time to save bandwidth on slow or metered connections
+    #[clap(long)]
+    sequential: bool,
+
+    /// Split the search for past segments into this many time windows searched in parallel,
+    /// to catch up faster on long streams
+    #[clap(long, default_value = "1")]
+    backwards_windows: usize,
+
+    /// Maximum number of past segments downloaded in parallel by each search
+    #[clap(long, default_value = "10")]
+    backwards_concurrency: usize,
+
+    /// Farthest offset from each known time delta at which past segments are searched for
+    /// before assuming segments are missing
+    #[clap(long, default_value = "1000")]
+    search_range: usize,
+
+    /// Time assumed missing at each gap in past segments, searched again with --reprobe-gaps
+    #[clap(long, default_value = "2000")]
+    assumed_missing_delta: usize,
+
+    /// Number of gaps in a row after which the search for past segments gives up
+    #[clap(long, default_value = "5")]
+    max_consecutive_skips: usize,
+
+    /// Stop everything if searching past video or audio fails. With --fail-fast=false, the
+    /// failure is reported and the other track and live segments are still downloaded and merged
+    #[clap(long, default_value_t = true, action = ArgAction::Set)]
+    fail_fast: bool,
+
+    /// Start checking past segments one at a time and gradually check more in parallel over
+    /// this long (e.g. "5s"), to avoid triggering rate limits
+    #[clap(long, value_parser = humantime::parse_duration, default_value = "0s")]
+    segment_concurrency_ramp: Duration,
+
+    /// Initial segment time deltas used to search past segments
+    #[clap(long, value_enum, default_value = "default")]
+    deltas_seed: DeltaSeed,
+
+    /// Number of newest live segments to learn time deltas from before searching past segments
+    #[clap(long, default_value = "0")]
+    warm_up_segments: usize,
+
+    /// Continue searching past segments from where a previous download into this directory
+    /// stopped
+    #[clap(long, conflicts_with = "live_only")]
+    resume_backwards_from: Option<PathBuf>,
+
+    /// Number of times a past segment with a too early PTS is checked again before skipping it
+    #[clap(long, default_value = "3")]
+    max_pts_early_retries: usize,
+
+    /// Don't check past segment times that fall within already downloaded segments
+    #[clap(long)]
+    skip_covered: bool,
+
+    /// After searching for past segments, search where segments appeared to be missing once
+    /// more, one at a time, and report how many gaps were recovered
+    #[clap(long)]
+    reprobe_gaps: bool,
+
+    /// After downloading, wait this long (e.g. "5m"), then search the gaps between downloaded
+    /// segments once more, as segments sometimes appear on the CDN late. See also the heal command
+    #[clap(long, value_parser = humantime::parse_duration)]
+    heal_after: Option<Duration>,
+
+    /// When downloading into a directory with segments from a previous download, remove
+    /// partial, duplicate and overlapping segments and continue from the remaining ones
+    #[clap(long)]
+    dedupe_on_resume: bool,
+
+    /// Continue a download interrupted in the same output directory without downloading its
+    /// segments again. Implies --dedupe-on-resume
+    #[clap(long)]
+    resume: bool,
+
+    /// How often to save the download state for --resume (e.g. "30s"), "0s" only saves it after
+    /// downloading
+    #[clap(long, value_parser = humantime::parse_duration, default_value = "30s")]
+    checkpoint_interval: Duration,
+
+    /// Remember past segment times that don't exist, so downloading again into the same
+    /// directory doesn't check them again
+    #[clap(long)]
+    probe_cache: bool,
+
+    /// What to do when the stream is re-published under a new ID
+    #[clap(long, value_enum, default_value = "continue")]
+    on_id_change: IdChangePolicy,
+
+    /// Keep downloading new live segments for this long (e.g. "30s") after the stream ends
+    #[clap(long, value_parser = humantime::parse_duration, default_value = "0s")]
+    post_finish_grace: Duration,
+
+    /// Log the segments listed by each manifest refresh to manifests.jsonl in the output
+    /// directory
+    #[clap(long)]
+    manifest_log: bool,
+
+    /// Number of times a manifest without segments is refreshed before giving up, when not
+    /// downloading live segments, or whose segments all came back empty or not media
+    #[clap(long, default_value = "3")]
+    empty_manifest_retries: usize,
+
+    /// Number of consecutive failed manifest refreshes tolerated while downloading live
+    #[clap(long, default_value = "5")]
+    max_manifest_failures: usize,
+
+    /// How to show progress
+    #[clap(long, value_enum, default_value = "bars")]
+    progress: ProgressOutput,
+
+    /// Show the number of segment download retries
+    #[clap(long)]
+    segment_retries_visible: bool,
+
+    /// Redact the query strings of signed URLs from logs and errors, e.g. to share them in
+    /// bug reports
+    #[clap(long)]
+    mask_urls: bool,
+
+    /// Log a line with the download's progress this often (e.g. "1m"), even if nothing changed
+    #[clap(long, value_parser = humantime::parse_duration)]
+    heartbeat: Option<Duration>,
+
+    /// Check that the video and audio codecs can be merged before downloading segments
+    #[clap(long)]
+    check_compatibility: bool,
+
+    /// Named pipe to stream live video segments to in timeline order, e.g. for live muxing with
+    /// ffmpeg
+    #[clap(long, requires = "pipe_audio")]
+    pipe_video: Option<PathBuf>,
+
+    /// Named pipe to stream live audio segments to in timeline order
+    #[clap(long, requires = "pipe_video")]
+    pipe_audio: Option<PathBuf>,
+
+    /// Download thumbnail or poster images referenced in the manifest
+    #[clap(long)]
+    thumbnails: bool,
+
+    /// Write ffconcat playlists with per-segment durations for frame-accurate editing
+    #[clap(long, conflicts_with = "compress_segments")]
+    ffconcat: bool,
+
+    /// Write integrity.json with checksums and times of all segments and the manifest when the
+    /// download completes, as an archival record
+    #[clap(long)]
+    integrity: bool,
+
+    /// Segments smaller than this many bytes are treated as not yet available
+    #[clap(long, default_value = "1")]
+    min_segment_size: usize,
+
+    /// Write segments gzip compressed to save disk space until they're merged
+    #[clap(long)]
+    compress_segments: bool,
+
+    /// Name segments with zero-padded times (e.g. "video_000000123456.m4v") so external tools
+    /// sort them correctly
+    #[clap(long)]
+    compat_filenames: bool,
+
+    /// Number of threads ffmpeg may use when merging
+    #[clap(long)]
+    merge_threads: Option<usize>,
+
+    /// Title to name the merged video after and embed in it
+    #[clap(long)]
+    title: Option<String>,
+
+    /// Merge into a fragmented mp4 with fragments aligned to the original segments, for
+    /// re-serving over DASH or HLS
+    #[clap(long)]
+    fragmented: bool,
+
+    /// Set the merged video's modification time to the stream's start time
+    #[clap(long)]
+    set_mtime: bool,
+
+    /// Mark a chapter this often (e.g. "10m") in the merged video, titled with the wall-clock
+    /// time it starts at
+    #[clap(long, value_parser = humantime::parse_duration)]
+    chapter_interval: Option<Duration>,
+
+    /// Merge in memory instead of through temporary files if the segments total at most this
+    /// many bytes
+    #[clap(long)]
+    in_memory_merge_limit: Option<u64>,
+
+    /// Show ffmpeg's output at this log level (e.g. "warning") when merging
+    #[clap(long)]
+    ffmpeg_loglevel: Option<String>,
+
+    /// Program to merge with. "builtin" writes a fragmented mp4 without ffmpeg, "auto" uses it
+    /// if ffmpeg isn't installed
+    #[clap(long, value_enum, default_value = "auto")]
+    muxer: MergeMuxer,
+
+    /// Container to merge into. mkv tolerates the timestamp jumps of missing segments better
+    #[clap(long, value_enum, default_value = "mp4")]
+    format: MergeFormat,
+
+    /// Path of the ffmpeg executable, for installs outside of PATH. ffprobe is run from the same
+    /// directory
+    #[clap(long)]
+    ffmpeg_path: Option<PathBuf>,
+
+    /// Extra arguments passed to ffmpeg before the output file when merging, split like a shell
+    /// would (e.g. "-preset slow -metadata title='My stream'")
+    #[clap(long, value_parser = parse_shell_words, default_value = "", allow_hyphen_values = true)]
+    ffmpeg_args: ::std::vec::Vec<String>,
+
+    #[clap(flatten)]
+    recode: RecodeArgs,
+
+    /// Stop downloading after this long (e.g. "2h 30m") and merge what was downloaded
+    #[clap(long, value_parser = humantime::parse_duration)]
+    max_runtime: Option<Duration>,
+
+    /// Stop downloading once this much of the stream (e.g. "1h") was downloaded live, and merge
+    /// what was downloaded
+    #[clap(long, value_parser = humantime::parse_duration)]
+    max_duration: Option<Duration>,
+
+    /// Stop downloading once this many bytes of segments (e.g. "5GiB") were downloaded, and
+    /// merge what was downloaded
+    #[clap(long, value_parser = parse_byte_size)]
+    max_size: Option<u64>,
+
+    /// Stop downloading at this time (e.g. "2024-01-31T22:00:00+09:00"), and merge what was
+    /// downloaded
+    #[clap(long, value_parser = parse_stop_at)]
+    stop_at: Option<DateTime<Utc>>,
+
+    /// Pause requesting new segments while this file exists, and resume once it is removed.
+    /// On Unix, SIGUSR1 also pauses and SIGUSR2 resumes
+    #[clap(long)]
+    pause_file: Option<PathBuf>,
+
+    /// Download into a subdirectory of the output directory and place only the merged video in
+    /// the output directory
+    #[clap(long)]
+    flatten_output: bool,
+
+    /// Keep the downloaded segments when using --flatten-output
+    #[clap(long)]
+    keep_segments: bool,
+
+    /// Add ".incomplete" to the output directory name until download and merge are complete
+    #[clap(long)]
+    rename_on_complete: bool,
+
+    /// Shell command to run, or http(s) URL to POST to, once downloading begins. The event is
+    /// passed as JSON on stdin or in the request body
+    #[clap(long)]
+    on_start: Option<Hook>,
+
+    /// Shell command to run, or http(s) URL to POST to, once downloading finished or failed
+    #[clap(long)]
+    on_end: Option<Hook>,
+
+    /// Shell command to run, or http(s) URL to POST to, once the merged video is written
+    #[clap(long)]
+    on_merge_complete: Option<Hook>,
+
+    /// Expected stream duration (e.g. "1h"), used to estimate download size
+    #[clap(long, value_parser = humantime::parse_duration)]
+    duration: Option<Duration>,
+
+    /// Expected stream duration (e.g. "1h"). Warn if the download is much shorter
+    #[clap(long, value_parser = humantime::parse_duration)]
+    expect_duration: Option<Duration>,
+
+    /// Fail with an error instead of warning if the download is much shorter than
+    /// --expect-duration
+    #[clap(long, requires = "expect_duration")]
+    strict: bool,
+
+    /// Don't ask for confirmation before large downloads. Not asked with --max-size either
+    #[clap(short, long)]
+    yes: bool,
+}
+
+#[derive(ValueEnum, Clone, Debug)]
+enum Backwards {
+    All,
+    Video,
+    Audio,
+    None,
+}
+
+#[derive(ValueEnum, Clone, Debug)]
+enum MergeFormat {
+    Mp4,
+    Mkv,
+    Ts,
+}
+
+impl From<MergeFormat> for OutputFormat {
+    fn from(format: MergeFormat) -> Self {
+        match format {
+            MergeFormat::Mp4 => OutputFormat::Mp4,
+            MergeFormat::Mkv => OutputFormat::Mkv,
+            MergeFormat::Ts => OutputFormat::Ts,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Debug)]
+enum MergeMuxer {
+    Auto,
+    Ffmpeg,
+    Builtin,
+}
+
+impl From<MergeMuxer> for Muxer {
+    fn from(muxer: MergeMuxer) -> Self {
+        match muxer {
+            MergeMuxer::Auto => Muxer::Auto,
+            MergeMuxer::Ffmpeg => Muxer::Ffmpeg,
+            MergeMuxer::Builtin => Muxer::Builtin,
+        }
+    }
+}
+
+/// Download and merge only the latest few seconds of a live stream, and print the merged clip's
+/// path
+#[derive(Parser, Debug)]
+struct Preview {
+    /// URL of .mpd file
+    mpd_url: String,
+
+    /// Output directory
+    #[clap(short, long)]
+    output: Option<PathBuf>,
+
+    /// Number of latest segments to download
+    #[clap(short, long, default_value = "3")]
+    segments: usize,
+
+    #[clap(flatten)]
+    connection: ConnectionArgs,
+}
+
+/// Search the gaps of an already downloaded live stream once more, while its .mpd URL is still
+/// valid, as segments sometimes appear on the CDN late
+#[derive(Parser, Debug)]
+struct Heal {
+    /// Download directory
+    directory: PathBuf,
+
+    /// URL of the stream's .mpd file. Signed URLs expire some time after the stream ends, get a
+    /// current one if healing fails to load it
+    mpd_url: String,
+
+    /// Quality the stream was downloaded at, see download --quality
+    #[clap(long, value_parser = parse_quality, default_value = "best")]
+    quality: Quality,
+
+    /// Video adaptation set the stream was downloaded from, see download --video-set
+    #[clap(long)]
+    video_set: Option<usize>,
+
+    /// Number of times to check in parallel
+    #[clap(short, long, default_value = "10")]
+    parallel_candidates: usize,
+
+    #[clap(flatten)]
+    connection: ConnectionArgs,
+}
+
+/// Show the state saved after downloading a live stream
+#[derive(Parser, Debug)]
+struct DumpState {
+    /// Download directory
+    directory: PathBuf,
+
+    /// Print the state as JSON
+    #[clap(long)]
+    json: bool,
+}
+
+/// Write the segment timeline of a download directory or live manifest as CSV
+#[derive(Parser, Debug)]
+struct DumpTimeline {
+    /// Download directory, or URL of .mpd file
+    source: String,
+
+    /// CSV file to write. If not given, print to stdout
+    #[clap(short, long)]
+    output: Option<PathBuf>,
+
+    #[clap(flatten)]
+    connection: ConnectionArgs,
+}
+
+/// List the video and audio representations of a live stream, grouped by adaptation set
+#[derive(Parser, Debug)]
+struct List {
+    /// URL of .mpd file
+    mpd_url: String,
+
+    #[clap(flatten)]
+    connection: ConnectionArgs,
+}
+
+/// Add, list or cancel streams to be downloaded by the daemon command
+#[derive(Parser, Debug)]
+struct QueueArgs {
+    /// Directory holding the queue
+    #[clap(long, default_value = ".")]
+    queue_dir: PathBuf,
+
+    #[clap(subcommand)]
+    action: QueueAction,
+}
+
+#[derive(Subcommand, Debug)]
+enum QueueAction {
+    /// Add streams to the queue
+    Add {
+        /// URLs of .mpd files
+        #[clap(required = true)]
+        urls: Vec<String>,
+    },
+    /// List queued streams
+    List,
+    /// Cancel pending or running streams
+    Cancel {
+        /// IDs of the streams to cancel
+        #[clap(required = true)]
+        ids: Vec<usize>,
+    },
+}
+
+/// Download queued streams until stopped with Ctrl-C, picking up newly queued streams.
+/// Streams interrupted by stopping are downloaded again when the daemon is restarted
+#[derive(Parser, Debug)]
+struct Daemon {
+    /// Directory holding the queue
+    #[clap(long, default_value = ".")]
+    queue_dir: PathBuf,
+
+    /// Directory to download streams into, each in a subdirectory named after its stream ID
+    #[clap(short, long)]
+    output: Option<PathBuf>,
+
+    /// Name each stream's subdirectory by this template instead of its stream ID
+    /// (e.g. "{date}_{time}_{stream_id}"). See download --output-template
+    #[clap(long)]
+    output_template: Option<String>,
+
+    /// Number of streams to download at the same time
+    #[clap(short, long, default_value = "2")]
+    concurrency: usize,
+
+    /// Don't merge streams into one video file after download
+    #[clap(short, long)]
+    no_merge: bool,
+
+    /// Pause requesting new segments of all downloads while this file exists, and resume once it
+    /// is removed. On Unix, SIGUSR1 also pauses and SIGUSR2 resumes
+    #[clap(long)]
+    pause_file: Option<PathBuf>,
+
+    /// Stop each download once this much of its stream (e.g. "1h") was downloaded live
+    #[clap(long, value_parser = humantime::parse_duration)]
+    max_duration: Option<Duration>,
+
+    /// Stop each download once this many bytes of its segments (e.g. "5GiB") were downloaded
+    #[clap(long, value_parser = parse_byte_size)]
+    max_size: Option<u64>,
+
+    #[clap(flatten)]
+    connection: ConnectionArgs,
+}
+
+/// Wait for a user to go live, download each live stream as it begins, and wait again
+#[derive(Parser, Debug)]
+struct Monitor {
+    /// Instagram username, or profile or live URL, to monitor
+    username: String,
+
+    /// Session cookies of a logged-in account, used to look up the user's live stream.
+    /// See download --cookie
+    #[clap(long)]
+    cookie: Option<String>,
+
+    /// Time between lookups while the user isn't live (e.g. "2m")
+    #[clap(long, value_parser = humantime::parse_duration, default_value = "1m")]
+    interval: Duration,
+
+    /// Directory to download streams into, each in a subdirectory named after its stream ID
+    #[clap(short, long)]
+    output: Option<PathBuf>,
+
+    /// Name each stream's subdirectory by this template instead of its stream ID
+    /// (e.g. "{date}_{time}_{stream_id}"). See download --output-template
+    #[clap(long)]
+    output_template: Option<String>,
+
+    /// Quality to download, see download --quality
+    #[clap(long, value_parser = parse_quality, default_value = "best")]
+    quality: Quality,
+
+    /// Don't merge streams into one video file after download
+    #[clap(short, long)]
+    no_merge: bool,
+
+    /// Shell command to run, or http(s) URL to POST to, once downloading begins. The event is
+    /// passed as JSON on stdin or in the request body
+    #[clap(long)]
+    on_start: Option<Hook>,
+
+    /// Shell command to run, or http(s) URL to POST to, once downloading finished or failed
+    #[clap(long)]
+    on_end: Option<Hook>,
+
+    /// Shell command to run, or http(s) URL to POST to, once the merged video is written
+    #[clap(long)]
+    on_merge_complete: Option<Hook>,
+
+    #[clap(flatten)]
+    connection: ConnectionArgs,
 }
 
 /// Merge an already downloaded live stream into one file
@@ -47,42 +721,1080 @@ struct Download {
 struct Merge {
     /// Directory to merge
     directory: PathBuf,
+
+    /// Number of threads ffmpeg may use
+    #[clap(long)]
+    merge_threads: Option<usize>,
+
+    /// Merge even if segments are missing, leaving discontinuities in the video
+    #[clap(short, long, alias = "force-merge")]
+    force: bool,
+
+    /// Title to name the merged video after and embed in it
+    #[clap(long)]
+    title: Option<String>,
+
+    /// Merge into a fragmented mp4 with fragments aligned to the original segments, for
+    /// re-serving over DASH or HLS
+    #[clap(long)]
+    fragmented: bool,
+
+    /// Set the merged video's modification time to the stream's start time
+    #[clap(long)]
+    set_mtime: bool,
+
+    /// Mark a chapter this often (e.g. "10m") in the merged video, titled with the wall-clock
+    /// time it starts at
+    #[clap(long, value_parser = humantime::parse_duration)]
+    chapter_interval: Option<Duration>,
+
+    /// Merge in memory instead of through temporary files if the segments total at most this
+    /// many bytes
+    #[clap(long)]
+    in_memory_merge_limit: Option<u64>,
+
+    /// Show ffmpeg's output at this log level (e.g. "warning")
+    #[clap(long)]
+    ffmpeg_loglevel: Option<String>,
+
+    /// Program to merge with. "builtin" writes a fragmented mp4 without ffmpeg, "auto" uses it
+    /// if ffmpeg isn't installed
+    #[clap(long, value_enum, default_value = "auto")]
+    muxer: MergeMuxer,
+
+    /// Container to merge into. mkv tolerates the timestamp jumps of missing segments better
+    #[clap(long, value_enum, default_value = "mp4")]
+    format: MergeFormat,
+
+    /// Path of the ffmpeg executable, for installs outside of PATH. ffprobe is run from the same
+    /// directory
+    #[clap(long)]
+    ffmpeg_path: Option<PathBuf>,
+
+    /// Extra arguments passed to ffmpeg before the output file when merging, split like a shell
+    /// would (e.g. "-preset slow -metadata title='My stream'")
+    #[clap(long, value_parser = parse_shell_words, default_value = "", allow_hyphen_values = true)]
+    ffmpeg_args: ::std::vec::Vec<String>,
+
+    #[clap(flatten)]
+    recode: RecodeArgs,
+}
+
+/// Options for transcoding when merging
+#[derive(ClapArgs, Debug)]
+struct RecodeArgs {
+    /// Re-encode when merging instead of copying the downloaded streams, for playback
+    /// compatibility. CPU-intensive and much slower than the default remux
+    #[clap(long, alias = "two-pass-merge")]
+    recode: bool,
+
+    /// ffmpeg video encoder used with --recode
+    #[clap(long, default_value = "libx264")]
+    recode_video_codec: String,
+
+    /// ffmpeg audio encoder used with --recode
+    #[clap(long, default_value = "aac")]
+    recode_audio_codec: String,
+
+    /// Constant rate factor used with --recode, ignored if --recode-video-bitrate is set
+    #[clap(long, default_value = "23")]
+    recode_crf: u8,
+
+    /// Target video bitrate used with --recode (e.g. "4M")
+    #[clap(long)]
+    recode_video_bitrate: Option<String>,
+
+    /// Target audio bitrate used with --recode (e.g. "128k")
+    #[clap(long)]
+    recode_audio_bitrate: Option<String>,
+
+    /// Audio sample rate of the merged video (e.g. "48000"). Re-encodes the audio, with aac
+    /// unless --recode is set
+    #[clap(long)]
+    audio_rate: Option<u32>,
+
+    /// Number of audio channels of the merged video (e.g. "2"). Re-encodes the audio, with aac
+    /// unless --recode is set
+    #[clap(long)]
+    audio_channels: Option<u32>,
+}
+
+impl RecodeArgs {
+    fn config(&self) -> Option<Recode> {
+        self.recode.then(|| Recode {
+            video_codec: self.recode_video_codec.clone(),
+            audio_codec: self.recode_audio_codec.clone(),
+            crf: Some(self.recode_crf),
+            video_bitrate: self.recode_video_bitrate.clone(),
+            audio_bitrate: self.recode_audio_bitrate.clone(),
+        })
+    }
+}
+
+/// Options for the HTTP requests of every command reaching Instagram
+#[derive(ClapArgs, Debug)]
+struct ConnectionArgs {
+    /// Resolve a host name to a fixed IP address instead of using DNS (e.g.
+    /// "scontent.cdninstagram.com:157.240.1.1"). Can be given multiple times
+    #[clap(long, value_parser = parse_resolve)]
+    resolve: Vec<(String, IpAddr)>,
+
+    /// Extra HTTP header sent with every request (e.g. "Cookie: sessionid=..."). Can be given
+    /// multiple times
+    #[clap(short = 'H', long = "header", value_name = "HEADER", value_parser = parse_header)]
+    headers: Vec<(String, String)>,
+
+    /// User agent sent with every request
+    #[clap(long)]
+    user_agent: Option<String>,
+
+    /// HTTP, HTTPS or SOCKS5 proxy to send every request through (e.g.
+    /// "socks5h://127.0.0.1:1080"). Defaults to the HTTPS_PROXY or ALL_PROXY environment variable
+    #[clap(long)]
+    proxy: Option<String>,
+}
+
+impl ConnectionArgs {
+    fn config(&self) -> DownloadConfig {
+        DownloadConfig {
+            resolve: self.resolve.clone(),
+            headers: self.headers.clone(),
+            user_agent: self.user_agent.clone(),
+            proxy: self.proxy.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Parse a `host:ip` mapping for --resolve
+fn parse_resolve(s: &str) -> Result<(String, IpAddr), String> {
+    let (host, ip) = s
+        .split_once(':')
+        .ok_or_else(|| format!("expected HOST:IP, got {s:?}"))?;
+    if host.is_empty() {
+        return Err(format!("missing host in {s:?}"));
+    }
+    let ip = ip.parse().map_err(|e| format!("invalid IP address {ip:?}: {e}"))?;
+    Ok((host.to_owned(), ip))
+}
+
+/// Parse bits per second with an optional k, M or G suffix
+fn parse_bits_per_second(value: &str) -> Result<usize, String> {
+    let (number, multiplier) = match value.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&value[..i], 1e3),
+        Some((i, 'M')) => (&value[..i], 1e6),
+        Some((i, 'G')) => (&value[..i], 1e9),
+        _ => (value, 1.0),
+    };
+    let number: f64 = number
+        .parse()
+        .map_err(|e| format!("invalid bandwidth {value:?}: {e}"))?;
+    Ok((number * multiplier) as usize)
+}
+
+/// Parse a --quality preset, maximum height or maximum bandwidth
+fn parse_quality(s: &str) -> Result<Quality, String> {
+    let lowercase = s.to_ascii_lowercase();
+    match lowercase.as_str() {
+        "best" => return Ok(Quality::Best),
+        "worst" => return Ok(Quality::Worst),
+        "audio-only" => return Ok(Quality::AudioOnly),
+        _ => (),
+    }
+    if let Some(Ok(height)) = lowercase.strip_suffix('p').map(str::parse) {
+        return Ok(Quality::MaxHeight(height));
+    }
+    parse_bits_per_second(s).map(Quality::MaxBandwidth).map_err(|_| {
+        format!("expected best, worst, audio-only, a height like 720p or a bandwidth like 2M, got {s:?}")
+    })
+}
+
+/// Parse a `MIN-MAX` range of bits per second for --bandwidth, either end may be left out.
+/// Values may have a k, M or G suffix.
+fn parse_bandwidth_range(s: &str) -> Result<BandwidthRange, String> {
+    let parse = |value: &str| -> Result<Option<usize>, String> {
+        if value.is_empty() {
+            return Ok(None);
+        }
+        parse_bits_per_second(value).map(Some)
+    };
+    let (min, max) = s
+        .split_once('-')
+        .ok_or_else(|| format!("expected MIN-MAX, got {s:?}"))?;
+    let range = BandwidthRange {
+        min: parse(min.trim())?,
+        max: parse(max.trim())?,
+    };
+    if range.min.zip(range.max).is_some_and(|(min, max)| min > max) {
+        return Err(format!("minimum is above maximum in {s:?}"));
+    }
+    Ok(range)
+}
+
+/// Split arguments like a POSIX shell, keeping quoted spaces
+fn parse_shell_words(s: &str) -> Result<Vec<String>, String> {
+    shlex::split(s).ok_or_else(|| format!("unmatched quote in {s:?}"))
+}
+
+/// Parse a number of bytes with an optional KiB, MiB or GiB (or kB, MB, GB) suffix
+fn parse_byte_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let multiplier = match unit.trim() {
+        "" | "B" => 1.0,
+        "KiB" => 1024.0,
+        "MiB" => 1024.0 * 1024.0,
+        "GiB" => 1024.0 * 1024.0 * 1024.0,
+        "kB" | "KB" => 1e3,
+        "MB" => 1e6,
+        "GB" => 1e9,
+        unit => return Err(format!("unknown unit {unit:?}, expected B, KiB, MiB or GiB")),
+    };
+    let number: f64 = number
+        .parse()
+        .map_err(|e| format!("invalid size {s:?}: {e}"))?;
+    Ok((number * multiplier) as u64)
+}
+
+/// Parse an RFC 3339 time for --stop-at
+fn parse_stop_at(s: &str) -> Result<DateTime<Utc>, String> {
+    DateTime::parse_from_rfc3339(s)
+        .map(|time| time.with_timezone(&Utc))
+        .map_err(|e| format!("invalid time {s:?}, expected e.g. 2024-01-31T22:00:00Z: {e}"))
+}
+
+/// Parse a `NAME: VALUE` header for --header
+fn parse_header(s: &str) -> Result<(String, String), String> {
+    let (name, value) = s
+        .split_once(':')
+        .ok_or_else(|| format!("expected NAME:VALUE, got {s:?}"))?;
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(format!("missing header name in {s:?}"));
+    }
+    Ok((name.to_owned(), value.trim().to_owned()))
+}
+
+/// Estimated download size above which confirmation is asked for
+const LARGE_DOWNLOAD_SIZE: u64 = 10 * 1024 * 1024 * 1024;
+
+/// Fraction of --expect-duration below which a download is considered too short
+const EXPECT_DURATION_TOLERANCE: f64 = 0.8;
+
+/// Segment size up to which previews are merged in memory
+const PREVIEW_IN_MEMORY_LIMIT: u64 = 64 * 1024 * 1024;
+
+/// Exit codes, see README
+mod exit_code {
+    pub const SUCCESS: i32 = 0;
+    pub const ERROR: i32 = 1;
+    pub const PARTIAL: i32 = 3;
+    pub const FORBIDDEN: i32 = 4;
+    pub const NETWORK: i32 = 5;
+    pub const MERGE: i32 = 6;
+    pub const MANIFEST: i32 = 7;
+    pub const GAPS: i32 = 8;
+    pub const SHORT: i32 = 9;
+    pub const INTERRUPTED: i32 = 130;
 }
 
 #[tokio::main]
 async fn main() {
     let args = Args::parse();
-    if let Err(e) =  run(args).await {
-        eprintln!("{e}");
-        process::exit(1);
+    let mask_urls = matches!(&args.command, Command::Download(d) if d.mask_urls);
+    let progress_bars = MultiProgress::new();
+    if let Err(e) = init_logging(&args, &progress_bars, mask_urls) {
+        eprintln!("Failed to open log file: {e}");
+        process::exit(exit_code::ERROR);
+    }
+    match run(args, progress_bars).await {
+        Ok(code) => process::exit(code),
+        Err(e) if mask_urls => {
+            eprintln!("{}", redact_urls(&e.to_string()));
+            process::exit(error_exit_code(&e));
+        }
+        Err(e) => {
+            eprintln!("{e}");
+            process::exit(error_exit_code(&e));
+        }
+    }
+}
+
+fn error_exit_code(e: &Error) -> i32 {
+    if let Some(e) = e.downcast_ref::<IgLiveError>() {
+        match e {
+            IgLiveError::StatusError(401 | 403, _) | IgLiveError::UnexpectedContentType(_) => {
+                exit_code::FORBIDDEN
+            }
+            IgLiveError::StatusError(500.., _) | IgLiveError::NoSegmentsAccepted => {
+                exit_code::NETWORK
+            }
+            IgLiveError::FfmpegFail
+            | IgLiveError::RequiresFfmpeg(_)
+            | IgLiveError::InvalidMp4(_) => exit_code::MERGE,
+            IgLiveError::MissingSegments(_) => exit_code::GAPS,
+            IgLiveError::ShortCapture(..) => exit_code::SHORT,
+            IgLiveError::EmptyManifest
+            | IgLiveError::NoRepresentations
+            | IgLiveError::NoMedia
+            | IgLiveError::AudioOnly
+            | IgLiveError::VideoOnly
+            | IgLiveError::MissingSegmentInfo
+            | IgLiveError::NoSegmentsYet
+            | IgLiveError::NotLive(_)
+            | IgLiveError::MissingFrameRate
+            | IgLiveError::NoSuchVideoSet(_)
+            | IgLiveError::NoBandwidthInRange(_)
+            | IgLiveError::NoSuchQuality(_)
+            | IgLiveError::ManifestExpired(_)
+            | IgLiveError::IncompatibleCodec(..)
+            | IgLiveError::MismatchedTimescales(..) => exit_code::MANIFEST,
+            _ => exit_code::ERROR,
+        }
+    } else if e.is::<reqwest::Error>() {
+        exit_code::NETWORK
+    } else if e.is::<quick_xml::DeError>() {
+        exit_code::MANIFEST
+    } else {
+        exit_code::ERROR
+    }
+}
+
+/// Log to stderr above the progress bars at the level set by --verbose and --quiet, and to
+/// --log-file if given. Only this crate's messages are logged, not those of its dependencies.
+fn init_logging(args: &Args, progress_bars: &MultiProgress, mask_urls: bool) -> Result<()> {
+    let level = match args.verbose {
+        _ if args.quiet => LevelFilter::WARN,
+        0 => LevelFilter::INFO,
+        1 => LevelFilter::DEBUG,
+        _ => LevelFilter::TRACE,
+    };
+    let stderr = fmt::layer()
+        .event_format(PlainFormat)
+        .with_writer(LogWriter {
+            file: None,
+            progress_bars: progress_bars.clone(),
+            mask_urls,
+        })
+        .with_filter(Targets::new().with_target(CRATE_TARGET, level));
+    let file = match &args.log_file {
+        Some(path) => {
+            let file = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+            let level = level.max(LevelFilter::DEBUG);
+            let layer = fmt::layer()
+                .with_ansi(false)
+                .with_writer(LogWriter {
+                    file: Some(Arc::new(Mutex::new(file))),
+                    progress_bars: progress_bars.clone(),
+                    mask_urls,
+                })
+                .with_filter(Targets::new().with_target(CRATE_TARGET, level));
+            Some(layer)
+        }
+        None => None,
+    };
+    tracing_subscriber::registry().with(stderr).with(file).init();
+    Ok(())
+}
+
+/// Module path prefix of both the library and the binary
+const CRATE_TARGET: &str = "download_iglive";
+
+/// Writes log lines to a file, or to stderr above the progress bars, redacting URLs if asked to
+#[derive(Clone)]
+struct LogWriter {
+    file: Option<Arc<Mutex<std::fs::File>>>,
+    progress_bars: MultiProgress,
+    mask_urls: bool,
+}
+
+impl io::Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let text = String::from_utf8_lossy(buf);
+        let text = if self.mask_urls {
+            redact_urls(&text)
+        } else {
+            text.into_owned()
+        };
+        match &self.file {
+            Some(file) => file.lock().unwrap().write_all(text.as_bytes())?,
+            None => self
+                .progress_bars
+                .suspend(|| io::stderr().write_all(text.as_bytes()))?,
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &self.file {
+            Some(file) => file.lock().unwrap().flush(),
+            None => io::stderr().flush(),
+        }
+    }
+}
+
+impl<'a> MakeWriter<'a> for LogWriter {
+    type Writer = Self;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.clone()
+    }
+}
+
+/// Formats messages as printed before logging went through `tracing`: info as is, and other
+/// levels prefixed with the level
+struct PlainFormat;
+
+impl<S, N> FormatEvent<S, N> for PlainFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: format::Writer<'_>,
+        event: &Event<'_>,
+    ) -> std::fmt::Result {
+        match *event.metadata().level() {
+            Level::INFO => (),
+            Level::WARN => write!(writer, "WARNING: ")?,
+            level => write!(writer, "{level}: ")?,
+        }
+        ctx.field_format().format_fields(writer.by_ref(), event)?;
+        writeln!(writer)
+    }
+}
+
+/// Cancel `cancel` on Ctrl-C or, on Unix, SIGTERM, letting in-flight downloads finish.
+/// A second signal exits immediately.
+fn handle_stop_signals(cancel: CancellationToken) {
+    tokio::spawn(async move {
+        for _ in 0..2 {
+            #[cfg(unix)]
+            {
+                use tokio::signal::unix::{signal, SignalKind};
+
+                let Ok(mut terminate) = signal(SignalKind::terminate()) else {
+                    warn!("Can't listen for SIGTERM");
+                    return;
+                };
+                tokio::select! {
+                    result = tokio::signal::ctrl_c() => {
+                        if result.is_err() {
+                            return;
+                        }
+                    }
+                    _ = terminate.recv() => (),
+                }
+            }
+            #[cfg(not(unix))]
+            if tokio::signal::ctrl_c().await.is_err() {
+                return;
+            }
+
+            if cancel.is_cancelled() {
+                process::exit(exit_code::INTERRUPTED);
+            }
+            info!("Stopping after in-flight downloads finish, interrupt again to exit now");
+            cancel.cancel();
+        }
+    });
+}
+
+/// Pause on SIGUSR1 and resume on SIGUSR2
+#[cfg(unix)]
+fn handle_pause_signals(pause: PauseSwitch) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    for (kind, paused) in [
+        (SignalKind::user_defined1(), true),
+        (SignalKind::user_defined2(), false),
+    ] {
+        let Ok(mut signals) = signal(kind) else {
+            warn!("Can't listen for pause signals");
+            return;
+        };
+        let pause = pause.clone();
+        tokio::spawn(async move {
+            while signals.recv().await.is_some() {
+                if paused {
+                    info!("Pausing, send SIGUSR2 to resume");
+                    pause.pause();
+                } else {
+                    info!("Resuming");
+                    pause.resume();
+                }
+            }
+        });
+    }
+}
+
+#[cfg(not(unix))]
+fn handle_pause_signals(_pause: PauseSwitch) {}
+
+/// Print the time spent in each phase of a download and merge
+fn print_timings(timings: &Timings, merge: Option<Duration>) {
+    let format = |d: Duration| humantime::format_duration(Duration::from_secs(d.as_secs()));
+    info!("Time spent:");
+    info!("  Manifest:   {}", format(timings.manifest));
+    if let Some(live) = timings.live {
+        info!("  Live:       {}", format(live));
+    }
+    for (media_type, name) in [(MediaType::Video, "Past video"), (MediaType::Audio, "Past audio")] {
+        if let Some(&time) = timings.backwards.get(&media_type) {
+            info!("  {name}: {}", format(time));
+        }
+    }
+    if let Some(merge) = merge {
+        info!("  Merge:      {}", format(merge));
+    }
+}
+
+/// Print the time missing from each track of a download
+fn print_gaps(report: &GapReport) {
+    let format = |secs: f64| humantime::format_duration(Duration::from_millis((secs * 1e3) as u64));
+    for track in &report.tracks {
+        let complete = match track.total_secs {
+            total if total > 0.0 => 100.0 * (1.0 - track.missing_secs / total),
+            _ => 100.0,
+        };
+        info!(
+            "{:?}: {} gap(s), {} missing ({complete:.1}% complete)",
+            track.media_type,
+            track.gaps.len(),
+            format(track.missing_secs),
+        );
+        for gap in &track.gaps {
+            info!(
+                "  {} missing at {}",
+                format(gap.duration_secs),
+                format(gap.offset_secs)
+            );
+        }
+    }
+}
+
+/// Print the video and audio representations of a manifest by adaptation set
+fn print_representations(manifest: &Mpd) {
+    let best_reps = manifest.select_tracks(&MediaSelection::default()).unwrap_or_default();
+    for media_type in [MediaType::Video, MediaType::Audio] {
+        let mut set = None;
+        for rep in manifest.representations() {
+            if rep.media_type() != media_type {
+                continue;
+            }
+            if set != Some(rep.adaptation_set) {
+                set = Some(rep.adaptation_set);
+                println!("{media_type:?} set {}:", rep.adaptation_set);
+            }
+
+            let mut line = String::from(" ");
+            if let (Some(width), Some(height)) = (rep.width, rep.height) {
+                line += &format!(" {width}x{height}");
+            }
+            if let Some(frame_rate) = rep.frame_rate {
+                line += &format!(" {frame_rate}fps");
+            }
+            line += &format!(" {}/s", HumanBytes(rep.bandwidth as u64 / 8));
+            if let Some(codecs) = &rep.codecs {
+                line += &format!(" {codecs}");
+            }
+            let best = best_reps.iter().any(|&best| std::ptr::eq(best, rep));
+            if best {
+                line += " (default)";
+            }
+            println!("{line}");
+        }
+    }
+}
+
+/// Print a human readable summary of a saved download state
+fn print_state(state: &StateSnapshot) {
+    println!("Stream IDs: {}", state.ids.join(", "));
+    println!("Retries: {}", state.retries);
+    for media_type in [MediaType::Video, MediaType::Audio] {
+        println!("{media_type:?}:");
+        let init = if state.downloaded_init.contains(&media_type) {
+            "present"
+        } else {
+            "missing"
+        };
+        println!("  Init segment: {init}");
+        // Live and past segments
+        let segs = state.segment_times(&media_type);
+        match (segs.first(), segs.last()) {
+            (Some(first), Some(last)) => {
+                println!("  Segments: {} (t={first} to t={last})", segs.len())
+            }
+            _ => println!("  Segments: 0"),
+        }
+        if let Some(pts) = state.back_pts.get(&media_type) {
+            println!("  Earliest PTS: {pts}");
+        }
+        if let Some(deltas) = state.deltas.get(&media_type) {
+            let mut deltas: Vec<_> = deltas.iter().collect();
+            deltas.sort_by(|(_, a), (_, b)| b.cmp(a));
+            let top: Vec<_> = deltas
+                .iter()
+                .take(10)
+                .map(|(delta, count)| format!("{delta} ({count})"))
+                .collect();
+            println!("  Top deltas: {}", top.join(", "));
+        }
+    }
+}
+
+/// Ask whether to continue with a large download.
+/// Only warns if not running interactively.
+async fn confirm_large_download() -> Result<bool> {
+    if !io::stdin().is_terminal() {
+        warn!("Download will be very large");
+        return Ok(true);
+    }
+
+    eprint!("Download will be very large, continue? [y/N] ");
+    let answer = tokio::task::spawn_blocking(|| {
+        let mut answer = String::new();
+        io::stdin().read_line(&mut answer).map(|_| answer)
+    })
+    .await??;
+    Ok(answer.trim().eq_ignore_ascii_case("y"))
+}
+
+/// Exit code for a successful merge
+fn merge_exit_code(missing_segments: usize) -> i32 {
+    if missing_segments > 0 {
+        exit_code::PARTIAL
+    } else {
+        exit_code::SUCCESS
+    }
+}
+
+/// Download a stream, then merge and finalize it as configured by `d`
+async fn download_stream(
+    d: &Download,
+    mpd_url: reqwest::Url,
+    manifest: Option<Mpd>,
+    config: DownloadConfig,
+    interrupted: &CancellationToken,
+) -> Result<i32> {
+    let download_report = match manifest {
+        Some(manifest) => download_with_manifest(mpd_url, manifest, config).await?,
+        None => download(mpd_url, config).await?,
+    };
+    let output_dir = download_report.path.clone();
+
+    // Check for a download that stopped early, unless the captured duration is unknown
+    let expected = d.expect_duration.zip(download_report.duration);
+    let short_capture = expected.and_then(|(expected, captured)| {
+        let short =
+            captured.as_secs_f64() < expected.as_secs_f64() * EXPECT_DURATION_TOLERANCE;
+        let format = |d: Duration| {
+            humantime::format_duration(Duration::from_secs(d.as_secs())).to_string()
+        };
+        short.then(|| IgLiveError::ShortCapture(format(captured), format(expected)))
+    });
+    if let Some(e) = short_capture.as_ref().filter(|_| !d.strict) {
+        warn!("{e}");
+    }
+
+    let final_dir = complete_dir_path(&output_dir);
+
+    // Merge
+    let mut merged = None;
+    let skip_merge = d.no_merge_on_interrupt && interrupted.is_cancelled();
+    if !d.no_merge && !skip_merge {
+        let merge_config = MergeConfig {
+            threads: d.merge_threads,
+            name: final_dir
+                .file_name()
+                .map(|n| n.to_string_lossy().to_string()),
+            title: d.title.clone(),
+            force: true,
+            format: d.format.clone().into(),
+            fragmented: d.fragmented,
+            set_mtime: d.set_mtime,
+            chapter_interval: d.chapter_interval,
+            recode: d.recode.config(),
+            audio_rate: d.recode.audio_rate,
+            audio_channels: d.recode.audio_channels,
+            in_memory_limit: d.in_memory_merge_limit,
+            ffmpeg_loglevel: d.ffmpeg_loglevel.clone(),
+            muxer: d.muxer.clone().into(),
+            ffmpeg_path: d.ffmpeg_path.clone(),
+            ffmpeg_args: d.ffmpeg_args.clone(),
+            on_complete: d.on_merge_complete.clone(),
+        };
+        merged = Some(merge(&output_dir, merge_config).await?);
+    }
+    print_timings(&download_report.timings, merged.as_ref().map(|r| r.duration));
+    print_gaps(&download_report.gaps);
+    for (media_type, e) in &download_report.backwards_errors {
+        warn!("Searching past {media_type:?} segments failed: {e}");
+    }
+
+    // Finalize output
+    if d.rename_on_complete {
+        tokio::fs::rename(&output_dir, &final_dir).await?;
+    }
+    if let Some(report) = &merged {
+        if d.flatten_output {
+            let file_name = report.path.file_name().unwrap();
+            flatten_output(final_dir.join(file_name), &final_dir, d.keep_segments)?;
+        }
+    }
+
+    // Fail only after merging what was downloaded
+    if let Some(e) = short_capture.filter(|_| d.strict) {
+        return Err(e.into());
     }
+    if let Some(report) = merged {
+        return Ok(merge_exit_code(report.missing_segments));
+    }
+    Ok(exit_code::SUCCESS)
+}
+
+/// Print the error of one of several streams downloaded at once, returning its exit code
+fn print_stream_error(label: &str, e: Error, mask_urls: bool) -> i32 {
+    let message = e.to_string();
+    let message = if mask_urls { redact_urls(&message) } else { message };
+    eprintln!("{label}: {message}");
+    error_exit_code(&e)
 }
 
-async fn run(args: Args) -> Result<()> {
+async fn run(args: Args, progress_bars: MultiProgress) -> Result<i32> {
     match args.command {
         Command::Download(d) => {
             // Config
-            let segments = if d.live_only {
-                DownloadSegments::LIVE
-            } else {
-                DownloadSegments::all()
+            let segments = match d.backwards {
+                _ if d.live_only => DownloadSegments::LIVE,
+                Backwards::All => DownloadSegments::all(),
+                Backwards::Video => DownloadSegments::LIVE | DownloadSegments::PAST_VIDEO,
+                Backwards::Audio => DownloadSegments::LIVE | DownloadSegments::PAST_AUDIO,
+                Backwards::None => DownloadSegments::LIVE,
             };
+            // Stopping early with a signal also stops the download, but not the other way around
+            let interrupted = CancellationToken::new();
+            handle_stop_signals(interrupted.clone());
+            let cancel = interrupted.child_token();
+            if let Some(max_runtime) = d.max_runtime {
+                let cancel = cancel.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep(max_runtime).await;
+                    cancel.cancel();
+                });
+            }
+            let pause = PauseSwitch::new();
+            handle_pause_signals(pause.clone());
+            let mut inputs = d.mpd_urls.clone();
+            if let Some(batch_file) = &d.batch_file {
+                let list = tokio::fs::read_to_string(batch_file).await?;
+                inputs.extend(
+                    list.lines()
+                        .map(str::trim)
+                        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                        .map(str::to_owned),
+                );
+            }
+            let multiple = inputs.len() > 1;
+            if multiple && (d.pipe_video.is_some() || d.pipe_audio.is_some()) {
+                return Err(Error::msg("--pipe-video and --pipe-audio take a single stream"));
+            }
             let config = DownloadConfig {
-                dir: d.output,
-                segments: segments,
+                dir: d.output.clone(),
+                stream_subdir: d.flatten_output || multiple,
+                output_template: d.output_template.clone(),
+                username: None,
+                cookie: d.cookie.clone(),
+                refresh_command: d.refresh_command.clone(),
+                mark_incomplete: d.rename_on_complete,
+                resolve: d.connection.resolve.clone(),
+                retries: d.retries,
+                retry_delay: d.retry_delay,
+                // Shared by all streams, so they're limited together
+                rate_limiter: d.rate_limit.filter(|&limit| limit > 0).map(|limit| {
+                    Arc::new(RateLimiter::new(limit))
+                }),
+                headers: d.connection.headers.clone(),
+                user_agent: d.connection.user_agent.clone(),
+                proxy: d.connection.proxy.clone(),
+                http3: d.http3,
+                segments,
+                frame_rate: d.select_by_framerate,
+                quality: d.quality,
+                video_set: d.video_set,
+                bandwidth: d.bandwidth,
+                all_audio: d.all_audio,
                 parallel_candidates: d.parallel_candidates,
+                limit_candidates_per_delta: d.limit_candidates_per_delta,
+                prioritize_live: !d.no_prioritize_live,
+                reserved_live_downloads: d.reserved_live_downloads,
+                sequential: d.sequential,
+                backwards_windows: d.backwards_windows,
+                backwards_concurrency: d.backwards_concurrency,
+                search_range: d.search_range,
+                assumed_missing_delta: d.assumed_missing_delta,
+                max_consecutive_skips: d.max_consecutive_skips,
+                fail_fast: d.fail_fast,
+                segment_concurrency_ramp: d.segment_concurrency_ramp,
+                deltas_seed: d.deltas_seed,
+                warm_up_segments: d.warm_up_segments,
+                resume_backwards_from: d.resume_backwards_from.clone(),
+                max_pts_early_retries: d.max_pts_early_retries,
+                skip_covered: d.skip_covered,
+                reprobe_gaps: d.reprobe_gaps,
+                heal_after: d.heal_after,
+                dedupe_on_resume: d.dedupe_on_resume,
+                resume: d.resume,
+                checkpoint_interval: (!d.checkpoint_interval.is_zero())
+                    .then_some(d.checkpoint_interval),
+                probe_cache: d.probe_cache,
+                id_change_policy: d.on_id_change,
+                post_finish_grace: d.post_finish_grace,
+                max_duration: d.max_duration,
+                max_size: d.max_size,
+                stop_at: d.stop_at,
+                manifest_log: d.manifest_log,
+                empty_manifest_retries: d.empty_manifest_retries,
+                max_manifest_failures: d.max_manifest_failures,
+                progress: d.progress,
+                cancel,
+                pause,
+                pause_file: d.pause_file.clone(),
+                init_in_memory: true,
+                show_retries: d.segment_retries_visible,
+                mask_urls: d.mask_urls,
+                heartbeat: d.heartbeat,
+                check_compatibility: d.check_compatibility,
+                ffmpeg_path: d.ffmpeg_path.clone(),
+                live_pipes: d.pipe_video.clone().zip(d.pipe_audio.clone()),
+                thumbnails: d.thumbnails,
+                ffconcat: d.ffconcat,
+                integrity: d.integrity,
+                min_segment_size: d.min_segment_size,
+                compress_segments: d.compress_segments,
+                compat_filenames: d.compat_filenames,
+                progress_bars: Some(progress_bars),
+                label: None,
+                on_start: d.on_start.clone(),
+                on_end: d.on_end.clone(),
             };
 
-            // Download live stream
-            let output_dir = download(&d.mpd_url, config).await?;
+            // Estimate size
+            let client = build_client(&config)?;
+            let mut streams = vec![];
+            let mut exit_codes = vec![];
+            for (i, input) in inputs.iter().enumerate() {
+                let label = username_from_input(input).unwrap_or_else(|| format!("#{}", i + 1));
+                let (cookie, user_agent) = (d.cookie.as_deref(), config.user_agent.as_deref());
+                let mpd_url = match resolve_mpd_url(&client, input, cookie, user_agent).await {
+                    Ok(mpd_url) => mpd_url,
+                    Err(e) if multiple => {
+                        exit_codes.push(print_stream_error(&label, e, d.mask_urls));
+                        continue;
+                    }
+                    Err(e) => return Err(e),
+                };
+                let prefix = if multiple { format!("{label}: ") } else { String::new() };
+                let estimated = async {
+                    let manifest = Mpd::download_from_url(&client, mpd_url.clone()).await?;
+                    let size = estimate_size(&manifest, &config, d.duration)?;
+                    Ok::<_, Error>((manifest, size))
+                };
+                // The download fetches the manifest again and fails with its own error instead
+                let manifest = match estimated.await {
+                    Ok((manifest, size)) => {
+                        info!("{prefix}Estimated download size: {}", HumanBytes(size));
+                        let ask = size > LARGE_DOWNLOAD_SIZE && d.max_size.is_none() && !d.yes;
+                        if ask && !confirm_large_download().await? {
+                            continue;
+                        }
+                        Some(manifest)
+                    }
+                    Err(e) => {
+                        let e = e.to_string();
+                        let e = if d.mask_urls { redact_urls(&e) } else { e };
+                        warn!("{prefix}Can't estimate the download size: {e}");
+                        None
+                    }
+                };
+                let config = DownloadConfig {
+                    username: username_from_input(input),
+                    label: multiple.then(|| label.clone()),
+                    // A stream reaching its limits stops only itself
+                    cancel: config.cancel.child_token(),
+                    ..config.clone()
+                };
+                streams.push((label, mpd_url, manifest, config));
+            }
 
-            // Merge
-            if !d.no_merge {
-                merge(output_dir).await?;
+            // Download live streams
+            let downloads = streams.into_iter().map(|(label, mpd_url, manifest, config)| {
+                let download = download_stream(&d, mpd_url, manifest, config, &interrupted);
+                async move { (label, download.await) }
+            });
+            for (label, result) in future::join_all(downloads).await {
+                match result {
+                    Ok(code) => exit_codes.push(code),
+                    Err(e) if multiple => {
+                        exit_codes.push(print_stream_error(&label, e, d.mask_urls))
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            return Ok(exit_codes
+                .into_iter()
+                .find(|&code| code != exit_code::SUCCESS)
+                .unwrap_or(exit_code::SUCCESS));
+        }
+        Command::Merge(m) => {
+            let merge_config = MergeConfig {
+                threads: m.merge_threads,
+                title: m.title,
+                force: m.force,
+                format: m.format.into(),
+                fragmented: m.fragmented,
+                set_mtime: m.set_mtime,
+                chapter_interval: m.chapter_interval,
+                recode: m.recode.config(),
+                audio_rate: m.recode.audio_rate,
+                audio_channels: m.recode.audio_channels,
+                in_memory_limit: m.in_memory_merge_limit,
+                ffmpeg_loglevel: m.ffmpeg_loglevel,
+                muxer: m.muxer.into(),
+                ffmpeg_path: m.ffmpeg_path,
+                ffmpeg_args: m.ffmpeg_args,
+                ..Default::default()
+            };
+            let report = merge(m.directory, merge_config).await?;
+            return Ok(merge_exit_code(report.missing_segments));
+        }
+        Command::Preview(p) => {
+            let config = p.connection.config();
+            let output_dir = download_preview(&p.mpd_url, p.output, p.segments, &config).await?;
+            let merge_config = MergeConfig {
+                force: true,
+                in_memory_limit: Some(PREVIEW_IN_MEMORY_LIMIT),
+                ..Default::default()
+            };
+            let report = merge(output_dir, merge_config).await?;
+            println!("{}", report.path.display());
+        }
+        Command::DumpTimeline(t) => {
+            let entries = if t.source.starts_with("http://") || t.source.starts_with("https://") {
+                let client = build_client(&t.connection.config())?;
+                timeline::from_manifest(&client, &t.source).await?
+            } else {
+                timeline::from_dir(&t.source).await?
+            };
+            let csv = timeline::to_csv(&entries);
+            match t.output {
+                Some(path) => tokio::fs::write(path, csv).await?,
+                None => print!("{csv}"),
+            }
+        }
+        Command::Queue(q) => match q.action {
+            QueueAction::Add { urls } => {
+                let ids = Queue::update(&q.queue_dir, |queue| {
+                    urls.into_iter().map(|url| queue.add(url)).collect::<Vec<_>>()
+                })
+                .await?;
+                for id in ids {
+                    println!("Queued {id}");
+                }
+            }
+            QueueAction::List => {
+                for item in Queue::read(&q.queue_dir).await?.items {
+                    let mut line = format!("{}  {:?}  {}", item.id, item.status, item.url);
+                    if let Some(error) = item.error {
+                        line += &format!("  ({error})");
+                    }
+                    println!("{line}");
+                }
+            }
+            QueueAction::Cancel { ids } => {
+                let cancelled = Queue::update(&q.queue_dir, |queue| {
+                    ids.into_iter().filter(|&id| !queue.cancel(id)).collect::<Vec<_>>()
+                })
+                .await?;
+                for id in cancelled {
+                    eprintln!("Stream {id} is not pending or running");
+                }
+            }
+        },
+        Command::Daemon(d) => {
+            let cancel = CancellationToken::new();
+            handle_stop_signals(cancel.clone());
+            let pause = PauseSwitch::new();
+            handle_pause_signals(pause.clone());
+            let config = DownloadConfig {
+                dir: d.output,
+                stream_subdir: true,
+                output_template: d.output_template,
+                progress: ProgressOutput::Log,
+                pause,
+                pause_file: d.pause_file,
+                max_duration: d.max_duration,
+                max_size: d.max_size,
+                ..d.connection.config()
+            };
+            let merge_config = (!d.no_merge).then(|| MergeConfig {
+                force: true,
+                ..Default::default()
+            });
+            run_daemon(&d.queue_dir, config, merge_config, d.concurrency, cancel).await?;
+        }
+        Command::Monitor(m) => {
+            let m = *m;
+            let username = username_from_input(&m.username).ok_or(IgLiveError::InvalidUrl)?;
+            let cancel = CancellationToken::new();
+            handle_stop_signals(cancel.clone());
+            let config = DownloadConfig {
+                dir: m.output,
+                stream_subdir: true,
+                output_template: m.output_template,
+                cookie: m.cookie,
+                quality: m.quality,
+                progress_bars: Some(progress_bars),
+                on_start: m.on_start,
+                on_end: m.on_end,
+                ..m.connection.config()
+            };
+            let merge_config = (!m.no_merge).then(|| MergeConfig {
+                force: true,
+                on_complete: m.on_merge_complete,
+                ..Default::default()
+            });
+            run_monitor(&username, config, merge_config, m.interval, cancel).await?;
+        }
+        Command::List(l) => {
+            let client = build_client(&l.connection.config())?;
+            let manifest = Mpd::download_from_url(&client, &l.mpd_url).await?;
+            print_representations(&manifest);
+        }
+        Command::Heal(h) => {
+            let config = DownloadConfig {
+                quality: h.quality,
+                video_set: h.video_set,
+                parallel_candidates: h.parallel_candidates,
+                ..h.connection.config()
+            };
+            let report = heal(&h.mpd_url, &h.directory, &config).await?;
+            print_gaps(&report);
+        }
+        Command::DumpState(s) => {
+            let state = StateSnapshot::read(&s.directory).await?;
+            if s.json {
+                println!("{}", serde_json::to_string_pretty(&state)?);
+            } else {
+                print_state(&state);
             }
         }
-        Command::Merge(m) => merge(m.directory).await?,
     }
 
-    Ok(())
+    Ok(exit_code::SUCCESS)
 }
@@ -0,0 +1,56 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+use crate::mpd::MediaType;
+
+const MARKER_FILE_NAME: &str = "backwards.json";
+
+/// Position of the past segment search of one media type
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BackwardsPosition {
+    /// Earliest segment time found so far
+    pub latest_t: isize,
+
+    /// Time below which no segments are searched
+    pub lower_bound: isize,
+
+    /// Most common segment time deltas, most common first
+    pub deltas: Vec<isize>,
+}
+
+/// Progress of past segment searches, written alongside segments so a separate run can continue
+/// them
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct BackwardsMarker {
+    /// Search position by media type
+    pub positions: HashMap<MediaType, BackwardsPosition>,
+}
+
+impl BackwardsMarker {
+    pub async fn read(dir: impl AsRef<Path>) -> Result<Self> {
+        let data = fs::read(dir.as_ref().join(MARKER_FILE_NAME)).await?;
+        Ok(serde_json::from_slice(&data)?)
+    }
+
+    /// Write the marker to `dir`, replacing the previous marker file at once, so concurrent
+    /// writes by the searches of each media type can't interleave.
+    pub async fn write(&self, dir: impl AsRef<Path>) -> Result<()> {
+        static WRITES: AtomicUsize = AtomicUsize::new(0);
+        let data = serde_json::to_vec_pretty(self)?;
+        let tmp_name = format!(
+            "{MARKER_FILE_NAME}.{}.{}.tmp",
+            process::id(),
+            WRITES.fetch_add(1, Ordering::Relaxed)
+        );
+        let tmp_path = dir.as_ref().join(tmp_name);
+        fs::write(&tmp_path, data).await?;
+        fs::rename(tmp_path, dir.as_ref().join(MARKER_FILE_NAME)).await?;
+        Ok(())
+    }
+}
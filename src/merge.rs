@@ -1,28 +1,218 @@
 use std::ffi::OsStr;
 use std::io::prelude::*;
-use std::path::Path;
-use std::{fs, process};
+use std::io::{self, BufReader, IsTerminal};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::{Duration, Instant};
+use std::{fs, process, thread};
 
 use anyhow::Result;
-use futures::future::join_all;
+use chrono::{DateTime, TimeDelta, Utc};
+use flate2::read::GzDecoder;
+use futures::future::join;
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use tracing::{info, warn};
 
+use crate::audio_track::{AudioTrack, AUDIO_TRACKS_DIR_NAME};
 use crate::error::IgLiveError;
-use crate::pts::get_pts;
+use crate::hooks::{Hook, HookEvent, HookEventKind};
+use crate::metadata::Metadata;
+use crate::mp4;
+use crate::pts::{ffprobe_path, get_pts, get_start_time};
+
+/// Options for merge
+#[derive(Clone, Debug, Default)]
+pub struct MergeConfig {
+    /// Number of threads `ffmpeg` may use.
+    /// If `None`, let `ffmpeg` decide.
+    pub threads: Option<usize>,
+
+    /// Output file name without extension.
+    /// If `None`, name after the merged directory.
+    pub name: Option<String>,
+
+    /// Title embedded in the output file.
+    /// If set, the output file is named after it instead of `name`.
+    pub title: Option<String>,
+
+    /// Merge even if segments are missing, leaving discontinuities in the output.
+    /// If `false`, fail with [IgLiveError::MissingSegments] instead.
+    pub force: bool,
+
+    /// Container of the merged video.
+    pub format: OutputFormat,
+
+    /// Write a fragmented `.mp4` with a fragment per keyframe, which segments start with, for
+    /// serving over DASH or HLS without re-fragmenting.
+    /// Only applies to [OutputFormat::Mp4].
+    pub fragmented: bool,
+
+    /// Set the output file's modification time to the stream's start time, if known.
+    pub set_mtime: bool,
+
+    /// Mark a chapter this often, titled with the wall-clock time it starts at.
+    /// Needs the stream's start and end time. Ignored by [Muxer::Builtin].
+    pub chapter_interval: Option<Duration>,
+
+    /// Transcode instead of copying the downloaded streams.
+    /// If `None`, remux without re-encoding.
+    pub recode: Option<Recode>,
+
+    /// Audio sample rate of the output, e.g. `48000`.
+    /// Setting it re-encodes the audio, with `aac` unless `recode` is set.
+    pub audio_rate: Option<u32>,
+
+    /// Number of audio channels of the output, e.g. `2` for stereo.
+    /// Setting it re-encodes the audio, with `aac` unless `recode` is set.
+    pub audio_channels: Option<u32>,
+
+    /// Assemble the video in memory and pipe it to `ffmpeg` if the segment files total at most
+    /// this many bytes, instead of concatenating them into a temporary file first.
+    /// If `None`, always use temporary files.
+    pub in_memory_limit: Option<u64>,
+
+    /// `ffmpeg` log level, e.g. `"warning"`, to show `ffmpeg`'s log output at.
+    /// If `None`, `ffmpeg`'s output is hidden.
+    pub ffmpeg_loglevel: Option<String>,
+
+    /// Program to mux the merged video with.
+    pub muxer: Muxer,
+
+    /// Path of the `ffmpeg` executable. `ffprobe` is run from the same directory.
+    /// If `None`, both are run from `$PATH`.
+    pub ffmpeg_path: Option<PathBuf>,
+
+    /// Extra arguments passed to `ffmpeg` right before the output file, e.g.
+    /// `["-preset", "slow"]`. They can override the arguments set by the other options.
+    pub ffmpeg_args: Vec<String>,
+
+    /// Notified once the merged video is written.
+    pub on_complete: Option<Hook>,
+}
+
+/// Container of a merged video
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// MPEG-4, playable almost anywhere
+    #[default]
+    Mp4,
+
+    /// Matroska, more tolerant of the timestamp jumps left by missing segments
+    Mkv,
+
+    /// MPEG transport stream, playable while it is still being written
+    Ts,
+}
+
+impl OutputFormat {
+    /// File extension, without the dot
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Mp4 => "mp4",
+            Self::Mkv => "mkv",
+            Self::Ts => "ts",
+        }
+    }
+}
+
+/// Program muxing the merged video
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Muxer {
+    /// `ffmpeg` if it is installed, else the built-in muxer
+    #[default]
+    Auto,
+
+    /// `ffmpeg` and `ffprobe`, which must be installed
+    Ffmpeg,
+
+    /// Built-in muxer, writing a fragmented `.mp4` without `ffmpeg` or `ffprobe`.
+    /// Can't re-encode or change the audio format, and leaves out the title and additional
+    /// audio tracks.
+    Builtin,
+}
+
+/// Transcoding target of a merge.
+/// Re-encoding is CPU-intensive and takes much longer than remuxing.
+#[derive(Clone, Debug)]
+pub struct Recode {
+    /// `ffmpeg` video encoder
+    pub video_codec: String,
+
+    /// `ffmpeg` audio encoder
+    pub audio_codec: String,
+
+    /// Constant rate factor for video quality. Ignored if `video_bitrate` is set.
+    pub crf: Option<u8>,
+
+    /// Target video bitrate, e.g. `"4M"`
+    pub video_bitrate: Option<String>,
+
+    /// Target audio bitrate, e.g. `"128k"`
+    pub audio_bitrate: Option<String>,
+}
+
+impl Default for Recode {
+    /// H.264/AAC, playable almost anywhere
+    fn default() -> Self {
+        Self {
+            video_codec: "libx264".to_owned(),
+            audio_codec: "aac".to_owned(),
+            crf: Some(23),
+            video_bitrate: None,
+            audio_bitrate: None,
+        }
+    }
+}
+
+/// Result of a merge
+#[derive(Clone, Debug)]
+pub struct MergeReport {
+    /// Merged video file path
+    pub path: PathBuf,
+
+    /// Number of gaps found between video and audio segments
+    pub missing_segments: usize,
+
+    /// Time spent merging
+    pub duration: Duration,
+}
 
 /// Merge video and audio segments downloaded by [download][crate::download::download] into a
-/// single `.mp4` video file.
-/// `ffmpeg` is required in `$PATH`.
+/// single video file, `.mp4` unless set otherwise by [MergeConfig::format].
+/// `ffmpeg` and `ffprobe` are used if they are installed, otherwise see [Muxer::Builtin].
 ///
 /// The output file will be placed in `dir`.
 ///
 /// # Arguments
 ///
 /// `dir` - Directory containing downloaded video and audio segments.
-pub async fn merge(dir: impl AsRef<Path>) -> Result<()> {
+/// `config` - Merge options.
+pub async fn merge(dir: impl AsRef<Path>, config: MergeConfig) -> Result<MergeReport> {
+    let hook = config.on_complete.clone();
+    let report = merge_dir(&dir, config).await?;
+    if let Some(hook) = hook {
+        let metadata = Metadata::read(&dir).await.unwrap_or_default();
+        let event = HookEvent {
+            event: HookEventKind::MergeComplete,
+            time: Utc::now(),
+            stream_id: Some(metadata.id).filter(|id| !id.is_empty()),
+            username: None,
+            path: dir.as_ref().to_path_buf(),
+            output: Some(report.path.clone()),
+            missing_segments: Some(report.missing_segments),
+            error: None,
+        };
+        hook.notify(&event).await;
+    }
+    Ok(report)
+}
+
+async fn merge_dir(dir: impl AsRef<Path>, config: MergeConfig) -> Result<MergeReport> {
+    let start = Instant::now();
     let mut video_segments = vec![];
     let mut audio_segments = vec![];
 
-    println!("Merging video file");
+    info!("Merging video file");
 
     // Read all files in output directory
     let segments_dir = dir.as_ref().join("segments");
@@ -33,9 +223,10 @@ pub async fn merge(dir: impl AsRef<Path>) -> Result<()> {
         }
 
         let file_name = entry.file_name().to_string_lossy().to_string();
-        if file_name.ends_with(".m4v") {
+        let file_name = file_name.strip_suffix(".gz").unwrap_or(&file_name);
+        if file_name.ends_with(".m4v") || file_name.starts_with("video_") {
             video_segments.push(entry.path());
-        } else if file_name.ends_with(".m4a") {
+        } else if file_name.ends_with(".m4a") || file_name.starts_with("audio_") {
             audio_segments.push(entry.path());
         }
     }
@@ -43,66 +234,566 @@ pub async fn merge(dir: impl AsRef<Path>) -> Result<()> {
     // Sort segments
     video_segments.sort_by(|a, b| alphanumeric_sort::compare_path(a, b));
     audio_segments.sort_by(|a, b| alphanumeric_sort::compare_path(a, b));
+    let first_video_segment = video_segments.first().cloned();
+    // Audio-only and video-only downloads are muxed as a single track
+    let has_video = !video_segments.is_empty();
+    let has_audio = !audio_segments.is_empty();
+
+    // Small streams are concatenated in memory
+    let total_size: u64 = video_segments
+        .iter()
+        .chain(&audio_segments)
+        .filter_map(|path| fs::metadata(path).ok())
+        .map(|metadata| metadata.len())
+        .sum();
+    let in_memory = has_video
+        && config
+            .in_memory_limit
+            .is_some_and(|limit| total_size <= limit);
 
     // Concatenate segments
-    let file_name_base = dir
-        .as_ref()
-        .file_name()
-        .unwrap()
-        .to_string_lossy()
-        .to_string();
+    let file_name_base = match (&config.title, &config.name) {
+        (Some(title), _) => sanitize_file_name(title),
+        (None, Some(name)) => name.clone(),
+        (None, None) => dir
+            .as_ref()
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .to_string(),
+    };
+
+    // Fall back to the built-in muxer without ffmpeg
+    let muxer = match config.muxer {
+        Muxer::Auto if ffmpeg_available(config.ffmpeg_path.as_deref()).await => Muxer::Ffmpeg,
+        Muxer::Auto => Muxer::Builtin,
+        muxer => muxer,
+    };
+    if muxer == Muxer::Builtin {
+        return merge_builtin(
+            dir.as_ref(),
+            &file_name_base,
+            video_segments,
+            audio_segments,
+            &config,
+            start,
+        )
+        .await;
+    }
+
+    let ffmpeg = config
+        .ffmpeg_path
+        .clone()
+        .unwrap_or_else(|| "ffmpeg".into());
+    let ffprobe = ffprobe_path(config.ffmpeg_path.as_deref());
     let video_concat = dir.as_ref().join(file_name_base.clone() + "video.tmp");
     let audio_concat = dir.as_ref().join(file_name_base.clone() + "audio.tmp");
-    let merge_futs = [
-        merge_segments(video_segments, &video_concat),
-        merge_segments(audio_segments, &audio_concat),
-    ];
-    for r in join_all(merge_futs).await {
-        r?;
+    let chapters_path = dir.as_ref().join(file_name_base.clone() + "chapters.tmp");
+    let mut video_data = vec![];
+    let audio_output = fs::File::create(&audio_concat)?;
+    let (video_missing, audio_missing) = if in_memory {
+        join(
+            merge_segments(&ffprobe, video_segments, &mut video_data),
+            merge_segments(&ffprobe, audio_segments, audio_output),
+        )
+        .await
+    } else {
+        join(
+            merge_segments(&ffprobe, video_segments, fs::File::create(&video_concat)?),
+            merge_segments(&ffprobe, audio_segments, audio_output),
+        )
+        .await
+    };
+    let missing_segments = video_missing? + audio_missing?;
+    if missing_segments > 0 && !config.force {
+        let _ = fs::remove_file(video_concat);
+        let _ = fs::remove_file(audio_concat);
+        return Err(IgLiveError::MissingSegments(missing_segments).into());
+    }
+
+    // Concatenate additional audio tracks
+    let audio_tracks = if has_video && has_audio {
+        concat_audio_tracks(
+            &ffprobe,
+            dir.as_ref(),
+            &file_name_base,
+            first_video_segment.as_deref(),
+        )
+        .await?
+    } else {
+        vec![]
+    };
+
+    // Write chapters
+    let metadata = Metadata::read(&dir).await.unwrap_or_default();
+    let chapters = match (
+        config.chapter_interval,
+        metadata.start_time,
+        metadata.end_time,
+    ) {
+        (Some(interval), Some(start_time), Some(end_time)) => {
+            chapter_metadata(start_time, end_time, interval)
+        }
+        (Some(_), _, _) => {
+            warn!("Stream start and end times unknown, leaving out chapters");
+            None
+        }
+        (None, _, _) => None,
+    };
+    if let Some(chapters) = &chapters {
+        fs::write(&chapters_path, chapters)?;
     }
 
     // Mux into final file
-    let output_path = dir.as_ref().join(file_name_base + ".mp4");
-    let output = process::Command::new("ffmpeg")
-        .args([OsStr::new("-i"), video_concat.as_os_str()])
-        .args([OsStr::new("-i"), audio_concat.as_os_str()])
-        .args(["-c", "copy"])
-        .args(["-movflags", "+faststart"])
-        .arg("-y")
-        .arg(&output_path)
-        .output()?;
+    let output_path = dir
+        .as_ref()
+        .join(format!("{file_name_base}.{}", config.format.extension()));
+    let mut command = process::Command::new(&ffmpeg);
+    if in_memory {
+        command.args(["-i", "pipe:0"]);
+    } else if has_video {
+        command.args([OsStr::new("-i"), video_concat.as_os_str()]);
+    }
+    if has_audio {
+        command.args([OsStr::new("-i"), audio_concat.as_os_str()]);
+    }
+    for track in &audio_tracks {
+        command
+            .args(["-itsoffset", &track.offset.to_string()])
+            .args([OsStr::new("-i"), track.path.as_os_str()]);
+    }
+    if chapters.is_some() {
+        let input =
+            usize::from(in_memory || has_video) + usize::from(has_audio) + audio_tracks.len();
+        command
+            .args([OsStr::new("-i"), chapters_path.as_os_str()])
+            .args(["-map_chapters", &input.to_string()]);
+    }
+    if !audio_tracks.is_empty() {
+        command.args(["-map", "0:v", "-map", "1:a"]);
+        for (i, track) in audio_tracks.iter().enumerate() {
+            command.args(["-map", &format!("{}:a", i + 2)]);
+            if let Some(label) = track.info.label() {
+                command.args([
+                    &format!("-metadata:s:a:{}", i + 1),
+                    &format!("title={label}"),
+                ]);
+            }
+            if let Some(lang) = &track.info.lang {
+                command.args([
+                    &format!("-metadata:s:a:{}", i + 1),
+                    &format!("language={lang}"),
+                ]);
+            }
+        }
+    }
+    match &config.recode {
+        None if config.audio_rate.is_some() || config.audio_channels.is_some() => {
+            command.args(["-c:v", "copy"]).args(["-c:a", "aac"]);
+        }
+        None => {
+            command.args(["-c", "copy"]);
+        }
+        Some(recode) => {
+            command
+                .args(["-c:v", &recode.video_codec])
+                .args(["-c:a", &recode.audio_codec]);
+            if let Some(bitrate) = &recode.video_bitrate {
+                command.args(["-b:v", bitrate]);
+            } else if let Some(crf) = recode.crf {
+                command.args(["-crf", &crf.to_string()]);
+            }
+            if let Some(bitrate) = &recode.audio_bitrate {
+                command.args(["-b:a", bitrate]);
+            }
+        }
+    }
+    if let Some(rate) = config.audio_rate {
+        command.args(["-ar", &rate.to_string()]);
+    }
+    if let Some(channels) = config.audio_channels {
+        command.args(["-ac", &channels.to_string()]);
+    }
+    match config.format {
+        OutputFormat::Mp4 if config.fragmented => {
+            command.args(["-movflags", "frag_keyframe+empty_moov+default_base_moof"]);
+        }
+        OutputFormat::Mp4 => {
+            command.args(["-movflags", "+faststart"]);
+        }
+        OutputFormat::Mkv | OutputFormat::Ts => (),
+    }
+    if let Some(threads) = config.threads {
+        command.args(["-threads", &threads.to_string()]);
+    }
+    if let Some(title) = &config.title {
+        command.args(["-metadata", &format!("title={title}")]);
+    }
+    let start_time = metadata.start_time;
+    if let Some(start_time) = start_time {
+        command.args([
+            "-metadata",
+            &format!("creation_time={}", start_time.to_rfc3339()),
+        ]);
+    }
+    match &config.ffmpeg_loglevel {
+        Some(level) => command.args(["-loglevel", level]).stderr(Stdio::inherit()),
+        None => command.stderr(Stdio::null()),
+    };
+    command.args(["-progress", "pipe:1", "-nostats"]);
+    command.args(&config.ffmpeg_args);
+    command.arg("-y").arg(&output_path);
+    let mut child = command
+        .stdin(if in_memory {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let writer = child
+        .stdin
+        .take()
+        .map(|mut stdin| thread::spawn(move || stdin.write_all(&video_data)));
+
+    // Show progress reported by ffmpeg, relative to the stream duration if known
+    let duration = metadata
+        .start_time
+        .zip(metadata.end_time)
+        .and_then(|(start, end)| (end - start).to_std().ok());
+    let pb = merge_progress_bar(duration)?;
+    let progress = BufReader::new(child.stdout.take().unwrap());
+    for line in progress.lines() {
+        if let Some(Ok(us)) = line?.strip_prefix("out_time_us=").map(str::parse::<u64>) {
+            pb.set_position(us / 1000);
+        }
+    }
+    pb.finish_and_clear();
+    let status = child.wait()?;
+    // A write error means ffmpeg stopped reading, which its exit status reports
+    if let Some(writer) = writer {
+        let _ = writer.join();
+    }
 
     // Remove concatenated files
     let _ = fs::remove_file(video_concat);
     let _ = fs::remove_file(audio_concat);
+    let _ = fs::remove_file(chapters_path);
+    for track in audio_tracks {
+        let _ = fs::remove_file(track.path);
+    }
 
-    if !output.status.success() {
-        Err(IgLiveError::FfmpegFail.into())
-    } else {
-        println!("Merged video written to {:?}", output_path);
-        Ok(())
+    if !status.success() {
+        return Err(IgLiveError::FfmpegFail.into());
+    }
+    finish_merge(output_path, start_time, missing_segments, &config, start)
+}
+
+/// Merge into a fragmented `.mp4` with the built-in muxer
+async fn merge_builtin(
+    dir: &Path,
+    file_name_base: &str,
+    video_segments: Vec<PathBuf>,
+    audio_segments: Vec<PathBuf>,
+    config: &MergeConfig,
+    start: Instant,
+) -> Result<MergeReport> {
+    if config.recode.is_some() {
+        return Err(IgLiveError::RequiresFfmpeg("Re-encoding").into());
+    }
+    if config.audio_rate.is_some() || config.audio_channels.is_some() {
+        return Err(IgLiveError::RequiresFfmpeg("Changing the audio format").into());
+    }
+    if config.format != OutputFormat::Mp4 {
+        return Err(IgLiveError::RequiresFfmpeg("Merging into a format other than mp4").into());
+    }
+    if dir.join(AUDIO_TRACKS_DIR_NAME).is_dir() {
+        warn!("Additional audio tracks are only merged with ffmpeg, leaving them out");
+    }
+
+    let output_path = dir.join(file_name_base.to_owned() + ".mp4");
+    let output = io::BufWriter::new(fs::File::create(&output_path)?);
+    let missing_segments = mp4::remux(vec![video_segments, audio_segments], output)?;
+    if missing_segments > 0 && !config.force {
+        let _ = fs::remove_file(output_path);
+        return Err(IgLiveError::MissingSegments(missing_segments).into());
+    }
+
+    let start_time = Metadata::read(dir).await.unwrap_or_default().start_time;
+    finish_merge(output_path, start_time, missing_segments, config, start)
+}
+
+/// Apply the final touches to a merged video and report it
+fn finish_merge(
+    output_path: PathBuf,
+    start_time: Option<DateTime<Utc>>,
+    missing_segments: usize,
+    config: &MergeConfig,
+    start: Instant,
+) -> Result<MergeReport> {
+    if let Some(start_time) = start_time.filter(|_| config.set_mtime) {
+        fs::File::options()
+            .write(true)
+            .open(&output_path)?
+            .set_modified(start_time.into())?;
+    }
+    info!("Merged video written to {:?}", output_path);
+    #[cfg(feature = "metrics")]
+    {
+        use std::sync::atomic::Ordering;
+
+        use crate::metrics::METRICS;
+        METRICS.merges.fetch_add(1, Ordering::Relaxed);
+        let millis = start.elapsed().as_millis() as u64;
+        METRICS.merge_millis.fetch_add(millis, Ordering::Relaxed);
+        let missing = missing_segments as u64;
+        METRICS
+            .missing_segments
+            .fetch_add(missing, Ordering::Relaxed);
+    }
+    Ok(MergeReport {
+        path: output_path,
+        missing_segments,
+        duration: start.elapsed(),
+    })
+}
+
+/// `ffmpeg` metadata with a chapter every `interval` from `start_time` to `end_time`, titled
+/// with the wall-clock time it starts at.
+/// Returns `None` if the stream is too short for any chapter.
+fn chapter_metadata(
+    start_time: DateTime<Utc>,
+    end_time: DateTime<Utc>,
+    interval: Duration,
+) -> Option<String> {
+    let length = (end_time - start_time).num_milliseconds();
+    let step = i64::try_from(interval.as_millis())
+        .unwrap_or(i64::MAX)
+        .max(1);
+    if length <= 0 {
+        return None;
+    }
+
+    let mut metadata = ";FFMETADATA1\n".to_owned();
+    let mut chapter_start = 0;
+    while chapter_start < length {
+        let chapter_end = chapter_start.saturating_add(step).min(length);
+        let title = start_time + TimeDelta::milliseconds(chapter_start);
+        metadata += &format!(
+            "[CHAPTER]\nTIMEBASE=1/1000\nSTART={chapter_start}\nEND={chapter_end}\ntitle={}\n",
+            title.format("%Y-%m-%d %H:%M:%S UTC")
+        );
+        chapter_start = chapter_end;
+    }
+    Some(metadata)
+}
+
+/// Whether `ffmpeg` and `ffprobe` can be run, from `$PATH` if `ffmpeg` is `None`
+async fn ffmpeg_available(ffmpeg: Option<&Path>) -> bool {
+    let programs = [
+        ffmpeg.map_or("ffmpeg".into(), Path::to_path_buf),
+        ffprobe_path(ffmpeg),
+    ];
+    for program in programs {
+        let status = tokio::process::Command::new(program)
+            .arg("-version")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .await;
+        if status.is_err() {
+            return false;
+        }
     }
+    true
+}
+
+/// Progress bar of muxing in milliseconds of output, hidden if stderr is not a terminal.
+/// If `duration` is unknown, a spinner is shown instead.
+fn merge_progress_bar(duration: Option<Duration>) -> Result<ProgressBar> {
+    let pb = match duration {
+        Some(duration) => {
+            let pb = ProgressBar::new(duration.as_millis() as u64);
+            pb.set_style(ProgressStyle::with_template(
+                "{prefix:.bold.fg.green} {wide_bar} {percent}%",
+            )?);
+            pb
+        }
+        None => {
+            let pb = ProgressBar::new_spinner();
+            pb.set_style(ProgressStyle::with_template(
+                "{prefix:.bold.fg.green} {spinner}",
+            )?);
+            pb
+        }
+    };
+    pb.set_prefix("Merging");
+    if !std::io::stderr().is_terminal() {
+        pb.set_draw_target(ProgressDrawTarget::hidden());
+    }
+    Ok(pb)
+}
+
+/// Additional audio track concatenated into a temporary file
+struct ConcatAudioTrack {
+    path: PathBuf,
+
+    /// Start time relative to the video, in seconds
+    offset: f64,
+
+    info: AudioTrack,
+}
+
+/// Concatenate the segments of additional audio tracks downloaded into `dir`, in track order.
+/// Gaps are warned about but don't prevent merging, since only live segments are downloaded for
+/// additional tracks.
+async fn concat_audio_tracks(
+    ffprobe: &Path,
+    dir: &Path,
+    file_name_base: &str,
+    first_video_segment: Option<&Path>,
+) -> Result<Vec<ConcatAudioTrack>> {
+    let tracks_dir = dir.join(AUDIO_TRACKS_DIR_NAME);
+    let (true, Some(first_video_segment)) = (tracks_dir.is_dir(), first_video_segment) else {
+        return Ok(vec![]);
+    };
+    let video_start = get_start_time(ffprobe, read_segment(first_video_segment)?).await?;
+
+    let mut track_dirs: Vec<_> = fs::read_dir(&tracks_dir)?
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.is_dir())
+        .collect();
+    track_dirs.sort_by(|a, b| alphanumeric_sort::compare_path(a, b));
+
+    let mut tracks = vec![];
+    for (i, track_dir) in track_dirs.into_iter().enumerate() {
+        let mut segments: Vec<_> = fs::read_dir(track_dir.join("segments"))?
+            .flatten()
+            .map(|entry| entry.path())
+            .collect();
+        segments.sort_by(|a, b| alphanumeric_sort::compare_path(a, b));
+        let Some(first_segment) = segments.first() else {
+            continue;
+        };
+        let start = get_start_time(ffprobe, read_segment(first_segment)?).await?;
+
+        let path = dir.join(format!("{file_name_base}audio{i}.tmp"));
+        merge_segments(ffprobe, segments, fs::File::create(&path)?).await?;
+        tracks.push(ConcatAudioTrack {
+            path,
+            offset: start - video_start,
+            info: AudioTrack::read(&track_dir).await.unwrap_or_default(),
+        });
+    }
+    Ok(tracks)
+}
+
+/// Replace characters that aren't allowed in file names on common platforms
+pub(crate) fn sanitize_file_name(name: &str) -> String {
+    name.trim()
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect()
+}
+
+/// Move a merged video file out of its download directory into the directory's parent.
+/// Returns the new output file path.
+///
+/// # Arguments
+///
+/// `output_path` - Merged video file path returned by [merge].
+/// `dir` - Download directory the video was merged from.
+/// `keep_segments` - If `false`, remove `dir` afterwards.
+pub fn flatten_output(
+    output_path: impl AsRef<Path>,
+    dir: impl AsRef<Path>,
+    keep_segments: bool,
+) -> Result<PathBuf> {
+    let file_name = output_path
+        .as_ref()
+        .file_name()
+        .ok_or(IgLiveError::InvalidPath)?;
+    let new_path = dir
+        .as_ref()
+        .parent()
+        .ok_or(IgLiveError::InvalidPath)?
+        .join(file_name);
+    fs::rename(output_path, &new_path)?;
+    if !keep_segments {
+        fs::remove_dir_all(dir)?;
+    }
+
+    info!("Merged video moved to {:?}", new_path);
+    Ok(new_path)
+}
+
+/// Read a segment file, decompressing it if it was written compressed
+pub(crate) fn read_segment(path: impl AsRef<Path>) -> Result<Vec<u8>> {
+    let data = fs::read(path.as_ref())?;
+    if path.as_ref().extension() != Some(OsStr::new("gz")) {
+        return Ok(data);
+    }
+
+    let mut decompressed = vec![];
+    GzDecoder::new(&data[..]).read_to_end(&mut decompressed)?;
+    Ok(decompressed)
 }
 
 async fn merge_segments(
+    ffprobe: &Path,
     segs: impl IntoIterator<Item = impl AsRef<Path>>,
-    path: impl AsRef<Path>,
-) -> Result<()> {
-    let mut output = fs::File::create(path.as_ref())?;
+    mut output: impl Write,
+) -> Result<usize> {
     let mut pts = None;
+    let mut missing_segments = 0;
 
     // Write segments
     for seg in segs.into_iter() {
-        let seg = fs::read(seg)?;
-        let cur_pts = get_pts(seg.clone()).await.unwrap();
+        let seg = read_segment(seg)?;
+        let cur_pts = get_pts(ffprobe, seg.clone()).await.unwrap();
         if let Some(pts) = pts {
             if pts != cur_pts.0 {
-                eprintln!("WARNING: Missing segment at PTS={}", pts);
+                warn!("Missing segment at PTS={}", pts);
+                missing_segments += 1;
             }
         }
         pts = Some(cur_pts.1);
         output.write_all(&seg)?;
     }
 
-    Ok(())
+    Ok(missing_segments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chapters_are_titled_with_wall_clock_time() {
+        let start_time: DateTime<Utc> = "2024-05-01T12:03:10Z".parse().unwrap();
+        let end_time = start_time + TimeDelta::seconds(25 * 60);
+        let chapter = |start: u32, end: u32, title: &str| {
+            format!("[CHAPTER]\nTIMEBASE=1/1000\nSTART={start}\nEND={end}\ntitle={title}\n")
+        };
+        assert_eq!(
+            chapter_metadata(start_time, end_time, Duration::from_secs(600)).unwrap(),
+            [
+                ";FFMETADATA1\n".to_owned(),
+                chapter(0, 600000, "2024-05-01 12:03:10 UTC"),
+                chapter(600000, 1200000, "2024-05-01 12:13:10 UTC"),
+                chapter(1200000, 1500000, "2024-05-01 12:23:10 UTC"),
+            ]
+            .concat()
+        );
+
+        assert_eq!(
+            chapter_metadata(start_time, start_time, Duration::from_secs(600)),
+            None
+        );
+    }
 }
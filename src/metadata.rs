@@ -0,0 +1,38 @@
+use std::path::Path;
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+const METADATA_FILE_NAME: &str = "metadata.json";
+
+/// Information about a downloaded live stream, written alongside its segments
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Metadata {
+    /// Live stream ID
+    pub id: String,
+
+    /// Stream IDs the stream was published under, in order
+    #[serde(default)]
+    pub ids: Vec<String>,
+
+    /// Wall-clock time of the earliest downloaded segment
+    pub start_time: Option<DateTime<Utc>>,
+
+    /// Wall-clock time of the latest downloaded segment
+    pub end_time: Option<DateTime<Utc>>,
+}
+
+impl Metadata {
+    pub async fn read(dir: impl AsRef<Path>) -> Result<Self> {
+        let data = fs::read(dir.as_ref().join(METADATA_FILE_NAME)).await?;
+        Ok(serde_json::from_slice(&data)?)
+    }
+
+    pub async fn write(&self, dir: impl AsRef<Path>) -> Result<()> {
+        let data = serde_json::to_vec_pretty(self)?;
+        fs::write(dir.as_ref().join(METADATA_FILE_NAME), data).await?;
+        Ok(())
+    }
+}
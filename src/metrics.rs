@@ -0,0 +1,144 @@
+use std::fmt::Display;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::Result;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, ToSocketAddrs};
+
+/// Counters of all downloads and merges in this process
+pub static METRICS: Metrics = Metrics::new();
+
+/// Process-wide download and merge counters, rendered in the Prometheus text format
+#[derive(Debug)]
+pub struct Metrics {
+    /// Downloads currently running
+    pub active_downloads: AtomicU64,
+
+    /// Media segments downloaded
+    pub segments: AtomicU64,
+
+    /// Bytes of media segments downloaded, excluding initialization segments
+    pub bytes: AtomicU64,
+
+    /// Segment download retries
+    pub retries: AtomicU64,
+
+    /// Segment requests answered with status 404, including nonexistent past segments
+    pub not_found: AtomicU64,
+
+    /// Segment requests answered with status 429
+    pub rate_limited: AtomicU64,
+
+    /// Completed merges
+    pub merges: AtomicU64,
+
+    /// Total time spent merging, in milliseconds
+    pub merge_millis: AtomicU64,
+
+    /// Gaps found between segments while merging
+    pub missing_segments: AtomicU64,
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Self {
+            active_downloads: AtomicU64::new(0),
+            segments: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+            retries: AtomicU64::new(0),
+            not_found: AtomicU64::new(0),
+            rate_limited: AtomicU64::new(0),
+            merges: AtomicU64::new(0),
+            merge_millis: AtomicU64::new(0),
+            missing_segments: AtomicU64::new(0),
+        }
+    }
+
+    /// Current values in the Prometheus text exposition format, to serve from a `/metrics`
+    /// endpoint.
+    pub fn render(&self) -> String {
+        let get = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let mut text = String::new();
+        let mut add = |name: &str, kind: &str, help: &str, value: &dyn Display| {
+            text += &format!("# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}\n");
+        };
+        add(
+            "iglive_active_downloads",
+            "gauge",
+            "Downloads currently running",
+            &get(&self.active_downloads),
+        );
+        add(
+            "iglive_segments_total",
+            "counter",
+            "Media segments downloaded",
+            &get(&self.segments),
+        );
+        add(
+            "iglive_bytes_total",
+            "counter",
+            "Bytes of media segments downloaded",
+            &get(&self.bytes),
+        );
+        add(
+            "iglive_retries_total",
+            "counter",
+            "Segment download retries",
+            &get(&self.retries),
+        );
+        add(
+            "iglive_not_found_total",
+            "counter",
+            "Segment requests answered with status 404",
+            &get(&self.not_found),
+        );
+        add(
+            "iglive_rate_limited_total",
+            "counter",
+            "Segment requests answered with status 429",
+            &get(&self.rate_limited),
+        );
+        add(
+            "iglive_merges_total",
+            "counter",
+            "Completed merges",
+            &get(&self.merges),
+        );
+        add(
+            "iglive_merge_seconds_total",
+            "counter",
+            "Time spent merging",
+            &(get(&self.merge_millis) as f64 / 1000.0),
+        );
+        add(
+            "iglive_missing_segments_total",
+            "counter",
+            "Gaps found between segments while merging",
+            &get(&self.missing_segments),
+        );
+        text
+    }
+}
+
+/// Serve [METRICS] over HTTP on `addr` until the returned future is dropped.
+/// Every request is answered with the metrics, regardless of its path.
+/// To serve them from an existing HTTP server instead, respond with [Metrics::render].
+pub async fn serve(addr: impl ToSocketAddrs) -> Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    loop {
+        let (mut stream, _) = listener.accept().await?;
+        tokio::spawn(async move {
+            // The request itself doesn't matter
+            let mut request = [0; 1024];
+            let _ = stream.read(&mut request).await;
+
+            let body = METRICS.render();
+            let headers = format!(
+                "Content-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close",
+                body.len()
+            );
+            let response = format!("HTTP/1.1 200 OK\r\n{headers}\r\n\r\n{body}");
+            let _ = stream.write_all(response.as_bytes()).await;
+        });
+    }
+}
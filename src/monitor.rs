@@ -0,0 +1,75 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use tokio::time;
+use tracing::{info, warn};
+
+use crate::download::{build_client, CancellationToken, DownloadConfig};
+use crate::error::IgLiveError;
+use crate::merge::MergeConfig;
+use crate::queue::download_and_merge;
+use crate::resolve::resolve_mpd_url;
+
+/// Wait for `username` to go live, download and merge each broadcast, and wait again, until
+/// `cancel` is cancelled.
+/// The user's broadcast is looked up every `interval`. A broadcast still listed after it was
+/// downloaded isn't downloaded again.
+///
+/// # Arguments
+///
+/// * `username` - Instagram username to monitor.
+/// * `config` - Options for every download. Its cancellation token is replaced per download.
+///   [DownloadConfig::cookie] is used to look up the broadcast.
+/// * `merge_config` - Options for merging every finished download. If `None`, don't merge.
+/// * `interval` - Time between lookups while the user isn't live.
+/// * `cancel` - Token to stop monitoring with. Stops the running download too.
+pub async fn run_monitor(
+    username: &str,
+    config: DownloadConfig,
+    merge_config: Option<MergeConfig>,
+    interval: Duration,
+    cancel: CancellationToken,
+) -> Result<()> {
+    let client = build_client(&config)?;
+    let cookie = config.cookie.as_deref();
+    let mut last_path = None;
+    let mut waiting = false;
+    while !cancel.is_cancelled() {
+        match resolve_mpd_url(&client, username, cookie, config.user_agent.as_deref()).await {
+            // Manifest URLs of the same broadcast only differ in their signed query
+            Ok(url) if last_path.as_deref() != Some(url.path()) => {
+                info!("{username} is live, starting download");
+                waiting = false;
+                let config = DownloadConfig {
+                    username: Some(username.to_owned()),
+                    cancel: cancel.child_token(),
+                    ..config.clone()
+                };
+                match download_and_merge(url.to_string(), config, merge_config.clone()).await {
+                    Ok(path) => {
+                        info!("Finished download of {username} in {path:?}");
+                        last_path = Some(url.path().to_owned());
+                        continue;
+                    }
+                    // Try the broadcast again after the interval, in case it's still live
+                    Err(e) => warn!("Download of {username} failed: {e}"),
+                }
+            }
+            Ok(_) => (),
+            Err(e) if matches!(e.downcast_ref(), Some(IgLiveError::NotLive(_))) => {
+                if !waiting {
+                    info!("Waiting for {username} to go live");
+                    waiting = true;
+                }
+            }
+            Err(e) => warn!("Looking up {username}'s broadcast failed: {e}"),
+        }
+
+        tokio::select! {
+            _ = time::sleep(interval) => (),
+            _ = cancel.cancelled() => break,
+        }
+    }
+
+    Ok(())
+}
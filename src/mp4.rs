@@ -0,0 +1,700 @@
+use std::io::Write;
+use std::ops::Range;
+use std::path::PathBuf;
+
+use anyhow::Result;
+use tracing::warn;
+
+use crate::error::IgLiveError;
+use crate::merge::read_segment;
+
+/// `tfhd` flag: `base_data_offset` is present
+const TFHD_BASE_DATA_OFFSET: u32 = 0x1;
+/// `tfhd` flag: `sample_description_index` is present
+const TFHD_SAMPLE_DESCRIPTION_INDEX: u32 = 0x2;
+/// `tfhd` flag: `default_sample_duration` is present
+const TFHD_DEFAULT_SAMPLE_DURATION: u32 = 0x8;
+
+/// `trun` flag: `data_offset` is present
+const TRUN_DATA_OFFSET: u32 = 0x1;
+/// `trun` flag: `first_sample_flags` is present
+const TRUN_FIRST_SAMPLE_FLAGS: u32 = 0x4;
+/// `trun` flag: each sample has a duration
+const TRUN_SAMPLE_DURATION: u32 = 0x100;
+/// `trun` flag: each sample has a size
+const TRUN_SAMPLE_SIZE: u32 = 0x200;
+/// `trun` flag: each sample has flags
+const TRUN_SAMPLE_FLAGS: u32 = 0x400;
+/// `trun` flag: each sample has a composition time offset
+const TRUN_SAMPLE_CTO: u32 = 0x800;
+
+/// Box found in a buffer, by offsets into that buffer
+#[derive(Clone, Copy, Debug)]
+struct Mp4Box {
+    kind: [u8; 4],
+
+    /// Start of the box header
+    start: usize,
+
+    /// Start of the box content, after the header
+    body: usize,
+
+    /// End of the box
+    end: usize,
+}
+
+impl Mp4Box {
+    fn range(&self) -> Range<usize> {
+        self.start..self.end
+    }
+
+    fn body_range(&self) -> Range<usize> {
+        self.body..self.end
+    }
+}
+
+fn invalid(msg: impl Into<String>) -> anyhow::Error {
+    IgLiveError::InvalidMp4(msg.into()).into()
+}
+
+fn read_u32(data: &[u8], pos: usize) -> Result<u32> {
+    let bytes = data
+        .get(pos..pos + 4)
+        .ok_or_else(|| invalid("truncated box"))?;
+    Ok(u32::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_u64(data: &[u8], pos: usize) -> Result<u64> {
+    let bytes = data
+        .get(pos..pos + 8)
+        .ok_or_else(|| invalid("truncated box"))?;
+    Ok(u64::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+fn write_u32(data: &mut [u8], pos: usize, value: u32) {
+    data[pos..pos + 4].copy_from_slice(&value.to_be_bytes());
+}
+
+fn write_u64(data: &mut [u8], pos: usize, value: u64) {
+    data[pos..pos + 8].copy_from_slice(&value.to_be_bytes());
+}
+
+/// Version and flags of the full box starting at `body`
+fn full_box_header(data: &[u8], body: usize) -> Result<(u8, u32)> {
+    let header = read_u32(data, body)?;
+    Ok(((header >> 24) as u8, header & 0xff_ffff))
+}
+
+/// Boxes directly within `range` of `data`
+fn children(data: &[u8], range: Range<usize>) -> Result<Vec<Mp4Box>> {
+    let mut boxes = vec![];
+    let mut pos = range.start;
+    while pos < range.end {
+        if range.end - pos < 8 {
+            return Err(invalid("truncated box header"));
+        }
+        let size = read_u32(data, pos)? as u64;
+        let kind: [u8; 4] = data[pos + 4..pos + 8].try_into().unwrap();
+        let (size, body) = match size {
+            // Box extends to the end of its parent
+            0 => ((range.end - pos) as u64, pos + 8),
+            1 => (read_u64(data, pos + 8)?, pos + 16),
+            size => (size, pos + 8),
+        };
+        let end = usize::try_from(size)
+            .ok()
+            .and_then(|size| pos.checked_add(size))
+            .filter(|&end| end >= body && end <= range.end)
+            .ok_or_else(|| {
+                invalid(format!(
+                    "{} box overruns its parent",
+                    String::from_utf8_lossy(&kind)
+                ))
+            })?;
+        boxes.push(Mp4Box {
+            kind,
+            start: pos,
+            body,
+            end,
+        });
+        pos = end;
+    }
+    Ok(boxes)
+}
+
+/// First box of `kind` directly within `range` of `data`
+fn find(data: &[u8], range: Range<usize>, kind: &[u8; 4]) -> Result<Option<Mp4Box>> {
+    Ok(children(data, range)?.into_iter().find(|b| &b.kind == kind))
+}
+
+/// First box found by following `path` of box kinds down from `range` of `data`
+fn find_path(data: &[u8], range: Range<usize>, path: &[&[u8; 4]]) -> Result<Option<Mp4Box>> {
+    let mut found = None;
+    let mut range = range;
+    for kind in path {
+        let Some(b) = find(data, range, kind)? else {
+            return Ok(None);
+        };
+        range = b.body_range();
+        found = Some(b);
+    }
+    Ok(found)
+}
+
+fn write_box(out: &mut Vec<u8>, kind: &[u8; 4], body: &[u8]) {
+    out.extend_from_slice(&(8 + body.len() as u32).to_be_bytes());
+    out.extend_from_slice(kind);
+    out.extend_from_slice(body);
+}
+
+/// Initialization of a track, read from the initialization segment preceding each segment
+struct TrackInit {
+    ftyp: Option<Vec<u8>>,
+    mvhd: Vec<u8>,
+    trak: Vec<u8>,
+    trex: Option<Vec<u8>>,
+
+    /// Time units per second
+    timescale: u32,
+
+    /// Sample duration used when fragments don't specify one
+    default_sample_duration: u32,
+}
+
+impl TrackInit {
+    fn parse(data: &[u8]) -> Result<Self> {
+        let top = children(data, 0..data.len())?;
+        let ftyp = top.iter().find(|b| &b.kind == b"ftyp");
+        let moov = top
+            .iter()
+            .find(|b| &b.kind == b"moov")
+            .ok_or_else(|| invalid("segment has no moov box"))?;
+        let mvhd = find(data, moov.body_range(), b"mvhd")?
+            .ok_or_else(|| invalid("moov has no mvhd box"))?;
+        let trak = find(data, moov.body_range(), b"trak")?
+            .ok_or_else(|| invalid("moov has no trak box"))?;
+        let trex = find_path(data, moov.body_range(), &[b"mvex", b"trex"])?;
+        let mdhd = find_path(data, trak.body_range(), &[b"mdia", b"mdhd"])?
+            .ok_or_else(|| invalid("trak has no mdhd box"))?;
+
+        // Skip creation and modification times
+        let (version, _) = full_box_header(data, mdhd.body)?;
+        let timescale_pos = mdhd.body + if version == 1 { 20 } else { 12 };
+        let default_sample_duration = match trex {
+            Some(trex) => read_u32(data, trex.body + 12)?,
+            None => 0,
+        };
+        Ok(Self {
+            ftyp: ftyp.map(|b| data[b.range()].to_vec()),
+            mvhd: data[mvhd.range()].to_vec(),
+            trak: data[trak.range()].to_vec(),
+            trex: trex.map(|b| data[b.range()].to_vec()),
+            timescale: read_u32(data, timescale_pos)?.max(1),
+            default_sample_duration,
+        })
+    }
+
+    /// `trak` box renumbered as `track_id`
+    fn trak(&self, track_id: u32) -> Result<Vec<u8>> {
+        let mut trak = self.trak.clone();
+        let tkhd = find_path(&trak, 0..trak.len(), &[b"trak", b"tkhd"])?
+            .ok_or_else(|| invalid("trak has no tkhd box"))?;
+        let (version, _) = full_box_header(&trak, tkhd.body)?;
+        // Skip creation and modification times
+        let track_id_pos = tkhd.body + if version == 1 { 20 } else { 12 };
+        read_u32(&trak, track_id_pos)?;
+        write_u32(&mut trak, track_id_pos, track_id);
+        Ok(trak)
+    }
+
+    /// `trex` box renumbered as `track_id`, with default sample values if there was none
+    fn trex(&self, track_id: u32) -> Result<Vec<u8>> {
+        let mut trex = match &self.trex {
+            Some(trex) => trex.clone(),
+            None => {
+                let mut trex = vec![];
+                let mut body = vec![0; 24];
+                // Default sample description index 1, the rest 0
+                write_u32(&mut body, 8, 1);
+                write_box(&mut trex, b"trex", &body);
+                trex
+            }
+        };
+        read_u32(&trex, 12)?;
+        write_u32(&mut trex, 12, track_id);
+        Ok(trex)
+    }
+}
+
+/// Movie fragments of a segment
+struct Fragments {
+    /// `moof` and `mdat` boxes, in order
+    boxes: Vec<Mp4Box>,
+
+    /// Decode time of the first sample, in the track's timescale
+    start: u64,
+
+    /// Decode time after the last sample, in the track's timescale
+    end: u64,
+}
+
+impl Fragments {
+    fn parse(data: &[u8], default_sample_duration: u32) -> Result<Self> {
+        let boxes: Vec<_> = children(data, 0..data.len())?
+            .into_iter()
+            .filter(|b| &b.kind == b"moof" || &b.kind == b"mdat")
+            .collect();
+        let mut times = None;
+        for moof in boxes.iter().filter(|b| &b.kind == b"moof") {
+            for traf in children(data, moof.body_range())? {
+                if &traf.kind != b"traf" {
+                    continue;
+                }
+                let (start, duration) = traf_times(data, traf, default_sample_duration)?;
+                let (first, _) = times.unwrap_or((start, start));
+                times = Some((first, start + duration));
+            }
+        }
+        let (start, end) = times.ok_or_else(|| invalid("segment has no movie fragment"))?;
+        Ok(Self { boxes, start, end })
+    }
+}
+
+/// Decode time and total sample duration of a track fragment
+fn traf_times(data: &[u8], traf: Mp4Box, default_sample_duration: u32) -> Result<(u64, u64)> {
+    let tfhd =
+        find(data, traf.body_range(), b"tfhd")?.ok_or_else(|| invalid("traf has no tfhd box"))?;
+    let (_, flags) = full_box_header(data, tfhd.body)?;
+    let mut pos = tfhd.body + 8;
+    if flags & TFHD_BASE_DATA_OFFSET != 0 {
+        pos += 8;
+    }
+    if flags & TFHD_SAMPLE_DESCRIPTION_INDEX != 0 {
+        pos += 4;
+    }
+    let default_duration = if flags & TFHD_DEFAULT_SAMPLE_DURATION != 0 {
+        read_u32(data, pos)?
+    } else {
+        default_sample_duration
+    };
+
+    let tfdt =
+        find(data, traf.body_range(), b"tfdt")?.ok_or_else(|| invalid("traf has no tfdt box"))?;
+    let start = match full_box_header(data, tfdt.body)? {
+        (1, _) => read_u64(data, tfdt.body + 4)?,
+        _ => read_u32(data, tfdt.body + 4)? as u64,
+    };
+
+    let mut duration = 0;
+    for trun in children(data, traf.body_range())? {
+        if &trun.kind != b"trun" {
+            continue;
+        }
+        let (_, flags) = full_box_header(data, trun.body)?;
+        let sample_count = read_u32(data, trun.body + 4)? as u64;
+        if flags & TRUN_SAMPLE_DURATION == 0 {
+            duration += sample_count * default_duration as u64;
+            continue;
+        }
+        let mut pos = trun.body + 8;
+        if flags & TRUN_DATA_OFFSET != 0 {
+            pos += 4;
+        }
+        if flags & TRUN_FIRST_SAMPLE_FLAGS != 0 {
+            pos += 4;
+        }
+        let sample_len = 4 * [
+            TRUN_SAMPLE_DURATION,
+            TRUN_SAMPLE_SIZE,
+            TRUN_SAMPLE_FLAGS,
+            TRUN_SAMPLE_CTO,
+        ]
+        .iter()
+        .filter(|&&flag| flags & flag != 0)
+        .count();
+        for _ in 0..sample_count {
+            duration += read_u32(data, pos)? as u64;
+            pos += sample_len;
+        }
+    }
+    Ok((start, duration))
+}
+
+/// Check that `data` is an initialization segment, with a `moov` box describing a track
+pub(crate) fn check_init(data: &[u8]) -> Result<()> {
+    TrackInit::parse(data).map(|_| ())
+}
+
+/// Decode time of the first sample, and after the last sample, of a segment starting with its
+/// initialization segment, in the track's timescale
+pub(crate) fn segment_times(data: &[u8]) -> Result<(usize, usize)> {
+    let init = TrackInit::parse(data)?;
+    let fragments = Fragments::parse(data, init.default_sample_duration)?;
+    Ok((fragments.start as usize, fragments.end as usize))
+}
+
+/// Renumber a `moof` box copied to `moof[..]` as fragment `sequence` of `track_id`, shifting its
+/// decode times back by `time_offset` and its absolute data offsets by `moved_by` bytes.
+fn rewrite_moof(
+    moof: &mut [u8],
+    sequence: u32,
+    track_id: u32,
+    time_offset: u64,
+    moved_by: i64,
+) -> Result<()> {
+    let root = children(moof, 0..moof.len())?[0];
+    for child in children(moof, root.body_range())? {
+        match &child.kind {
+            b"mfhd" => write_u32(moof, child.body + 4, sequence),
+            b"traf" => {
+                for b in children(moof, child.body_range())? {
+                    match &b.kind {
+                        b"tfhd" => {
+                            let (_, flags) = full_box_header(moof, b.body)?;
+                            read_u32(moof, b.body + 4)?;
+                            write_u32(moof, b.body + 4, track_id);
+                            if flags & TFHD_BASE_DATA_OFFSET != 0 {
+                                let offset = read_u64(moof, b.body + 8)?;
+                                let moved = offset.saturating_add_signed(moved_by);
+                                write_u64(moof, b.body + 8, moved);
+                            }
+                        }
+                        b"tfdt" => match full_box_header(moof, b.body)? {
+                            (1, _) => {
+                                let time = read_u64(moof, b.body + 4)?;
+                                write_u64(moof, b.body + 4, time.saturating_sub(time_offset));
+                            }
+                            _ => {
+                                let time = read_u32(moof, b.body + 4)? as u64;
+                                let time = time.saturating_sub(time_offset) as u32;
+                                write_u32(moof, b.body + 4, time);
+                            }
+                        },
+                        _ => (),
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+/// Segments of one track being remuxed
+struct TrackCursor {
+    init: TrackInit,
+    segments: std::vec::IntoIter<PathBuf>,
+
+    /// Current segment's data and fragments, if any are left
+    current: Option<(Vec<u8>, Fragments)>,
+
+    /// Decode time shifted away so all tracks start near 0
+    time_offset: u64,
+
+    /// Decode time after the last segment written
+    previous_end: Option<u64>,
+}
+
+impl TrackCursor {
+    fn advance(&mut self) -> Result<()> {
+        self.current = match self.segments.next() {
+            Some(path) => {
+                let data = read_segment(path)?;
+                let fragments = Fragments::parse(&data, self.init.default_sample_duration)?;
+                Some((data, fragments))
+            }
+            None => None,
+        };
+        Ok(())
+    }
+
+    /// Start time of the current segment in seconds
+    fn start_secs(&self) -> Option<f64> {
+        let (_, fragments) = self.current.as_ref()?;
+        Some(fragments.start as f64 / self.init.timescale as f64)
+    }
+}
+
+/// Remux fragmented MP4 segments into a single fragmented MP4 file, without `ffmpeg`.
+/// Each segment must start with its track's initialization segment, as written by the
+/// downloader.
+/// Fragments of all tracks are interleaved by time, renumbered, and shifted so the earliest
+/// track starts at 0.
+/// Returns the number of gaps between consecutive segments of the same track.
+///
+/// # Arguments
+///
+/// * `tracks` - Segment files of each track, in order. Tracks without segments are left out.
+/// * `output` - Where to write the merged file.
+pub(crate) fn remux(tracks: Vec<Vec<PathBuf>>, mut output: impl Write) -> Result<usize> {
+    let mut cursors = vec![];
+    for segments in tracks.into_iter().filter(|s| !s.is_empty()) {
+        let init = TrackInit::parse(&read_segment(&segments[0])?)?;
+        let mut cursor = TrackCursor {
+            init,
+            segments: segments.into_iter(),
+            current: None,
+            time_offset: 0,
+            previous_end: None,
+        };
+        cursor.advance()?;
+        cursors.push(cursor);
+    }
+    if cursors.is_empty() {
+        return Err(invalid("no segments to merge"));
+    }
+
+    // Start the earliest track at 0, keeping the others in sync with it
+    let start = cursors
+        .iter()
+        .filter_map(TrackCursor::start_secs)
+        .fold(f64::INFINITY, f64::min);
+    for cursor in &mut cursors {
+        let first = cursor.current.as_ref().map_or(0, |(_, f)| f.start);
+        cursor.time_offset = ((start * cursor.init.timescale as f64) as u64).min(first);
+    }
+
+    // Initialization of all tracks, numbered from 1
+    let mut header = vec![];
+    match &cursors[0].init.ftyp {
+        Some(ftyp) => header.extend_from_slice(ftyp),
+        None => write_box(&mut header, b"ftyp", b"iso6\0\0\0\0iso6mp41"),
+    }
+    let mut moov = cursors[0].init.mvhd.clone();
+    let next_track_id_pos = moov.len() - 4;
+    write_u32(&mut moov, next_track_id_pos, cursors.len() as u32 + 1);
+    let mut mvex = vec![];
+    for (i, cursor) in cursors.iter().enumerate() {
+        moov.extend(cursor.init.trak(i as u32 + 1)?);
+        mvex.extend(cursor.init.trex(i as u32 + 1)?);
+    }
+    write_box(&mut moov, b"mvex", &mvex);
+    write_box(&mut header, b"moov", &moov);
+    output.write_all(&header)?;
+    let mut written = header.len() as u64;
+
+    // Write the earliest segment of any track next
+    let mut sequence = 0;
+    let mut missing_segments = 0;
+    while let Some(i) = (0..cursors.len())
+        .filter(|&i| cursors[i].current.is_some())
+        .min_by(|&a, &b| {
+            let (a, b) = (cursors[a].start_secs(), cursors[b].start_secs());
+            a.partial_cmp(&b).unwrap_or(std::cmp::Ordering::Equal)
+        })
+    {
+        let cursor = &mut cursors[i];
+        let (data, fragments) = cursor.current.as_ref().unwrap();
+        if let Some(previous_end) = cursor.previous_end {
+            if previous_end != fragments.start {
+                warn!("Missing segment at PTS={previous_end}");
+                missing_segments += 1;
+            }
+        }
+        cursor.previous_end = Some(fragments.end);
+
+        for b in &fragments.boxes {
+            let mut bytes = data[b.range()].to_vec();
+            if &b.kind == b"moof" {
+                sequence += 1;
+                let moved_by = written as i64 - b.start as i64;
+                rewrite_moof(
+                    &mut bytes,
+                    sequence,
+                    i as u32 + 1,
+                    cursor.time_offset,
+                    moved_by,
+                )?;
+            }
+            output.write_all(&bytes)?;
+            written += bytes.len() as u64;
+        }
+        cursor.advance()?;
+    }
+    output.flush()?;
+
+    Ok(missing_segments)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub(crate) fn mp4_box(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut out = vec![];
+        write_box(&mut out, kind, body);
+        out
+    }
+
+    fn full_box(kind: &[u8; 4], version: u8, flags: u32, body: &[u8]) -> Vec<u8> {
+        let mut content = ((version as u32) << 24 | flags).to_be_bytes().to_vec();
+        content.extend_from_slice(body);
+        mp4_box(kind, &content)
+    }
+
+    fn u32s(values: &[u32]) -> Vec<u8> {
+        values.iter().flat_map(|v| v.to_be_bytes()).collect()
+    }
+
+    /// Initialization segment of one track with `timescale` and samples lasting
+    /// `sample_duration` by default
+    pub(crate) fn init(timescale: u32, sample_duration: u32) -> Vec<u8> {
+        let mut mvhd = vec![0; 96];
+        mvhd[8..12].copy_from_slice(&1000u32.to_be_bytes());
+        mvhd[92..96].copy_from_slice(&2u32.to_be_bytes());
+        let tkhd = full_box(b"tkhd", 0, 0, &[u32s(&[0, 0, 1]), vec![0; 68]].concat());
+        let mdhd = full_box(b"mdhd", 0, 0, &u32s(&[0, 0, timescale, 0, 0]));
+        let trak = mp4_box(b"trak", &[tkhd, mp4_box(b"mdia", &mdhd)].concat());
+        let trex = full_box(b"trex", 0, 0, &u32s(&[1, 1, sample_duration, 0, 0]));
+        let moov = [
+            full_box(b"mvhd", 0, 0, &mvhd),
+            trak,
+            mp4_box(b"mvex", &trex),
+        ]
+        .concat();
+        [mp4_box(b"ftyp", b"iso6\0\0\0\0"), mp4_box(b"moov", &moov)].concat()
+    }
+
+    /// Movie fragment of `samples` default length samples decoded from `tfdt`, whose
+    /// `tfdt` box has `tfdt_version`, and `base_data_offset` if any
+    pub(crate) fn moof(
+        tfdt: u64,
+        tfdt_version: u8,
+        samples: u32,
+        base_data_offset: Option<u64>,
+    ) -> Vec<u8> {
+        let mfhd = full_box(b"mfhd", 0, 0, &u32s(&[7]));
+        let tfhd = match base_data_offset {
+            Some(offset) => {
+                let body = [u32s(&[9]), offset.to_be_bytes().to_vec()].concat();
+                full_box(b"tfhd", 0, TFHD_BASE_DATA_OFFSET, &body)
+            }
+            None => full_box(b"tfhd", 0, 0, &u32s(&[9])),
+        };
+        let tfdt = match tfdt_version {
+            1 => full_box(b"tfdt", 1, 0, &tfdt.to_be_bytes()),
+            _ => full_box(b"tfdt", 0, 0, &u32s(&[tfdt as u32])),
+        };
+        let trun = full_box(b"trun", 0, 0, &u32s(&[samples]));
+        let traf = mp4_box(b"traf", &[tfhd, tfdt, trun].concat());
+        mp4_box(b"moof", &[mfhd, traf].concat())
+    }
+
+    /// Values of the `tfhd`, `tfdt` and `mfhd` boxes of a `moof` box
+    fn moof_fields(moof: &[u8]) -> (u32, u32, Option<u64>, u64) {
+        let root = children(moof, 0..moof.len()).unwrap()[0];
+        let mfhd = find(moof, root.body_range(), b"mfhd").unwrap().unwrap();
+        let traf = find(moof, root.body_range(), b"traf").unwrap().unwrap();
+        let tfhd = find(moof, traf.body_range(), b"tfhd").unwrap().unwrap();
+        let tfdt = find(moof, traf.body_range(), b"tfdt").unwrap().unwrap();
+        let (_, flags) = full_box_header(moof, tfhd.body).unwrap();
+        let base_data_offset =
+            (flags & TFHD_BASE_DATA_OFFSET != 0).then(|| read_u64(moof, tfhd.body + 8).unwrap());
+        let time = match full_box_header(moof, tfdt.body).unwrap() {
+            (1, _) => read_u64(moof, tfdt.body + 4).unwrap(),
+            _ => read_u32(moof, tfdt.body + 4).unwrap() as u64,
+        };
+        (
+            read_u32(moof, mfhd.body + 4).unwrap(),
+            read_u32(moof, tfhd.body + 4).unwrap(),
+            base_data_offset,
+            time,
+        )
+    }
+
+    fn is_invalid_mp4(result: Result<impl std::fmt::Debug>) -> bool {
+        matches!(
+            result.unwrap_err().downcast_ref(),
+            Some(IgLiveError::InvalidMp4(_))
+        )
+    }
+
+    #[test]
+    fn rewrite_moof_shifts_tfdt_v0() {
+        let mut moof = moof(1500, 0, 1, None);
+        rewrite_moof(&mut moof, 3, 2, 1000, 0).unwrap();
+        assert_eq!(moof_fields(&moof), (3, 2, None, 500));
+    }
+
+    #[test]
+    fn rewrite_moof_shifts_tfdt_v1() {
+        let mut moof = moof(u32::MAX as u64 + 1500, 1, 1, None);
+        rewrite_moof(&mut moof, 1, 1, u32::MAX as u64, 0).unwrap();
+        assert_eq!(moof_fields(&moof), (1, 1, None, 1500));
+    }
+
+    #[test]
+    fn rewrite_moof_moves_base_data_offset() {
+        let mut moof = moof(0, 0, 1, Some(5000));
+        rewrite_moof(&mut moof, 1, 1, 0, -1200).unwrap();
+        assert_eq!(moof_fields(&moof).2, Some(3800));
+        rewrite_moof(&mut moof, 1, 1, 0, 200).unwrap();
+        assert_eq!(moof_fields(&moof).2, Some(4000));
+    }
+
+    #[test]
+    fn segment_times_reads_fragment_times() {
+        let segment = [
+            init(1000, 40),
+            moof(2000, 0, 25, None),
+            mp4_box(b"mdat", &[0; 4]),
+        ];
+        assert_eq!(segment_times(&segment.concat()).unwrap(), (2000, 3000));
+    }
+
+    #[test]
+    fn remux_counts_gaps() {
+        let dir = std::env::temp_dir().join(format!("iglive-mp4-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        // 1 second segments at 0, 1 and 3 seconds
+        let paths: Vec<_> = [0, 1000, 3000]
+            .into_iter()
+            .map(|t| {
+                let path = dir.join(format!("{t}.m4v"));
+                let segment = [
+                    init(1000, 40),
+                    moof(t, 0, 25, None),
+                    mp4_box(b"mdat", &[0; 4]),
+                ];
+                std::fs::write(&path, segment.concat()).unwrap();
+                path
+            })
+            .collect();
+
+        let mut output = vec![];
+        let missing_segments = remux(vec![paths], &mut output);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(missing_segments.unwrap(), 1);
+
+        let kinds: Vec<_> = children(&output, 0..output.len())
+            .unwrap()
+            .iter()
+            .map(|b| b.kind)
+            .collect();
+        assert_eq!(
+            kinds,
+            [*b"ftyp", *b"moov", *b"moof", *b"mdat", *b"moof", *b"mdat", *b"moof", *b"mdat"]
+        );
+    }
+
+    #[test]
+    fn truncated_boxes_are_rejected() {
+        // Header shorter than 8 bytes
+        assert!(is_invalid_mp4(children(&[0, 0, 0, 8], 0..4)));
+
+        // Box larger than its parent
+        let mut data = mp4_box(b"moof", &[0; 8]);
+        data.truncate(12);
+        assert!(is_invalid_mp4(children(&data, 0..data.len())));
+
+        // Box too short for its fields
+        let mdhd = full_box(b"mdhd", 0, 0, &[0; 4]);
+        let trak = mp4_box(b"trak", &mp4_box(b"mdia", &mdhd));
+        let moov = [full_box(b"mvhd", 0, 0, &[0; 96]), trak].concat();
+        assert!(is_invalid_mp4(
+            TrackInit::parse(&mp4_box(b"moov", &moov)).map(|_| ())
+        ));
+        let segment = [init(1000, 40), full_box(b"moof", 0, 0, &[])].concat();
+        assert!(is_invalid_mp4(segment_times(&segment)));
+    }
+}
@@ -1,27 +1,58 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Read;
+use std::path::Path;
+use std::str::FromStr;
+
 use anyhow::Result;
+use chrono::{DateTime, TimeDelta, Utc};
+use clap::ValueEnum;
+use flate2::read::GzDecoder;
 use reqwest::header::HeaderName;
 use reqwest::{Client, Url};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 use crate::error::IgLiveError;
+use crate::util::sha256_hex;
+
+/// First bytes of gzip compressed data
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Largest difference between a segment time read as Unix time and `availabilityStartTime` for
+/// segment times to be taken as Unix times
+const EPOCH_TIME_TOLERANCE: TimeDelta = TimeDelta::days(1);
 
+/// DASH manifest of an IG live stream
 #[derive(Deserialize, Debug, Clone)]
 pub struct Mpd {
     #[serde(rename = "Period")]
     period: Option<Period>,
+
+    /// Live stream ID
     #[serde(rename = "@loapStreamId")]
     pub id: String,
 
+    /// Segment time `t` the stream started at
     #[serde(rename = "@publishFrameTime")]
     pub start_frame: usize,
 
+    /// Wall-clock time that segment time `t = 0` corresponds to
+    #[serde(rename = "@availabilityStartTime")]
+    pub availability_start_time: Option<DateTime<Utc>>,
+
+    /// Whether the stream has ended.
+    /// Only known when downloaded with [Mpd::download_from_url].
     #[serde(skip)]
     pub finished: bool,
+
+    /// SHA-256 of the manifest XML, as hex.
+    #[serde(skip)]
+    pub sha256: String,
 }
 
 #[derive(Deserialize, Debug, Clone)]
 struct Period {
-    #[serde(rename = "AdaptationSet")]
+    #[serde(rename = "AdaptationSet", default)]
     adaptation_sets: Vec<AdaptationSet>,
 }
 
@@ -29,60 +60,197 @@ struct Period {
 #[serde(rename_all = "camelCase")]
 #[allow(dead_code)]
 struct AdaptationSet {
-    #[serde(rename = "Representation")]
+    #[serde(rename = "Representation", default)]
     representations: Vec<Representation>,
     max_width: Option<usize>,
     max_height: Option<usize>,
     max_frame_rate: Option<usize>,
+    #[serde(rename = "@lang")]
+    lang: Option<String>,
 }
 
+/// A single video, audio or image rendition of the stream
 #[derive(Deserialize, Debug, Clone)]
 pub struct Representation {
+    /// Representation ID
+    #[serde(rename = "@id")]
+    pub id: Option<String>,
+    /// Templated segment URLs
     #[serde(rename = "SegmentTemplate")]
-    pub segment_template: SegmentTemplate,
+    pub segment_template: Option<SegmentTemplate>,
+    /// Explicitly listed segment URLs
+    #[serde(rename = "SegmentList")]
+    pub segment_list: Option<SegmentList>,
+    /// MIME type, e.g. `video/mp4`
     #[serde(rename = "@mimeType")]
     pub mime_type: String,
+    /// Video width
     #[serde(rename = "@width")]
     pub width: Option<usize>,
+    /// Video height
     #[serde(rename = "@height")]
     pub height: Option<usize>,
+    /// Video frame rate
     #[serde(rename = "@frameRate")]
     pub frame_rate: Option<usize>,
+    /// Bits per second
     #[serde(rename = "@bandwidth")]
     pub bandwidth: usize,
+    /// Codecs string, e.g. `avc1.64001f`
+    #[serde(rename = "@codecs")]
+    pub codecs: Option<String>,
+    /// Index of the adaptation set this representation is listed in, among the manifest's
+    /// adaptation sets of the same media type.
+    /// Video adaptation sets can differ in crop or orientation.
+    #[serde(skip)]
+    pub adaptation_set: usize,
+    /// Language of the adaptation set this representation is listed in
+    #[serde(skip)]
+    pub lang: Option<String>,
 }
 
+/// Segment URLs generated from a template
 #[derive(Deserialize, Debug, Clone)]
 pub struct SegmentTemplate {
+    /// Segments currently available
     #[serde(rename = "SegmentTimeline")]
     pub segment_timeline: SegmentTimeline,
+    /// Initialization segment path, relative to the manifest
     #[serde(rename = "@initialization")]
     pub initialization_path: String,
+    /// Media segment path, relative to the manifest, with `$Time$` in place of segment time or
+    /// `$Number$` in place of segment number
     #[serde(rename = "@media")]
     pub media_path: String,
+    /// Units per second of segment times
+    #[serde(rename = "@timescale")]
+    pub timescale: Option<usize>,
+    /// Number of the first segment in the timeline
+    #[serde(rename = "@startNumber")]
+    pub start_number: Option<usize>,
+}
+
+/// Variable a [SegmentTemplate] identifies segments by
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TemplateVariable {
+    /// `$Time$`, the segment start time
+    Time,
+    /// `$Number$`, the segment's position in the timeline
+    Number,
+}
+
+/// Explicitly listed segment URLs
+#[derive(Deserialize, Debug, Clone)]
+pub struct SegmentList {
+    /// Initialization segment
+    #[serde(rename = "Initialization")]
+    pub initialization: Initialization,
+    /// Media segments, in order
+    #[serde(rename = "SegmentURL", default)]
+    pub segment_urls: Vec<SegmentUrl>,
+    /// Times of media segments, if not derived from `duration`
+    #[serde(rename = "SegmentTimeline")]
+    pub segment_timeline: Option<SegmentTimeline>,
+    /// Duration of every media segment
+    #[serde(rename = "@duration")]
+    pub duration: Option<usize>,
+    /// Units per second of segment times
+    #[serde(rename = "@timescale")]
+    pub timescale: Option<usize>,
+    /// Number of the first listed segment
+    #[serde(rename = "@startNumber")]
+    pub start_number: Option<usize>,
+    /// Time of the first segment of the stream
+    #[serde(rename = "@presentationTimeOffset")]
+    pub presentation_time_offset: Option<usize>,
 }
 
+/// Initialization segment of a [SegmentList]
+#[derive(Deserialize, Debug, Clone)]
+pub struct Initialization {
+    /// Path relative to the manifest
+    #[serde(rename = "@sourceURL")]
+    pub source_url: String,
+}
+
+/// Media segment of a [SegmentList]
+#[derive(Deserialize, Debug, Clone)]
+pub struct SegmentUrl {
+    /// Path relative to the manifest
+    #[serde(rename = "@media")]
+    pub media: String,
+}
+
+/// Available segments of a representation
 #[derive(Deserialize, Debug, Clone)]
 pub struct SegmentTimeline {
-    #[serde(rename = "S")]
+    /// Segments, in order. Empty until a just started broadcast has a segment.
+    #[serde(rename = "S", default)]
     pub segments: Vec<Segment>,
 }
 
+/// A media segment in a [SegmentTimeline]
 #[derive(Deserialize, Debug, Clone)]
 pub struct Segment {
+    /// Start time
     #[serde(rename = "@t")]
     pub t: usize,
+    /// Duration
     #[serde(rename = "@d")]
     pub d: usize,
 }
 
+impl FromStr for Mpd {
+    type Err = anyhow::Error;
+
+    /// Parse a manifest from XML.
+    /// [Mpd::finished] is always `false`.
+    fn from_str(xml: &str) -> Result<Self> {
+        let mut manifest: Self = quick_xml::de::from_str(xml)?;
+        manifest.sha256 = sha256_hex(xml.as_bytes());
+
+        // Number adaptation sets per media type and pass their attributes on to representations
+        if let Some(period) = manifest.period.as_mut() {
+            let mut counts: HashMap<MediaType, usize> = HashMap::new();
+            for a in &mut period.adaptation_sets {
+                let Some(media_type) = a.representations.first().map(|r| r.media_type()) else {
+                    continue;
+                };
+                let count = counts.entry(media_type).or_insert(0);
+                for r in &mut a.representations {
+                    r.adaptation_set = *count;
+                    r.lang = a.lang.clone();
+                }
+                *count += 1;
+            }
+        }
+
+        Ok(manifest)
+    }
+}
+
 impl Mpd {
+    /// Download and parse a manifest.
     pub async fn download_from_url(client: &Client, url: impl AsRef<str>) -> Result<Self> {
         let resp = client.get(url.as_ref()).send().await?;
+        let status = resp.status();
+        if !status.is_success() {
+            return Err(IgLiveError::StatusError(status.into(), resp.url().to_string()).into());
+        }
         let headers = resp.headers().clone();
-        let text = resp.text().await?;
+        let bytes = resp.bytes().await?;
+
+        // Compressed responses are normally decoded by reqwest, unless served without a
+        // `Content-Encoding` header
+        let text = if bytes.starts_with(&GZIP_MAGIC) {
+            let mut text = String::new();
+            GzDecoder::new(&bytes[..]).read_to_string(&mut text)?;
+            text
+        } else {
+            String::from_utf8(bytes.to_vec())?
+        };
 
-        let mut manifest: Self = quick_xml::de::from_str(&text)?;
+        let mut manifest: Self = text.parse()?;
 
         if let Some(v) = headers.get(HeaderName::from_static("x-fb-video-broadcast-ended")) {
             if v.to_str()? == "1" {
@@ -93,54 +261,679 @@ impl Mpd {
         Ok(manifest)
     }
 
+    /// Parse a manifest from a local file.
+    /// [Mpd::finished] is always `false`.
+    pub async fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        tokio::fs::read_to_string(path).await?.parse()
+    }
+
+    /// Highest bandwidth video and audio representations.
     pub fn best_media(&self) -> Result<(&Representation, &Representation)> {
+        self.select_media(&MediaSelection::default())
+    }
+
+    /// Video and audio representations matching `selection`.
+    /// Fails with [IgLiveError::AudioOnly] or [IgLiveError::VideoOnly] if the manifest only has
+    /// one of them or only audio is selected, see [Mpd::select_tracks].
+    pub fn select_media(
+        &self,
+        selection: &MediaSelection,
+    ) -> Result<(&Representation, &Representation)> {
+        match self.select(selection)? {
+            (Some(video), Some(audio)) => Ok((video, audio)),
+            (None, Some(_)) => Err(IgLiveError::AudioOnly.into()),
+            (Some(_), None) => Err(IgLiveError::VideoOnly.into()),
+            (None, None) => Err(IgLiveError::NoMedia.into()),
+        }
+    }
+
+    /// Representations to download, like [Mpd::select_media], but only the audio or the video
+    /// representation if the manifest has no video or no audio, or only audio is selected.
+    /// The video representation comes first.
+    pub fn select_tracks(&self, selection: &MediaSelection) -> Result<Vec<&Representation>> {
+        match self.select(selection)? {
+            (None, None) => Err(IgLiveError::NoMedia.into()),
+            (video, audio) => Ok(video.into_iter().chain(audio).collect()),
+        }
+    }
+
+    /// Video and audio representations matching `selection`, if any
+    fn select(
+        &self,
+        selection: &MediaSelection,
+    ) -> Result<(Option<&Representation>, Option<&Representation>)> {
+        let MediaSelection {
+            quality,
+            frame_rate,
+            video_set,
+            bandwidth,
+        } = *selection;
         let period = self.period.as_ref().ok_or(IgLiveError::EmptyManifest)?;
-        let mut cur_video_bandwidth = 0;
-        let mut cur_audio_bandwidth = 0;
-        let mut ret: (Option<&Representation>, Option<&Representation>) = (None, None);
-        for a in &period.adaptation_sets {
-            for r in &a.representations {
-                if r.mime_type.starts_with("video") && r.bandwidth > cur_video_bandwidth {
-                    cur_video_bandwidth = r.bandwidth;
-                    ret.0 = Some(r);
-                }
-                if r.mime_type.starts_with("audio") && r.bandwidth > cur_audio_bandwidth {
-                    cur_audio_bandwidth = r.bandwidth;
-                    ret.1 = Some(r);
-                }
+        if self.representations().is_empty() {
+            return Err(IgLiveError::NoRepresentations.into());
+        }
+        let in_set = |r: &Representation| video_set.is_none_or(|s| r.adaptation_set == s);
+        let in_range = |r: &Representation| bandwidth.is_none_or(|b| b.contains(r.bandwidth));
+        let in_video_set = |r: &Representation| in_set(r) && in_range(r) && quality.allows(r);
+        let has_video = |filter: &dyn Fn(&Representation) -> bool| {
+            self.representations()
+                .into_iter()
+                .any(|r| r.media_type() == MediaType::Video && filter(r))
+        };
+        if let Some(set) = video_set {
+            if !has_video(&in_set) {
+                return Err(IgLiveError::NoSuchVideoSet(set).into());
             }
         }
-        match ret {
-            (Some(video), Some(audio)) => Ok((video, audio)),
-            _ => Err(IgLiveError::EmptyManifest.into()),
+        if let Some(range) = bandwidth {
+            if !has_video(&|r| in_set(r) && in_range(r)) {
+                return Err(IgLiveError::NoBandwidthInRange(range.to_string()).into());
+            }
+        }
+        if has_video(&|_| true) && !has_video(&in_video_set) && quality != Quality::AudioOnly {
+            return Err(IgLiveError::NoSuchQuality(quality.to_string()).into());
+        }
+
+        // Frame rate video representations must have
+        let video_frame_rates = period
+            .adaptation_sets
+            .iter()
+            .flat_map(|a| &a.representations)
+            .filter(|r| r.mime_type.starts_with("video") && in_video_set(r))
+            .filter_map(|r| r.frame_rate);
+        let target_frame_rate = match frame_rate {
+            None => None,
+            Some(FrameRatePreference::High) => Some(video_frame_rates.max()),
+            Some(FrameRatePreference::Low) => Some(video_frame_rates.min()),
+        };
+        if target_frame_rate == Some(None) && quality != Quality::AudioOnly {
+            return Err(IgLiveError::MissingFrameRate.into());
+        }
+
+        // Highest bandwidth, or lowest for the worst quality.
+        // Searched in reverse, as the last of equals is picked, to keep the first one.
+        let rank = |r: &Representation| match quality {
+            Quality::Worst => usize::MAX - r.bandwidth,
+            _ => r.bandwidth,
+        };
+        let representations = period
+            .adaptation_sets
+            .iter()
+            .flat_map(|a| &a.representations)
+            .rev();
+        let video = representations
+            .clone()
+            .filter(|r| {
+                r.mime_type.starts_with("video")
+                    && in_video_set(r)
+                    && target_frame_rate.is_none_or(|f| r.frame_rate == f)
+            })
+            .max_by_key(|r| rank(r));
+        let audio = representations
+            .filter(|r| r.mime_type.starts_with("audio"))
+            .max_by_key(|r| rank(r));
+        Ok((video, audio))
+    }
+
+    /// All representations, in manifest order.
+    pub fn representations(&self) -> Vec<&Representation> {
+        self.period
+            .iter()
+            .flat_map(|p| &p.adaptation_sets)
+            .flat_map(|a| &a.representations)
+            .collect()
+    }
+
+    /// Audio representations, in manifest order.
+    pub fn audio_representations(&self) -> Vec<&Representation> {
+        self.representations()
+            .into_iter()
+            .filter(|r| r.media_type() == MediaType::Audio)
+            .collect()
+    }
+
+    /// Thumbnail or poster image representations.
+    pub fn image_representations(&self) -> Vec<&Representation> {
+        self.period
+            .iter()
+            .flat_map(|p| &p.adaptation_sets)
+            .flat_map(|a| &a.representations)
+            .filter(|r| r.media_type() == MediaType::Image)
+            .collect()
+    }
+
+    /// Wall-clock time of segment `t` of `rep`, based on `availabilityStartTime`.
+    ///
+    /// IG counts segment times from the Unix epoch rather than from `availabilityStartTime`, so
+    /// `t` is read as Unix time if that is within [EPOCH_TIME_TOLERANCE] of
+    /// `availabilityStartTime`, and as an offset from it otherwise.
+    pub fn segment_time(&self, rep: &Representation, t: usize) -> Option<DateTime<Utc>> {
+        let offset_ms = t as u128 * 1000 / rep.timescale() as u128;
+        let offset = TimeDelta::try_milliseconds(offset_ms.try_into().ok()?)?;
+        let start = self.availability_start_time?;
+        match DateTime::UNIX_EPOCH.checked_add_signed(offset) {
+            Some(unix) if (unix - start).abs() <= EPOCH_TIME_TOLERANCE => Some(unix),
+            _ => start.checked_add_signed(offset),
         }
     }
 }
 
-#[derive(PartialEq, Eq, Hash, Clone, Debug)]
+/// Preferred video frame rate when selecting representations
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameRatePreference {
+    /// Highest frame rate, for smoother video
+    High,
+    /// Lowest frame rate, to save space
+    Low,
+}
+
+/// Range of bandwidths in bits per second to select video representations from.
+/// Both ends are inclusive.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BandwidthRange {
+    /// Lowest bandwidth, if bounded
+    pub min: Option<usize>,
+    /// Highest bandwidth, if bounded
+    pub max: Option<usize>,
+}
+
+impl BandwidthRange {
+    /// Whether `bandwidth` is within the range.
+    pub fn contains(&self, bandwidth: usize) -> bool {
+        self.min.is_none_or(|min| bandwidth >= min) && self.max.is_none_or(|max| bandwidth <= max)
+    }
+}
+
+impl fmt::Display for BandwidthRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.min, self.max) {
+            (Some(min), Some(max)) => write!(f, "between {min} and {max} bps"),
+            (Some(min), None) => write!(f, "of at least {min} bps"),
+            (None, Some(max)) => write!(f, "of at most {max} bps"),
+            (None, None) => write!(f, "of any bps"),
+        }
+    }
+}
+
+/// Criteria to select the video and audio representations to download by
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MediaSelection {
+    /// Quality to pick among the remaining representations
+    pub quality: Quality,
+
+    /// Only consider video with the highest or lowest frame rate
+    pub frame_rate: Option<FrameRatePreference>,
+
+    /// Only consider video of this video adaptation set. See [Representation::adaptation_set].
+    pub video_set: Option<usize>,
+
+    /// Only consider video with a bandwidth within this range
+    pub bandwidth: Option<BandwidthRange>,
+}
+
+/// Quality of the video and audio to select
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Quality {
+    /// Highest bandwidth video and audio
+    #[default]
+    Best,
+    /// Lowest bandwidth video and audio
+    Worst,
+    /// Highest bandwidth video whose shorter side is at most this many pixels, so portrait video
+    /// matches too, and the best audio
+    MaxHeight(usize),
+    /// Highest bandwidth video of at most this many bits per second, and the best audio
+    MaxBandwidth(usize),
+    /// Only the best audio, no video
+    AudioOnly,
+}
+
+impl Quality {
+    /// Whether video representation `rep` can be selected for this quality.
+    fn allows(&self, rep: &Representation) -> bool {
+        match *self {
+            Self::Best | Self::Worst => true,
+            Self::MaxHeight(height) => {
+                let short_side = rep.width.into_iter().chain(rep.height).min();
+                short_side.is_some_and(|side| side <= height)
+            }
+            Self::MaxBandwidth(bandwidth) => rep.bandwidth <= bandwidth,
+            Self::AudioOnly => false,
+        }
+    }
+}
+
+impl fmt::Display for Quality {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Best => write!(f, "best"),
+            Self::Worst => write!(f, "worst"),
+            Self::MaxHeight(height) => write!(f, "{height}p"),
+            Self::MaxBandwidth(bandwidth) => write!(f, "{bandwidth} bps"),
+            Self::AudioOnly => write!(f, "audio-only"),
+        }
+    }
+}
+
+/// Kind of media of a [Representation]
+#[derive(Serialize, Deserialize, PartialEq, Eq, Hash, Clone, Debug)]
 pub enum MediaType {
+    /// Video
     Video,
+    /// Audio
     Audio,
+    /// Thumbnail or poster image
+    Image,
+    /// Anything else
     Unknown,
 }
 
 impl Representation {
+    /// Kind of media, based on MIME type.
     pub fn media_type(&self) -> MediaType {
         if self.mime_type.starts_with("video/") {
             MediaType::Video
         } else if self.mime_type.starts_with("audio/") {
             MediaType::Audio
+        } else if self.mime_type.starts_with("image/") {
+            MediaType::Image
         } else {
             MediaType::Unknown
         }
     }
 
-    pub fn download_url(&self, url_base: &Url, t: impl ToString) -> Result<Url> {
-        Ok(url_base.join(
-            &self
-                .segment_template
-                .media_path
-                .replace("$Time$", &t.to_string()),
-        )?)
+    /// Whether segments are listed explicitly with `SegmentList` rather than templated.
+    pub fn is_segment_list(&self) -> bool {
+        self.segment_template.is_none() && self.segment_list.is_some()
+    }
+
+    /// Units per second of segment `t` and `d` values.
+    pub fn timescale(&self) -> usize {
+        let timescale = match (&self.segment_template, &self.segment_list) {
+            (Some(template), _) => template.timescale,
+            (None, Some(list)) => list.timescale,
+            (None, None) => None,
+        };
+        timescale.filter(|&t| t > 0).unwrap_or(1)
+    }
+
+    /// Segments currently available for this representation.
+    ///
+    /// For a `SegmentList` without a timeline, start times are derived from the list index and
+    /// `@duration`.
+    pub fn segments(&self) -> Vec<Segment> {
+        if let Some(template) = &self.segment_template {
+            return template.segment_timeline.segments.clone();
+        }
+        match &self.segment_list {
+            Some(SegmentList {
+                segment_timeline: Some(timeline),
+                ..
+            }) => timeline.segments.clone(),
+            // Times follow from segment numbers, so they stay the same as the list moves along
+            Some(list) => {
+                let d = list.duration.unwrap_or(1);
+                let first = list.start_number.unwrap_or(1).saturating_sub(1);
+                let offset = list.presentation_time_offset.unwrap_or(0);
+                (0..list.segment_urls.len())
+                    .map(|i| Segment {
+                        t: offset + (first + i) * d,
+                        d,
+                    })
+                    .collect()
+            }
+            None => vec![],
+        }
+    }
+
+    /// Full URL of the initialization segment.
+    pub fn initialization_url(&self, url_base: &Url) -> Result<Url> {
+        let path = match (&self.segment_template, &self.segment_list) {
+            (Some(template), _) => &template.initialization_path,
+            (None, Some(list)) => &list.initialization.source_url,
+            (None, None) => return Err(IgLiveError::MissingSegmentInfo.into()),
+        };
+        Ok(url_base.join(path)?)
+    }
+
+    /// Full URL of the media segment starting at `t`.
+    pub fn download_url(&self, url_base: &Url, t: usize) -> Result<Url> {
+        self.download_url_as(url_base, t, self.template_variable())
+    }
+
+    /// Template variable segments are identified by, preferring `$Time$` if the template has
+    /// both.
+    pub fn template_variable(&self) -> TemplateVariable {
+        match &self.segment_template {
+            Some(template)
+                if !template.media_path.contains("$Time$")
+                    && template.media_path.contains("$Number$") =>
+            {
+                TemplateVariable::Number
+            }
+            _ => TemplateVariable::Time,
+        }
+    }
+
+    /// The other template variable than `variable`, if the template contains both.
+    pub fn alternate_template_variable(
+        &self,
+        variable: TemplateVariable,
+    ) -> Option<TemplateVariable> {
+        let template = self.segment_template.as_ref()?;
+        let has_both =
+            template.media_path.contains("$Time$") && template.media_path.contains("$Number$");
+        has_both.then_some(match variable {
+            TemplateVariable::Time => TemplateVariable::Number,
+            TemplateVariable::Number => TemplateVariable::Time,
+        })
+    }
+
+    /// URL of the segment starting at `t`, identifying it by `variable` if it's templated.
+    /// The unused variable is removed from the template.
+    pub fn download_url_as(
+        &self,
+        url_base: &Url,
+        t: usize,
+        variable: TemplateVariable,
+    ) -> Result<Url> {
+        match (&self.segment_template, &self.segment_list) {
+            (Some(template), _) => {
+                let path = match variable {
+                    TemplateVariable::Time => template
+                        .media_path
+                        .replace("$Time$", &t.to_string())
+                        .replace("$Number$", ""),
+                    TemplateVariable::Number => {
+                        let number =
+                            segment_number(template, t).ok_or(IgLiveError::SegmentNotListed(t))?;
+                        template
+                            .media_path
+                            .replace("$Number$", &number.to_string())
+                            .replace("$Time$", "")
+                    }
+                };
+                Ok(url_base.join(&path)?)
+            }
+            (None, Some(list)) => {
+                // Explicit media URLs are used as-is, matched by position in the timeline
+                let index = self
+                    .segments()
+                    .iter()
+                    .position(|s| s.t == t)
+                    .ok_or(IgLiveError::SegmentNotListed(t))?;
+                let segment_url = list
+                    .segment_urls
+                    .get(index)
+                    .ok_or(IgLiveError::SegmentNotListed(t))?;
+                Ok(url_base.join(&segment_url.media)?)
+            }
+            (None, None) => Err(IgLiveError::MissingSegmentInfo.into()),
+        }
+    }
+}
+
+/// Number of the segment starting at `t` in a `$Number$` `template`.
+/// Segments outside the timeline are numbered on from its first segment by the average segment
+/// duration, so past segments can be located too.
+fn segment_number(template: &SegmentTemplate, t: usize) -> Option<usize> {
+    let segments = &template.segment_timeline.segments;
+    let start_number = template.start_number.unwrap_or(1);
+    if let Some(index) = segments.iter().position(|s| s.t == t) {
+        return Some(start_number + index);
+    }
+    let (first, last) = (segments.first()?, segments.last()?);
+    let average = (last.t + last.d).checked_sub(first.t)? as f64 / segments.len() as f64;
+    if average <= 0.0 {
+        return None;
+    }
+    let offset = ((t as f64 - first.t as f64) / average).round() as isize;
+    usize::try_from(start_number as isize + offset).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Path of a manifest saved under `tests/fixtures`
+    fn fixture(name: &str) -> std::path::PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures")
+            .join(name)
+    }
+
+    #[test]
+    fn parses_live_fixture() {
+        let mpd: Mpd = include_str!("../tests/fixtures/live.mpd").parse().unwrap();
+        assert_eq!(mpd.id, "17849304123456789");
+        assert_eq!(mpd.start_frame, 1714564800000);
+        assert!(!mpd.finished);
+        assert_eq!(mpd.representations().len(), 3);
+
+        let (video, audio) = mpd.best_media().unwrap();
+        assert_eq!(video.height, Some(1280));
+        assert_eq!(video.lang.as_deref(), Some("und"));
+        assert_eq!(audio.codecs.as_deref(), Some("mp4a.40.2"));
+        let times: Vec<_> = video.segments().iter().map(|s| s.t).collect();
+        assert_eq!(
+            times,
+            [
+                1714564990000,
+                1714564992000,
+                1714564994000,
+                1714564996033,
+                1714564998000
+            ]
+        );
+
+        let url_base = Url::parse("https://example.com/hls-live/stream/dash-abr/dash.mpd").unwrap();
+        assert_eq!(
+            video.download_url(&url_base, 1714564996033).unwrap().as_str(),
+            "https://example.com/hls-live/stream/live-dash/dash-hd/17849304123456789-1714564996033.m4v"
+        );
+    }
+
+    #[tokio::test]
+    async fn decodes_gzip_manifest_without_content_encoding() {
+        use std::io::Write;
+
+        let xml = include_str!("../tests/fixtures/live.mpd");
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(xml.as_bytes()).unwrap();
+        let body = encoder.finish().unwrap();
+        let (url, _) = crate::test_util::serve(200, "application/dash+xml", body).await;
+
+        let mpd = Mpd::download_from_url(&Client::new(), url).await.unwrap();
+        assert_eq!(mpd.id, "17849304123456789");
+        assert_eq!(mpd.sha256, sha256_hex(xml.as_bytes()));
+    }
+
+    #[tokio::test]
+    async fn manifest_shapes_fail_or_select_tracks() {
+        let selection = MediaSelection::default();
+        let error = |mpd: &Mpd| {
+            let e = mpd.select_tracks(&selection).unwrap_err();
+            e.downcast::<IgLiveError>().unwrap()
+        };
+
+        let mpd = Mpd::from_file(fixture("no-period.mpd")).await.unwrap();
+        assert!(matches!(error(&mpd), IgLiveError::EmptyManifest));
+        let mpd = Mpd::from_file(fixture("empty-adaptation-sets.mpd"))
+            .await
+            .unwrap();
+        assert!(matches!(error(&mpd), IgLiveError::NoRepresentations));
+        let mpd = Mpd::from_file(fixture("images-only.mpd")).await.unwrap();
+        assert!(matches!(error(&mpd), IgLiveError::NoMedia));
+
+        // Single-track manifests are captured as that track
+        let mpd = Mpd::from_file(fixture("audio-only.mpd")).await.unwrap();
+        let tracks = mpd.select_tracks(&selection).unwrap();
+        assert_eq!(tracks.len(), 1);
+        assert_eq!(tracks[0].media_type(), MediaType::Audio);
+        let e = mpd.best_media().unwrap_err();
+        assert!(matches!(e.downcast_ref(), Some(IgLiveError::AudioOnly)));
+
+        let mpd = Mpd::from_file(fixture("video-only.mpd")).await.unwrap();
+        let tracks = mpd.select_tracks(&selection).unwrap();
+        assert_eq!(tracks.len(), 1);
+        assert_eq!(tracks[0].media_type(), MediaType::Video);
+        let e = mpd.best_media().unwrap_err();
+        assert!(matches!(e.downcast_ref(), Some(IgLiveError::VideoOnly)));
+    }
+
+    #[tokio::test]
+    async fn parses_number_template_fixture() {
+        let mpd = Mpd::from_file(fixture("number-template.mpd"))
+            .await
+            .unwrap();
+        let (video, audio) = mpd.best_media().unwrap();
+        assert_eq!(video.timescale(), 90000);
+        assert_eq!(video.template_variable(), TemplateVariable::Number);
+        assert_eq!(audio.segments().len(), 3);
+
+        let url_base = Url::parse("https://example.com/live/dash.mpd").unwrap();
+        assert_eq!(
+            video.download_url(&url_base, 7380000).unwrap().as_str(),
+            "https://example.com/live/dash-sd/42.m4v"
+        );
+    }
+
+    #[tokio::test]
+    async fn selects_within_video_set() {
+        let mpd = Mpd::from_file(fixture("multi-video-set.mpd"))
+            .await
+            .unwrap();
+        let select = |video_set, quality| {
+            let selection = MediaSelection {
+                quality,
+                video_set,
+                ..Default::default()
+            };
+            mpd.select_media(&selection)
+                .map(|(video, audio)| (video.id.as_deref().unwrap(), audio.id.as_deref().unwrap()))
+        };
+
+        // Audio is picked the same way whichever video set is selected
+        let audio = "17849304123456789a-hq";
+        assert_eq!(
+            select(None, Quality::Best).unwrap(),
+            ("17849304123456789v", audio)
+        );
+        assert_eq!(
+            select(Some(0), Quality::Best).unwrap(),
+            ("17849304123456789v", audio)
+        );
+        assert_eq!(
+            select(Some(1), Quality::Best).unwrap(),
+            ("17849304123456789v-land", audio)
+        );
+        assert_eq!(
+            select(Some(1), Quality::MaxHeight(400)).unwrap(),
+            ("17849304123456789v-land-ld", audio)
+        );
+        assert_eq!(
+            select(Some(1), Quality::Worst).unwrap(),
+            ("17849304123456789v-land-ld", "17849304123456789a")
+        );
+
+        let e = select(Some(2), Quality::Best).unwrap_err();
+        assert!(matches!(
+            e.downcast_ref(),
+            Some(IgLiveError::NoSuchVideoSet(2))
+        ));
+    }
+
+    #[tokio::test]
+    async fn segment_times_map_to_wall_clock() {
+        let time = |s: &str| s.parse::<DateTime<Utc>>().unwrap();
+
+        // Unix time segment times, as IG lists them
+        let mpd: Mpd = include_str!("../tests/fixtures/live.mpd").parse().unwrap();
+        let (video, _) = mpd.best_media().unwrap();
+        assert_eq!(
+            mpd.segment_time(video, 1714564990000),
+            Some(time("2024-05-01T12:03:10Z"))
+        );
+        assert_eq!(
+            mpd.segment_time(video, mpd.start_frame),
+            Some(time("2024-05-01T12:00:00Z"))
+        );
+
+        // Segment times relative to `availabilityStartTime`
+        let mpd = Mpd::from_file(fixture("number-template.mpd"))
+            .await
+            .unwrap();
+        let (video, audio) = mpd.best_media().unwrap();
+        assert_eq!(
+            mpd.segment_time(video, 7200000),
+            Some(time("2024-05-01T12:01:20Z"))
+        );
+        assert_eq!(
+            mpd.segment_time(audio, video.timescale() * 2),
+            Some(time("2024-05-01T12:00:02Z"))
+        );
+
+        // No anchor
+        assert_eq!(segment_list(1).segment_time(video, 7200000), None);
+    }
+
+    fn segment_list(start_number: usize) -> Mpd {
+        let urls: String = (start_number..start_number + 3)
+            .map(|n| format!(r#"<SegmentURL media="video-{n}.m4v"/>"#))
+            .collect();
+        format!(
+            r#"<MPD loapStreamId="1" publishFrameTime="0">
+                <Period>
+                    <AdaptationSet>
+                        <Representation id="video" mimeType="video/mp4" bandwidth="2000000">
+                            <SegmentList duration="2000" timescale="1000"
+                                startNumber="{start_number}" presentationTimeOffset="500">
+                                <Initialization sourceURL="video-init.m4v"/>
+                                {urls}
+                            </SegmentList>
+                        </Representation>
+                    </AdaptationSet>
+                </Period>
+            </MPD>"#
+        )
+        .parse()
+        .unwrap()
+    }
+
+    #[test]
+    fn segment_list_times_follow_numbers() {
+        let url_base = Url::parse("https://example.com/live/dash.mpd").unwrap();
+        let first = segment_list(1);
+        let refreshed = segment_list(2);
+        let rep = &first.representations()[0];
+        let refreshed_rep = &refreshed.representations()[0];
+
+        let times = |rep: &Representation| rep.segments().iter().map(|s| s.t).collect::<Vec<_>>();
+        assert_eq!(times(rep), [500, 2500, 4500]);
+        assert_eq!(times(refreshed_rep), [2500, 4500, 6500]);
+        assert_eq!(
+            refreshed_rep
+                .download_url(&url_base, 2500)
+                .unwrap()
+                .as_str(),
+            "https://example.com/live/video-2.m4v"
+        );
+    }
+
+    #[test]
+    fn segment_numbers_extend_past_the_timeline() {
+        let template = SegmentTemplate {
+            segment_timeline: SegmentTimeline {
+                segments: vec![Segment { t: 10000, d: 2000 }, Segment { t: 12000, d: 2000 }],
+            },
+            initialization_path: "init.m4v".to_owned(),
+            media_path: "$Number$.m4v".to_owned(),
+            timescale: Some(1000),
+            start_number: Some(6),
+        };
+        assert_eq!(segment_number(&template, 12000), Some(7));
+        assert_eq!(segment_number(&template, 6033), Some(4));
+        assert_eq!(segment_number(&template, 14000), Some(8));
+        assert_eq!(segment_number(&template, 0), Some(1));
     }
 }
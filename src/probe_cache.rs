@@ -0,0 +1,47 @@
+use std::collections::{BTreeSet, HashMap};
+use std::path::Path;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+use crate::mpd::MediaType;
+
+const PROBE_CACHE_FILE_NAME: &str = "probes.json";
+
+/// Past segment times known not to exist, written alongside segments so later runs don't check
+/// them again
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ProbeCache {
+    /// Manifest URL the probes were made with.
+    /// Signed URLs expire, so probes made with a different URL may have different results.
+    pub url: String,
+
+    /// Segment times that returned 404, by media type
+    pub not_found: HashMap<MediaType, BTreeSet<isize>>,
+}
+
+impl ProbeCache {
+    pub async fn read(dir: impl AsRef<Path>) -> Result<Self> {
+        let data = fs::read(dir.as_ref().join(PROBE_CACHE_FILE_NAME)).await?;
+        Ok(serde_json::from_slice(&data)?)
+    }
+
+    /// Write the cache to `dir`, replacing the previous cache file at once, so concurrent
+    /// writes can't interleave.
+    pub async fn write(&self, dir: impl AsRef<Path>) -> Result<()> {
+        static WRITES: AtomicUsize = AtomicUsize::new(0);
+        let data = serde_json::to_vec(self)?;
+        let tmp_name = format!(
+            "{PROBE_CACHE_FILE_NAME}.{}.{}.tmp",
+            process::id(),
+            WRITES.fetch_add(1, Ordering::Relaxed)
+        );
+        let tmp_path = dir.as_ref().join(tmp_name);
+        fs::write(&tmp_path, data).await?;
+        fs::rename(tmp_path, dir.as_ref().join(PROBE_CACHE_FILE_NAME)).await?;
+        Ok(())
+    }
+}
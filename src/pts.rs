@@ -1,39 +1,80 @@
+use std::ffi::OsStr;
+use std::io;
+use std::path::{Path, PathBuf};
 use std::process::Stdio;
 
 use anyhow::Result;
 use tokio::io::AsyncWriteExt;
 use tokio::process::Command;
 
-pub async fn get_pts(data: Vec<u8>) -> Result<(usize, usize)> {
-    let mut child = Command::new("ffprobe")
+use crate::error::IgLiveError;
+use crate::mp4;
+
+/// `ffprobe` next to `ffmpeg`, e.g. `bin/ffprobe.exe` for `bin/ffmpeg.exe`, or from `$PATH` if
+/// `ffmpeg` is `None`
+pub fn ffprobe_path(ffmpeg: Option<&Path>) -> PathBuf {
+    let Some(ffmpeg) = ffmpeg else {
+        return "ffprobe".into();
+    };
+    let file_name = ffmpeg.file_name().map_or("ffprobe".into(), |name| {
+        name.to_string_lossy().replacen("ffmpeg", "ffprobe", 1)
+    });
+    ffmpeg.with_file_name(file_name)
+}
+
+/// Start and end PTS of the segment in `data`, as reported by `ffprobe`.
+/// Without `ffprobe`, they are read from the segment's boxes instead.
+pub async fn get_pts(ffprobe: impl AsRef<OsStr>, data: Vec<u8>) -> Result<(usize, usize)> {
+    let output = match run_ffprobe(ffprobe, "stream=start_pts,duration_ts", &data).await {
+        Ok(output) => output,
+        Err(e) => match e.downcast_ref::<io::Error>() {
+            Some(io_error) if io_error.kind() == io::ErrorKind::NotFound => {
+                return mp4::segment_times(&data)
+            }
+            _ => return Err(e),
+        },
+    };
+    let (pts_start, pts_end) = output.split_once('|').ok_or(IgLiveError::FfmpegFail)?;
+    Ok((pts_start.trim().parse()?, pts_end.trim().parse()?))
+}
+
+/// Start time in seconds of the first stream in `data`, as reported by `ffprobe`
+pub async fn get_start_time(ffprobe: impl AsRef<OsStr>, data: Vec<u8>) -> Result<f64> {
+    let output = run_ffprobe(ffprobe, "stream=start_time", &data).await?;
+    Ok(output.lines().next().unwrap_or_default().trim().parse()?)
+}
+
+/// Name of the codec of the first stream in `data`, as reported by `ffprobe`
+pub async fn get_codec(ffprobe: impl AsRef<OsStr>, data: Vec<u8>) -> Result<String> {
+    let output = run_ffprobe(ffprobe, "stream=codec_name", &data).await?;
+    Ok(output.lines().next().unwrap_or_default().trim().to_owned())
+}
+
+/// Output of `ffprobe` showing `entries` of the media in `data`, one line per stream with
+/// values separated by `|`
+async fn run_ffprobe(ffprobe: impl AsRef<OsStr>, entries: &str, data: &[u8]) -> Result<String> {
+    let mut child = Command::new(ffprobe)
         .args([
             "-v",
             "0",
             "-show_entries",
-            "stream=start_pts,duration_ts",
+            entries,
             "-of",
             "compact=p=0:nk=1",
             "-",
         ])
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
-        .spawn()
-        .unwrap();
+        .spawn()?;
     let mut stdin = child.stdin.take().unwrap();
-    tokio::spawn(async move {
-        if let outer @ Err(e) = &stdin.write_all(&data).await {
-            if e.kind() != std::io::ErrorKind::BrokenPipe {
-                outer.as_ref().unwrap();
-            }
-        }
-    });
-    let output = child.wait_with_output().await.unwrap();
+    let write = async move {
+        // ffprobe may exit without reading all of its input
+        let _ = stdin.write_all(data).await;
+    };
+    let (_, output) = tokio::join!(write, child.wait_with_output());
+    let output = output?;
     if !output.status.success() {
-        panic!("ffmpeg error");
+        return Err(IgLiveError::FfmpegFail.into());
     }
-    let data = String::from_utf8(output.stdout).unwrap();
-    let data = data.split_once('|').unwrap();
-    let pts_start = data.0.trim().parse().unwrap();
-    let pts_end = data.1.trim().parse().unwrap();
-    Ok((pts_start, pts_end))
+    Ok(String::from_utf8(output.stdout)?)
 }
@@ -0,0 +1,271 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use futures::stream::{FuturesUnordered, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::{fs, time};
+use tracing::{info, warn};
+
+use crate::download::{download, CancellationToken, DownloadConfig};
+use crate::merge::{merge, MergeConfig};
+
+const QUEUE_FILE_NAME: &str = "queue.json";
+
+/// File locked while the queue is changed, so changes by several processes don't overwrite
+/// each other
+const LOCK_FILE_NAME: &str = "queue.json.lock";
+
+/// How often the daemon checks the queue for new and cancelled items
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Processing status of a queued stream
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum QueueStatus {
+    /// Waiting to be downloaded
+    Pending,
+    /// Being downloaded. Started again if the daemon is restarted.
+    Running,
+    /// Downloaded, and merged if enabled
+    Done,
+    /// Download or merge failed
+    Failed,
+    /// Cancelled before it finished
+    Cancelled,
+}
+
+/// Stream in the download queue
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct QueueItem {
+    /// Queue-unique ID
+    pub id: usize,
+
+    /// URL of the stream's .mpd manifest
+    pub url: String,
+
+    /// Processing status
+    pub status: QueueStatus,
+
+    /// Time the stream was added to the queue
+    pub added: DateTime<Utc>,
+
+    /// Download directory, once downloaded
+    pub path: Option<PathBuf>,
+
+    /// Why the download failed, if it did
+    pub error: Option<String>,
+}
+
+/// Streams to be downloaded by [run_daemon], persisted in `queue.json` in a queue directory.
+/// The queue file is re-read on every change, so items can be added and cancelled by other
+/// processes while the daemon runs.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Queue {
+    /// Queued streams, in the order they were added
+    pub items: Vec<QueueItem>,
+}
+
+impl Queue {
+    /// Read the queue in `dir`. An empty queue is returned if there is none yet.
+    pub async fn read(dir: impl AsRef<Path>) -> Result<Self> {
+        match fs::read(dir.as_ref().join(QUEUE_FILE_NAME)).await {
+            Ok(data) => Ok(serde_json::from_slice(&data)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Write the queue to `dir`, replacing the previous queue file at once.
+    pub async fn write(&self, dir: impl AsRef<Path>) -> Result<()> {
+        static WRITES: AtomicUsize = AtomicUsize::new(0);
+        let data = serde_json::to_vec_pretty(self)?;
+        let tmp_name = format!(
+            "{QUEUE_FILE_NAME}.{}.{}.tmp",
+            process::id(),
+            WRITES.fetch_add(1, Ordering::Relaxed)
+        );
+        let tmp_path = dir.as_ref().join(tmp_name);
+        fs::write(&tmp_path, data).await?;
+        fs::rename(tmp_path, dir.as_ref().join(QUEUE_FILE_NAME)).await?;
+        Ok(())
+    }
+
+    /// Read the queue in `dir`, change it with `f` and write it back.
+    /// The queue is locked meanwhile, waiting for other processes changing it.
+    pub async fn update<T>(dir: impl AsRef<Path>, f: impl FnOnce(&mut Self) -> T) -> Result<T> {
+        let _lock = lock(dir.as_ref()).await?;
+        let mut queue = Self::read(&dir).await?;
+        let ret = f(&mut queue);
+        queue.write(&dir).await?;
+        Ok(ret)
+    }
+
+    /// Add a pending stream. Returns its ID.
+    pub fn add(&mut self, url: impl Into<String>) -> usize {
+        let id = self.items.iter().map(|item| item.id + 1).max().unwrap_or(0);
+        self.items.push(QueueItem {
+            id,
+            url: url.into(),
+            status: QueueStatus::Pending,
+            added: Utc::now(),
+            path: None,
+            error: None,
+        });
+        id
+    }
+
+    /// Cancel a pending or running stream.
+    /// Returns `false` if there is no such stream or it already finished.
+    pub fn cancel(&mut self, id: usize) -> bool {
+        let item = self.items.iter_mut().find(|item| item.id == id);
+        match item {
+            Some(item) if matches!(item.status, QueueStatus::Pending | QueueStatus::Running) => {
+                item.status = QueueStatus::Cancelled;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn set_status(&mut self, id: usize, status: QueueStatus) {
+        if let Some(item) = self.items.iter_mut().find(|item| item.id == id) {
+            item.status = status;
+        }
+    }
+}
+
+/// Take the advisory lock of the queue in `dir`, held until the returned file is dropped
+async fn lock(dir: &Path) -> Result<std::fs::File> {
+    let path = dir.join(LOCK_FILE_NAME);
+    let file = tokio::task::spawn_blocking(move || {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)?;
+        file.lock()?;
+        Ok::<_, std::io::Error>(file)
+    })
+    .await??;
+    Ok(file)
+}
+
+/// Download the streams queued in `dir` until `cancel` is cancelled, running up to
+/// `concurrency` downloads at a time.
+/// Streams still running when stopped are started again the next time.
+///
+/// # Arguments
+///
+/// * `dir` - Directory holding the queue file.
+/// * `config` - Options for every download. Its cancellation token is replaced per download.
+/// * `merge_config` - Options for merging every finished download. If `None`, don't merge.
+/// * `concurrency` - Maximum number of simultaneous downloads.
+/// * `cancel` - Token to stop the daemon with.
+pub async fn run_daemon(
+    dir: impl AsRef<Path>,
+    config: DownloadConfig,
+    merge_config: Option<MergeConfig>,
+    concurrency: usize,
+    cancel: CancellationToken,
+) -> Result<()> {
+    let dir = dir.as_ref();
+
+    // Restart streams interrupted by a previous run
+    Queue::update(dir, |queue| {
+        for item in &mut queue.items {
+            if item.status == QueueStatus::Running {
+                item.status = QueueStatus::Pending;
+            }
+        }
+    })
+    .await?;
+
+    type Task<'a> = Pin<Box<dyn Future<Output = (usize, Result<PathBuf>)> + 'a>>;
+    let mut tasks: FuturesUnordered<Task> = FuturesUnordered::new();
+    let mut running: HashMap<usize, CancellationToken> = HashMap::new();
+    loop {
+        // Stop downloads cancelled in the queue, and start pending ones
+        let queue = Queue::read(dir).await?;
+        for item in &queue.items {
+            if let (QueueStatus::Cancelled, Some(token)) = (item.status, running.get(&item.id)) {
+                token.cancel();
+            }
+        }
+        let available = concurrency.saturating_sub(running.len());
+        let pending = queue
+            .items
+            .iter()
+            .filter(|item| item.status == QueueStatus::Pending)
+            .take(available);
+        for item in pending {
+            info!("Starting queued download {}", item.id);
+            let id = item.id;
+            Queue::update(dir, |queue| queue.set_status(id, QueueStatus::Running)).await?;
+            let token = cancel.child_token();
+            running.insert(id, token.clone());
+            let config = DownloadConfig {
+                cancel: token,
+                ..config.clone()
+            };
+            let (url, merge_config) = (item.url.clone(), merge_config.clone());
+            tasks.push(Box::pin(async move {
+                (id, download_and_merge(url, config, merge_config).await)
+            }));
+        }
+
+        tokio::select! {
+            Some((id, result)) = tasks.next(), if !tasks.is_empty() => {
+                running.remove(&id);
+                match &result {
+                    Ok(_) => info!("Finished queued download {id}"),
+                    Err(e) => warn!("Queued download {id} failed: {e}"),
+                }
+                Queue::update(dir, |queue| {
+                    if let Some(item) = queue.items.iter_mut().find(|item| item.id == id) {
+                        match result {
+                            _ if item.status == QueueStatus::Cancelled => (),
+                            Ok(path) => {
+                                item.status = QueueStatus::Done;
+                                item.path = Some(path);
+                            }
+                            Err(e) => {
+                                item.status = QueueStatus::Failed;
+                                item.error = Some(e.to_string());
+                            }
+                        }
+                    }
+                })
+                .await?;
+            }
+            _ = time::sleep(POLL_INTERVAL) => (),
+            _ = cancel.cancelled() => break,
+        }
+    }
+
+    Ok(())
+}
+
+/// Download the stream at `url`, and merge it unless `merge_config` is `None`.
+/// Returns the download directory.
+pub(crate) async fn download_and_merge(
+    url: String,
+    config: DownloadConfig,
+    merge_config: Option<MergeConfig>,
+) -> Result<PathBuf> {
+    let cancel = config.cancel.clone();
+    let report = download(url, config).await?;
+    if cancel.is_cancelled() {
+        return Ok(report.path);
+    }
+    if let Some(merge_config) = merge_config {
+        merge(&report.path, merge_config).await?;
+    }
+    Ok(report.path)
+}
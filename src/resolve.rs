@@ -0,0 +1,162 @@
+use anyhow::Result;
+use reqwest::header::{COOKIE, USER_AGENT};
+use reqwest::{Client, RequestBuilder, Url};
+use serde::Deserialize;
+
+use crate::error::IgLiveError;
+
+/// App ID the Instagram web client identifies itself with
+const IG_APP_ID: &str = "936619743392459";
+
+/// Browser user agent, the web API rejects unknown clients
+const BROWSER_USER_AGENT: &str =
+    "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:128.0) Gecko/20100101 Firefox/128.0";
+
+const PROFILE_INFO_URL: &str = "https://www.instagram.com/api/v1/users/web_profile_info/";
+const LIVE_INFO_URL: &str = "https://www.instagram.com/api/v1/live/web_info/";
+
+#[derive(Deserialize)]
+struct ProfileInfo {
+    data: ProfileData,
+}
+
+#[derive(Deserialize)]
+struct ProfileData {
+    user: Option<ProfileUser>,
+}
+
+#[derive(Deserialize)]
+struct ProfileUser {
+    id: String,
+}
+
+#[derive(Deserialize)]
+struct LiveInfo {
+    dash_abr_playback_url: Option<String>,
+    dash_playback_url: Option<String>,
+}
+
+/// Find the .mpd manifest URL of a live stream.
+///
+/// # Arguments
+///
+/// * `client` - Client to make the requests with, see [crate::download::build_client].
+/// * `input` - Manifest URL, returned as is, Instagram username, or profile, story or live URL
+///   (e.g. `https://www.instagram.com/<user>/live/`).
+/// * `cookie` - Session cookies of a logged-in account, as sent in the `Cookie` header.
+///   Instagram usually only answers logged-in requests.
+/// * `user_agent` - User agent to send instead of a browser's.
+pub async fn resolve_mpd_url(
+    client: &Client,
+    input: &str,
+    cookie: Option<&str>,
+    user_agent: Option<&str>,
+) -> Result<Url> {
+    let username = match parse_input(input)? {
+        Input::Url(url) => return Ok(url),
+        Input::Username(username) => username,
+    };
+
+    let request = |url: Url| {
+        let request = client
+            .get(url)
+            .header(USER_AGENT, user_agent.unwrap_or(BROWSER_USER_AGENT))
+            .header("X-IG-App-ID", IG_APP_ID);
+        match cookie {
+            Some(cookie) => request.header(COOKIE, cookie),
+            None => request,
+        }
+    };
+
+    // Look up the user ID, then the user's broadcast
+    let url = Url::parse_with_params(PROFILE_INFO_URL, [("username", &username)])?;
+    let profile: ProfileInfo = get_json(request(url)).await?;
+    let user = profile
+        .data
+        .user
+        .ok_or_else(|| IgLiveError::NotLive(username.clone()))?;
+    let url = Url::parse_with_params(LIVE_INFO_URL, [("target_user_id", &user.id)])?;
+    let live: LiveInfo = match get_json(request(url)).await {
+        Ok(live) => live,
+        // Users who aren't live have no broadcast
+        Err(e) if matches!(e.downcast_ref(), Some(IgLiveError::StatusNotFound)) => {
+            return Err(IgLiveError::NotLive(username).into())
+        }
+        Err(e) => return Err(e),
+    };
+    let mpd_url = live
+        .dash_abr_playback_url
+        .or(live.dash_playback_url)
+        .ok_or(IgLiveError::NotLive(username))?;
+    Ok(Url::parse(&mpd_url)?)
+}
+
+/// Instagram username given by `input`, if it is a username or profile, story or live URL
+/// rather than a manifest URL.
+pub fn username_from_input(input: &str) -> Option<String> {
+    match parse_input(input) {
+        Ok(Input::Username(username)) => Some(username),
+        _ => None,
+    }
+}
+
+/// Manifest URL or Instagram username, as given by the user
+enum Input {
+    Url(Url),
+    Username(String),
+}
+
+fn parse_input(input: &str) -> Result<Input> {
+    // Accept Instagram URLs copied without the scheme
+    let input = if ["instagram.com/", "www.instagram.com/"]
+        .iter()
+        .any(|prefix| input.starts_with(prefix))
+    {
+        format!("https://{input}")
+    } else {
+        input.to_owned()
+    };
+    let username = match Url::parse(&input) {
+        Ok(url) if is_instagram_host(&url) => {
+            // Story URLs look like /stories/<user>/, profile and live URLs like /<user>/live/
+            let mut segments = url.path_segments().ok_or(IgLiveError::InvalidUrl)?;
+            let user = match segments.next() {
+                Some("stories") => segments.next(),
+                user => user,
+            };
+            user.filter(|user| is_username(user))
+                .ok_or(IgLiveError::InvalidUrl)?
+                .to_owned()
+        }
+        Ok(url) => return Ok(Input::Url(url)),
+        Err(_) if is_username(input.trim_start_matches('@')) => {
+            input.trim_start_matches('@').to_owned()
+        }
+        Err(_) => return Err(IgLiveError::InvalidUrl.into()),
+    };
+    Ok(Input::Username(username))
+}
+
+async fn get_json<T: for<'de> Deserialize<'de>>(request: RequestBuilder) -> Result<T> {
+    let resp = request.send().await?;
+    let status = resp.status();
+    if status == reqwest::StatusCode::NOT_FOUND {
+        return Err(IgLiveError::StatusNotFound.into());
+    }
+    if !status.is_success() {
+        return Err(IgLiveError::StatusError(status.into(), resp.url().to_string()).into());
+    }
+    Ok(serde_json::from_slice(&resp.bytes().await?)?)
+}
+
+fn is_instagram_host(url: &Url) -> bool {
+    url.host_str()
+        .is_some_and(|host| host == "instagram.com" || host.ends_with(".instagram.com"))
+}
+
+/// Whether `s` can be an Instagram username
+fn is_username(s: &str) -> bool {
+    (1..=30).contains(&s.len())
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '_')
+}
@@ -1,15 +1,156 @@
-use std::collections::{HashMap, HashSet};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
-use crate::mpd::MediaType;
+use anyhow::Result;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+use tokio::sync::Mutex;
+
+use crate::download::pipe::LivePipe;
+use crate::download::priority::PrioritySemaphore;
+use crate::marker::BackwardsMarker;
+use crate::mpd::{MediaType, Representation, Segment, TemplateVariable};
+use crate::probe_cache::ProbeCache;
 
 pub struct State {
-    pub downloaded_init: HashMap<MediaType, Vec<u8>>,
+    pub downloaded_init: HashMap<MediaType, InitSegment>,
 
     pub downloaded_segs: HashMap<MediaType, HashSet<usize>>,
 
+    /// Durations of downloaded segments, by start time, where known
+    pub durations: HashMap<MediaType, BTreeMap<usize, usize>>,
+
     pub deltas: HashMap<MediaType, HashMap<isize, i32>>,
 
     pub back_pts: HashMap<MediaType, usize>,
+
+    pub retries: usize,
+
+    /// Bytes of segment data downloaded, excluding initialization segments
+    pub bytes_downloaded: u64,
+
+    /// Stream IDs seen in the manifest, in order
+    pub ids: Vec<String>,
+
+    /// Time spent downloading live segments
+    pub live_time: Option<Duration>,
+
+    /// Time spent searching past segments, by media type
+    pub backwards_times: HashMap<MediaType, Duration>,
+
+    /// Errors that stopped searching past segments, by media type, if not failing fast
+    pub backwards_errors: HashMap<MediaType, String>,
+
+    /// Position of past segment searches, to continue them in a separate run
+    pub marker: BackwardsMarker,
+
+    /// Past segment times known not to exist, if cached across runs
+    pub probe_cache: Option<ProbeCache>,
+
+    /// Pipes live segments are streamed to, by media type.
+    /// Each is locked on its own, so a slow reader doesn't hold up the state.
+    pub pipes: HashMap<MediaType, Arc<Mutex<LivePipe>>>,
+
+    /// Concurrent downloads shared by live and past segments, if live segments are prioritized
+    pub permits: Option<Arc<PrioritySemaphore>>,
+
+    /// Template variable that located a live segment, by media type, once one has
+    pub template_variables: HashMap<MediaType, TemplateVariable>,
+
+    /// Manifest URL to use instead of the original, once its token expired and it was refreshed
+    pub refreshed_url: Option<Url>,
+
+    /// Whether an HTTP/3 request failed, so the rest are sent over HTTP/1.1 or HTTP/2
+    pub http3_failed: bool,
+}
+
+const STATE_FILE_NAME: &str = "state.json";
+
+/// Longest delta between existing segments learned when resuming, longer ones span gaps
+const MAX_LEARNED_DELTA: isize = 10000;
+
+/// Start times of downloaded segments, from the `live` ones and the past ones recorded with
+/// their `durations`
+fn segment_times<'a>(
+    live: impl IntoIterator<Item = &'a usize>,
+    durations: Option<&'a BTreeMap<usize, usize>>,
+) -> BTreeSet<usize> {
+    let past = durations.into_iter().flat_map(|d| d.keys());
+    live.into_iter().chain(past).copied().collect()
+}
+
+/// Download state written alongside segments during and after a download, for inspection and
+/// to resume from
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StateSnapshot {
+    /// Stream IDs seen in the manifest, in order
+    pub ids: Vec<String>,
+
+    /// Start times of downloaded segments, by media type
+    pub downloaded_segs: HashMap<MediaType, BTreeSet<usize>>,
+
+    /// Durations of downloaded segments by start time, where known
+    #[serde(default)]
+    pub durations: HashMap<MediaType, BTreeMap<usize, usize>>,
+
+    /// Media types with a downloaded initialization segment
+    pub downloaded_init: Vec<MediaType>,
+
+    /// Segment time deltas learned while searching past segments, with how often each was seen
+    pub deltas: HashMap<MediaType, BTreeMap<isize, i32>>,
+
+    /// Earliest PTS of downloaded past segments, by media type
+    pub back_pts: HashMap<MediaType, usize>,
+
+    /// Number of segment download retries
+    pub retries: usize,
+}
+
+impl StateSnapshot {
+    /// Start times of the downloaded segments of `media_type`, live and past
+    pub fn segment_times(&self, media_type: &MediaType) -> BTreeSet<usize> {
+        let live = self.downloaded_segs.get(media_type).into_iter().flatten();
+        segment_times(live, self.durations.get(media_type))
+    }
+
+    /// Read the state written to a download directory
+    pub async fn read(dir: impl AsRef<Path>) -> Result<Self> {
+        let data = fs::read(dir.as_ref().join(STATE_FILE_NAME)).await?;
+        Ok(serde_json::from_slice(&data)?)
+    }
+
+    /// Write the state, replacing the previous one only once fully written, so a crash while
+    /// checkpointing leaves the last checkpoint intact
+    pub(crate) async fn write(&self, dir: impl AsRef<Path>) -> Result<()> {
+        let data = serde_json::to_vec_pretty(self)?;
+        let path = dir.as_ref().join(STATE_FILE_NAME);
+        let tmp_path = path.with_extension("json.tmp");
+        fs::write(&tmp_path, data).await?;
+        fs::rename(tmp_path, path).await?;
+        Ok(())
+    }
+}
+
+/// Downloaded initialization segment
+pub enum InitSegment {
+    /// Data kept in memory
+    Memory(Vec<u8>),
+
+    /// Path the data was written to, read again when needed
+    Disk(PathBuf),
+}
+
+impl InitSegment {
+    pub async fn read(&self) -> Result<Cow<'_, [u8]>> {
+        match self {
+            Self::Memory(data) => Ok(Cow::Borrowed(data)),
+            Self::Disk(path) => Ok(Cow::Owned(fs::read(path).await?)),
+        }
+    }
 }
 
 impl State {
@@ -22,6 +163,12 @@ impl State {
             .map(|t| (t, HashSet::new()))
             .collect();
 
+        let durations = media_types
+            .iter()
+            .cloned()
+            .map(|t| (t, BTreeMap::new()))
+            .collect();
+
         let mut default_delta = HashMap::new();
         for x in 16..=24 {
             default_delta.insert(x * 100, 10);
@@ -55,8 +202,194 @@ impl State {
         Self {
             downloaded_init: HashMap::new(),
             downloaded_segs,
+            durations,
             back_pts: HashMap::new(),
             deltas,
+            retries: 0,
+            bytes_downloaded: 0,
+            ids: vec![],
+            live_time: None,
+            backwards_times: HashMap::new(),
+            backwards_errors: HashMap::new(),
+            marker: BackwardsMarker::default(),
+            probe_cache: None,
+            pipes: HashMap::new(),
+            permits: None,
+            template_variables: HashMap::new(),
+            refreshed_url: None,
+            http3_failed: false,
+        }
+    }
+
+    /// Manifest URL to locate segments by, `original` unless it was refreshed
+    pub fn url_base(&self, original: &Url) -> Url {
+        self.refreshed_url
+            .clone()
+            .unwrap_or_else(|| original.clone())
+    }
+
+    /// Variable to locate segments of `rep` by, the one that located a live segment if any
+    pub fn template_variable(&self, rep: &Representation) -> TemplateVariable {
+        self.template_variables
+            .get(&rep.media_type())
+            .copied()
+            .unwrap_or_else(|| rep.template_variable())
+    }
+
+    /// Seed the deltas of `media_type` from consecutive `segments`, in ascending order.
+    /// The deltas between them and their durations rank above all current deltas by how often
+    /// they occur, followed by their +33 and +67 jitter variants and the dominant delta mirrored
+    /// by each observed jitter. If `replace`, the current deltas are dropped first.
+    /// Deltas too long to be between adjacent segments are ignored.
+    /// Return the dominant delta, or `None` without changes if the segments give no delta.
+    pub fn seed_deltas(
+        &mut self,
+        media_type: &MediaType,
+        segments: &[Segment],
+        replace: bool,
+    ) -> Option<isize> {
+        let mut observed: HashMap<isize, i32> = HashMap::new();
+        let gaps = segments
+            .windows(2)
+            .map(|pair| pair[1].t.saturating_sub(pair[0].t) as isize);
+        let durations = segments.iter().map(|s| s.d as isize);
+        for delta in gaps.chain(durations) {
+            if delta > 0 && delta <= MAX_LEARNED_DELTA {
+                *observed.entry(delta).or_insert(0) += 1;
+            }
+        }
+        // Prefer the shorter delta on ties, so segments aren't skipped over
+        let (&dominant, _) = observed.iter().max_by_key(|&(&d, &count)| (count, -d))?;
+
+        let deltas = self.deltas.get_mut(media_type).unwrap();
+        if replace {
+            deltas.clear();
+        }
+        let top_count = deltas.values().copied().max().unwrap_or(0);
+        for &delta in observed.keys() {
+            let base = delta - delta % 100;
+            let mirrored = 2 * dominant - delta;
+            for variant in [base, base + 33, base + 67, mirrored] {
+                let count = deltas.entry(variant).or_insert(0);
+                *count = (*count).max(top_count + 1);
+            }
+        }
+        for (&delta, &count) in &observed {
+            deltas.insert(delta, top_count + 1 + count);
+        }
+        Some(dominant)
+    }
+
+    /// Continue with the deltas and PTS learned by the download that wrote `snapshot`.
+    /// Downloaded segments aren't restored, the segment files are what was actually kept.
+    pub fn restore(&mut self, snapshot: &StateSnapshot) {
+        for (media_type, deltas) in &snapshot.deltas {
+            self.deltas.insert(
+                media_type.clone(),
+                deltas.iter().map(|(&d, &c)| (d, c)).collect(),
+            );
+        }
+        for (media_type, &pts) in &snapshot.back_pts {
+            self.back_pts
+                .entry(media_type.clone())
+                .and_modify(|p| *p = (*p).min(pts))
+                .or_insert(pts);
         }
+        self.retries += snapshot.retries;
+    }
+
+    /// Count the deltas between consecutive segment `times` of `media_type`, in ascending order,
+    /// as if found while searching.
+    /// Deltas too long to be between adjacent segments are ignored.
+    pub fn learn_deltas(&mut self, media_type: &MediaType, times: &[usize]) {
+        let deltas = self.deltas.get_mut(media_type).unwrap();
+        for pair in times.windows(2) {
+            let delta = pair[1].saturating_sub(pair[0]) as isize;
+            if delta <= MAX_LEARNED_DELTA {
+                *deltas.entry(delta).or_insert(0) += 1;
+            }
+        }
+    }
+
+    /// Rank `ordered` deltas of `media_type` above all others, keeping their order
+    pub fn prioritize_deltas(&mut self, media_type: &MediaType, ordered: &[isize]) {
+        let deltas = self.deltas.get_mut(media_type).unwrap();
+        let top_count = deltas.values().copied().max().unwrap_or(0);
+        for (i, &delta) in ordered.iter().enumerate() {
+            deltas.insert(delta, top_count + (ordered.len() - i) as i32);
+        }
+    }
+
+    /// Start times of the downloaded segments of `media_type`, live and past.
+    /// Past segments are only recorded with their durations.
+    pub fn segment_times(&self, media_type: &MediaType) -> BTreeSet<usize> {
+        let live = self.downloaded_segs.get(media_type).into_iter().flatten();
+        segment_times(live, self.durations.get(media_type))
+    }
+
+    /// Serializable summary of the state
+    pub fn snapshot(&self) -> StateSnapshot {
+        StateSnapshot {
+            ids: self.ids.clone(),
+            downloaded_segs: self
+                .downloaded_segs
+                .iter()
+                .map(|(media_type, segs)| (media_type.clone(), segs.iter().copied().collect()))
+                .collect(),
+            durations: self.durations.clone(),
+            downloaded_init: self.downloaded_init.keys().cloned().collect(),
+            deltas: self
+                .deltas
+                .iter()
+                .map(|(media_type, deltas)| {
+                    (media_type.clone(), deltas.clone().into_iter().collect())
+                })
+                .collect(),
+            back_pts: self.back_pts.clone(),
+            retries: self.retries,
+        }
+    }
+
+    /// Most common deltas of `media_type`, most common first
+    pub fn top_deltas(&self, media_type: &MediaType, n: usize) -> Vec<isize> {
+        let mut deltas: Vec<_> = self.deltas[media_type].iter().collect();
+        deltas.sort_by(|(_, a), (_, b)| b.cmp(a));
+        deltas.into_iter().take(n).map(|(&d, _)| d).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seed_deltas_ranks_measured_deltas_first() {
+        let mut state = State::new();
+        let segments = [
+            Segment { t: 0, d: 2033 },
+            Segment { t: 2033, d: 2034 },
+            Segment { t: 4067, d: 2033 },
+        ];
+        let dominant = state.seed_deltas(&MediaType::Video, &segments, false);
+        assert_eq!(dominant, Some(2033));
+
+        // Above the built-in 2000, which ranks above all other built-in deltas
+        let deltas = &state.deltas[&MediaType::Video];
+        assert_eq!(deltas[&2033], 101 + 3);
+        assert_eq!(deltas[&2034], 101 + 2);
+        assert_eq!(deltas[&2000], 101);
+        assert_eq!(deltas[&2067], 101);
+        // 2033 mirrored by the +1 jitter
+        assert_eq!(deltas[&2032], 101);
+        assert_eq!(state.top_deltas(&MediaType::Video, 2), [2033, 2034],);
+        assert_eq!(state.deltas[&MediaType::Audio][&2033], 3);
+    }
+
+    #[test]
+    fn seed_deltas_without_deltas_changes_nothing() {
+        let mut state = State::new();
+        let deltas = state.deltas.clone();
+        assert_eq!(state.seed_deltas(&MediaType::Video, &[], true), None);
+        assert_eq!(state.deltas, deltas);
     }
 }
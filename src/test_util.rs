@@ -0,0 +1,96 @@
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use reqwest::Url;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+use crate::mpd::Mpd;
+
+/// Answer every request on a local port with `status`, `content_type` and `body`.
+/// Returns the URL of a manifest on that server, and the number of requests answered so far.
+pub(crate) async fn serve(
+    status: u16,
+    content_type: &'static str,
+    body: Vec<u8>,
+) -> (Url, Arc<AtomicUsize>) {
+    serve_with(move |_| (status, content_type, body.clone())).await
+}
+
+/// Answer every request on a local port with the status, content type and body `respond`
+/// returns for the requested path.
+/// Returns the URL of a manifest on that server, and the number of requests answered so far.
+pub(crate) async fn serve_with(
+    respond: impl Fn(&str) -> (u16, &'static str, Vec<u8>) + Send + 'static,
+) -> (Url, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/live/stream.mpd", listener.local_addr().unwrap());
+    let requests = Arc::new(AtomicUsize::new(0));
+    let counter = requests.clone();
+    tokio::spawn(async move {
+        while let Ok((mut socket, _)) = listener.accept().await {
+            counter.fetch_add(1, Ordering::SeqCst);
+            let mut request = [0; 4096];
+            let len = socket.read(&mut request).await.unwrap_or(0);
+            let request = String::from_utf8_lossy(&request[..len]);
+            let path = request.split(' ').nth(1).unwrap_or("/");
+            let (status, content_type, body) = respond(path);
+            let head = format!(
+                "HTTP/1.1 {status} Test\r\nContent-Type: {content_type}\r\n\
+                 Content-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            );
+            let _ = socket.write_all(head.as_bytes()).await;
+            let _ = socket.write_all(&body).await;
+        }
+    });
+    (Url::parse(&url).unwrap(), requests)
+}
+
+/// Manifest of a video and an audio representation whose segments start at `times` and last
+/// until the next one, the last one 2000
+pub(crate) fn manifest(times: &[usize]) -> Mpd {
+    let timeline: String = times
+        .iter()
+        .enumerate()
+        .map(|(i, &t)| {
+            let d = times.get(i + 1).map_or(2000, |next| next - t);
+            format!(r#"<S t="{t}" d="{d}"/>"#)
+        })
+        .collect();
+    format!(
+        r#"<MPD loapStreamId="1" publishFrameTime="0">
+            <Period>
+                <AdaptationSet>
+                    <Representation id="video" mimeType="video/mp4" width="720" height="1280"
+                        frameRate="30" bandwidth="2000000" codecs="avc1.64001f">
+                        <SegmentTemplate initialization="video-init.m4v" media="video-$Time$.m4v"
+                            timescale="1000">
+                            <SegmentTimeline>{timeline}</SegmentTimeline>
+                        </SegmentTemplate>
+                    </Representation>
+                </AdaptationSet>
+                <AdaptationSet>
+                    <Representation id="audio" mimeType="audio/mp4" bandwidth="64000"
+                        codecs="mp4a.40.2">
+                        <SegmentTemplate initialization="audio-init.m4a" media="audio-$Time$.m4a"
+                            timescale="1000">
+                            <SegmentTimeline>{timeline}</SegmentTimeline>
+                        </SegmentTemplate>
+                    </Representation>
+                </AdaptationSet>
+            </Period>
+        </MPD>"#
+    )
+    .parse()
+    .unwrap()
+}
+
+/// Empty directory named after `name` and this process in the system's temporary directory
+pub(crate) fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("iglive-{name}-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
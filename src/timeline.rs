@@ -0,0 +1,103 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Write;
+use std::path::Path;
+
+use anyhow::Result;
+use reqwest::{Client, IntoUrl};
+use tokio::fs;
+
+use crate::download::reconcile::parse_segment_file_name;
+use crate::mpd::{MediaSelection, MediaType, Mpd};
+use crate::state::StateSnapshot;
+
+/// Segment of a reconstructed timeline
+#[derive(Clone, Debug)]
+pub struct TimelineEntry {
+    /// Kind of media
+    pub media_type: MediaType,
+
+    /// Start time
+    pub t: usize,
+
+    /// Duration, if known
+    pub d: Option<usize>,
+
+    /// Time between the end of this segment and the start of the next one.
+    /// Negative if they overlap, `None` for the last segment or if the duration is unknown.
+    pub gap: Option<isize>,
+}
+
+/// Timeline of the segment files downloaded into a directory, live and past, with their
+/// durations from the state saved after download, if any
+pub async fn from_dir(dir: impl AsRef<Path>) -> Result<Vec<TimelineEntry>> {
+    let dir = dir.as_ref();
+    let mut times: HashMap<MediaType, BTreeSet<usize>> = HashMap::new();
+    let mut files = fs::read_dir(dir.join("segments")).await?;
+    while let Some(file) = files.next_entry().await? {
+        if let Some((media_type, t)) = parse_segment_file_name(&file.file_name().to_string_lossy())
+        {
+            times.entry(media_type).or_default().insert(t);
+        }
+    }
+    let durations = StateSnapshot::read(dir)
+        .await
+        .map(|state| state.durations)
+        .unwrap_or_default();
+
+    let mut entries = vec![];
+    for media_type in [MediaType::Video, MediaType::Audio] {
+        let Some(times) = times.get(&media_type) else {
+            continue;
+        };
+        let durations = durations.get(&media_type);
+        let segments: BTreeMap<_, _> = times
+            .iter()
+            .map(|&t| (t, durations.and_then(|d| d.get(&t)).copied()))
+            .collect();
+        entries.extend(reconstruct(media_type, segments));
+    }
+    Ok(entries)
+}
+
+/// Timeline of segments currently listed in a manifest, downloaded with `client`
+pub async fn from_manifest(client: &Client, mpd_url: impl IntoUrl) -> Result<Vec<TimelineEntry>> {
+    let manifest = Mpd::download_from_url(client, mpd_url.into_url()?).await?;
+    let mut entries = vec![];
+    for rep in manifest.select_tracks(&MediaSelection::default())? {
+        let segments = rep.segments().iter().map(|s| (s.t, Some(s.d))).collect();
+        entries.extend(reconstruct(rep.media_type(), segments));
+    }
+    Ok(entries)
+}
+
+/// Format a timeline as CSV with a header row
+pub fn to_csv(entries: &[TimelineEntry]) -> String {
+    let mut csv = String::from("media_type,t,d,gap\n");
+    for entry in entries {
+        let d = entry.d.map(|d| d.to_string()).unwrap_or_default();
+        let gap = entry.gap.map(|g| g.to_string()).unwrap_or_default();
+        // Writing to a String can't fail
+        let _ = writeln!(csv, "{:?},{},{d},{gap}", entry.media_type, entry.t);
+    }
+    csv
+}
+
+fn reconstruct(
+    media_type: MediaType,
+    segments: BTreeMap<usize, Option<usize>>,
+) -> Vec<TimelineEntry> {
+    let times: Vec<_> = segments.keys().copied().collect();
+    segments
+        .into_iter()
+        .enumerate()
+        .map(|(i, (t, d))| TimelineEntry {
+            media_type: media_type.clone(),
+            t,
+            d,
+            gap: times
+                .get(i + 1)
+                .zip(d)
+                .map(|(&next_t, d)| next_t as isize - (t + d) as isize),
+        })
+        .collect()
+}
@@ -0,0 +1,9 @@
+use sha2::{Digest, Sha256};
+
+/// SHA-256 of `data` as lowercase hex
+pub(crate) fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}